@@ -2,6 +2,7 @@ import "./wapo";
 import "./polyfill-dom-basic";
 import "./polyfill-textencoding";
 import "./polyfill-streams";
+import "./polyfill-compression";
 import "./polyfill-fetch";
 import "./polyfill-url";
 import "./polyfill-abortcontroller";
@@ -1,8 +1,7 @@
 (function (g) {
     g.self = g;
     g.Buffer = require("buffer").Buffer;
-    function nodeRequire(id) {
-        if (id.startsWith("node:")) id = id.slice(5);
+    function nodeBuiltin(id) {
         switch (id) {
             case "assert": return require("assert");
             case "assert/strict": return require("assert/strict");
@@ -48,6 +47,65 @@
             case "zlib": return require("zlib");
         }
     }
+
+    // CommonJS modules registered in the host's module registry, see `Wapo.registerModule`.
+    const moduleCache = Object.create(null);
+    function dirnameOf(filename) {
+        const pos = filename.lastIndexOf("/");
+        return pos <= 0 ? "/" : filename.slice(0, pos);
+    }
+    function resolveModule(id, dirname) {
+        const filename = Wapo.requireResolve(id, dirname);
+        if (filename == null) {
+            const err = new Error(`Cannot find module '${id}'`);
+            err.code = "MODULE_NOT_FOUND";
+            throw err;
+        }
+        return filename;
+    }
+    function loadModule(filename) {
+        const cached = moduleCache[filename];
+        if (cached) {
+            // May be partially loaded when there is a require cycle.
+            return cached.exports;
+        }
+        const module = {
+            id: filename,
+            filename,
+            path: dirnameOf(filename),
+            exports: {},
+            loaded: false,
+        };
+        moduleCache[filename] = module;
+        try {
+            if (filename.endsWith(".json")) {
+                module.exports = JSON.parse(Wapo.requireSource(filename));
+            } else {
+                const wrapper = Wapo.requireCompile(filename);
+                wrapper.call(module.exports, module.exports, makeRequire(module.path), module, filename, module.path);
+            }
+        } catch (err) {
+            delete moduleCache[filename];
+            throw err;
+        }
+        module.loaded = true;
+        return module.exports;
+    }
+    function makeRequire(dirname) {
+        // Not named `require`, which webpack would take for its own.
+        function moduleRequire(id) {
+            const builtinId = id.startsWith("node:") ? id.slice(5) : id;
+            const builtin = nodeBuiltin(builtinId);
+            if (builtin !== undefined) {
+                return builtin;
+            }
+            return loadModule(resolveModule(id, dirname));
+        }
+        moduleRequire.resolve = (id) => resolveModule(id, dirname);
+        moduleRequire.cache = moduleCache;
+        return moduleRequire;
+    }
+    const nodeRequire = makeRequire("/");
     g.require = nodeRequire;
     g.__dirname = "/";
     g.__filename = "index.js";
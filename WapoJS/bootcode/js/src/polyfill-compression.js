@@ -0,0 +1,47 @@
+(function (g) {
+    const formats = ['gzip', 'deflate', 'deflate-raw', 'br'];
+    // Large chunks are fed in pieces, which bounds what a single push inflates to.
+    const pushSize = 16 * 1024;
+
+    function codecTransformer(format, decompress) {
+        if (!formats.includes(format)) {
+            throw new TypeError(`Unsupported compression format: '${format}'`);
+        }
+        const codec = Wapo.compressionOpen(format, decompress);
+        return {
+            transform(chunk, controller) {
+                if (chunk instanceof ArrayBuffer) {
+                    chunk = new Uint8Array(chunk);
+                } else if (ArrayBuffer.isView(chunk) && !(chunk instanceof Uint8Array)) {
+                    chunk = new Uint8Array(chunk.buffer, chunk.byteOffset, chunk.byteLength);
+                } else if (!(chunk instanceof Uint8Array)) {
+                    throw new TypeError('The chunk must be a BufferSource');
+                }
+                for (let offset = 0; offset < chunk.length; offset += pushSize) {
+                    const output = Wapo.compressionPush(codec, chunk.subarray(offset, offset + pushSize));
+                    if (output.length > 0) {
+                        controller.enqueue(output);
+                    }
+                }
+            },
+            flush(controller) {
+                const output = Wapo.compressionFinish(codec);
+                if (output.length > 0) {
+                    controller.enqueue(output);
+                }
+            },
+        };
+    }
+
+    g.CompressionStream = class CompressionStream extends TransformStream {
+        constructor(format) {
+            super(codecTransformer(format, false));
+        }
+    }
+
+    g.DecompressionStream = class DecompressionStream extends TransformStream {
+        constructor(format) {
+            super(codecTransformer(format, true));
+        }
+    }
+}(globalThis))
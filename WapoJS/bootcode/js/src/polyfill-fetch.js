@@ -242,33 +242,8 @@
                 });
                 return this._body;
             }
-            const anchor = {};
-            const opaqueBodyStream = this._opaqueBodyStream;
-            this._body = new ReadableStream({
-                start(controller) {
-                    anchor.reqId = Wapo.streamOpenRead(opaqueBodyStream, (cmd, data) => {
-                        switch (cmd) {
-                            case "data":
-                                controller.enqueue(data);
-                                break;
-                            case "end":
-                                controller.close();
-                                break;
-                            case "error":
-                                controller.error(data);
-                                break;
-                            default:
-                                console.log("unknown cmd:", cmd);
-                                break;
-                        }
-                    });
-                },
-                cancel() {
-                    if (anchor.reqId) {
-                        Wapo.close(anchor.reqId);
-                    }
-                }
-            });
+            // Pull from the native body only as fast as the consumer reads.
+            this._body = Wapo.streamToReadable(this._opaqueBodyStream);
             return this._body;
         }
     }
@@ -376,29 +351,16 @@
                 method: r.method,
                 headers: Object.fromEntries(r.headers.entries()),
                 body: await r.bytes(),
+                redirect: r.redirect,
+                // Non-standard: custom trust roots, client certificate and pins
+                tls: options?.tls,
             };
-            const redirect = r.redirect;
             Wapo.httpRequest(request,
                 (cmd, data) => {
                     if (cmd == "head") {
-                        if (redirect == "follow" && [301, 302, 307, 308].includes(data.status)) {
-                            const headers = new Headers(data.headers);
-                            const location = headers.get('Location');
-                            if (location) {
-                                let url;
-                                if (location.startsWith("http")) {
-                                    url = location;
-                                } else if (location.startsWith("//")) {
-                                    const base = new URL(request.url);
-                                    url = base.protocol + location;
-                                } else {
-                                    url = new URL(location, request.url).href;
-                                }
-                                g.fetch(url, options).then(resolve).catch(reject);
-                                return;
-                            }
-                        }
-                        resolve(new Response(null, data));
+                        const response = new Response(null, data);
+                        response.redirected = data.redirected;
+                        resolve(response);
                     } else {
                         reject(data);
                     }
@@ -9,7 +9,9 @@
     g.CloseEvent = class CloseEvent extends Event {
         constructor(type, eventInitDict = {}) {
             super(type);
-            this.code = eventInitDict.code;
+            this.code = eventInitDict.code ?? 0;
+            this.reason = eventInitDict.reason ?? '';
+            this.wasClean = eventInitDict.wasClean ?? false;
         }
     }
 
@@ -19,15 +21,17 @@
         CLOSING = 2;
         CLOSED = 3;
         binaryType = 'blob';
+        protocol = '';
+        extensions = '';
 
-        constructor(url, protocols = []) {
+        // `options.perMessageDeflate` opts in to offering the permessage-deflate extension.
+        constructor(url, protocols = [], options = {}) {
             super();
             this.url = url;
             let parsedUrl = new URL(url);
             this.readyState = WebSocket.CONNECTING;
-            const headers = new Headers();
-            if (protocols && protocols.length > 0) {
-                headers.set('Sec-WebSocket-Protocol', protocols.join(','));
+            if (typeof protocols === 'string') {
+                protocols = [protocols];
             }
             const key = btoa(crypto.getRandomValues(new Uint8Array(16)));
             const options = {
@@ -39,12 +43,16 @@
                     'Sec-WebSocket-Version': '13',
                     'Sec-WebSocket-Key': key,
                 },
+                protocols: protocols || [],
+                config: { perMessageDeflate: options?.perMessageDeflate === true },
             };
-            this._task = Wapo.wsOpen(options, (cmd, msg) => {
+            this._task = Wapo.wsOpen(options, (cmd, msg, info) => {
                 switch (cmd) {
                     case 'open':
                         this.readyState = WebSocket.OPEN;
                         this._wsTx = msg;
+                        this.protocol = info?.protocol ?? '';
+                        this.extensions = info?.extensions ?? '';
                         this.dispatchEvent(new Event('open'));
                         break;
                     case 'message':
@@ -60,8 +68,7 @@
                                 }
                                 break;
                             case 'close':
-                                this.readyState = WebSocket.CLOSED;
-                                this.dispatchEvent(new CloseEvent('close'));
+                                this._closed(msg.code ?? 1005, msg.data ?? '', true);
                                 return;
                             case 'ping':
                                 Wapo.wsSend(this._wsTx, { kind: 'pong', data: msg.data });
@@ -69,38 +76,72 @@
                         }
                         this.dispatchEvent(new MessageEvent('message', { data: msg }));
                         break;
+                    case 'drain':
+                        this.dispatchEvent(new Event('drain'));
+                        break;
                     case 'error':
-                        this.readyState = WebSocket.CLOSED;
-                        this.dispatchEvent(new CloseEvent('close'));
+                        this._closed(1006, '', false);
                         break;
                 }
             });
         }
 
         send(data) {
-            let kind = 'text';
-            if (data instanceof ArrayBuffer) {
-                data = new Uint8Array(data);
-                kind = 'binary';
-            } else if (typeof data === 'string') {
-                kind = 'text';
-            } else if (data instanceof Blob) {
-                kind = 'binary';
-                data = new Uint8Array(data);
-            } else if (data instanceof Uint8Array) {
-                kind = 'binary';
-            } else {
-                throw new Error('Unsupported data type');
+            if (this.readyState === WebSocket.CONNECTING) {
+                throw new DOMException('WebSocket is not open', 'InvalidStateError');
+            }
+            if (this.readyState !== WebSocket.OPEN) {
+                return;
             }
-            Wapo.wsSend(this._wsTx, { kind, data });
+            Wapo.wsSend(this._wsTx, encodeMessage(data));
+        }
+
+        // Resolves once the message has been flushed to the socket. Callers streaming large
+        // amounts of data can await it, or watch `bufferedAmount` and the `drain` event.
+        sendAsync(data) {
+            return new Promise((resolve, reject) => {
+                if (this.readyState !== WebSocket.OPEN) {
+                    reject(new DOMException('WebSocket is not open', 'InvalidStateError'));
+                    return;
+                }
+                Wapo.wsSend(this._wsTx, encodeMessage(data), (ok, err) => {
+                    if (ok) {
+                        resolve();
+                    } else {
+                        reject(new Error(err));
+                    }
+                });
+            });
         }
 
         close(code = 1000, reason = '') {
+            if (code !== 1000 && (code < 3000 || code > 4999)) {
+                throw new DOMException(`invalid close code ${code}`, 'InvalidAccessError');
+            }
+            if (this.readyState === WebSocket.CLOSING || this.readyState === WebSocket.CLOSED) {
+                return;
+            }
+            if (this.readyState === WebSocket.CONNECTING) {
+                this._closed(1006, '', false);
+                return;
+            }
+            this.readyState = WebSocket.CLOSING;
             Wapo.wsClose(this._wsTx, code, reason);
+            // Give the peer a chance to answer the close frame before dropping the connection.
+            this._closeTimer = setTimeout(() => this._closed(1006, '', false), 5000);
+        }
+
+        _closed(code, reason, wasClean) {
+            if (this.readyState === WebSocket.CLOSED) {
+                return;
+            }
+            this.readyState = WebSocket.CLOSED;
+            clearTimeout(this._closeTimer);
             Wapo.close(this._task);
+            this.dispatchEvent(new CloseEvent('close', { code, reason, wasClean }));
         }
         get bufferedAmount() {
-            return 0;
+            return this._wsTx ? Wapo.wsBufferedAmount(this._wsTx) : 0;
         }
         set onopen(handler) {
             this.addEventListener('open', handler);
@@ -115,4 +156,22 @@
             this.addEventListener('error', handler);
         }
     }
+
+    function encodeMessage(data) {
+        let kind = 'text';
+        if (data instanceof ArrayBuffer) {
+            data = new Uint8Array(data);
+            kind = 'binary';
+        } else if (typeof data === 'string') {
+            kind = 'text';
+        } else if (data instanceof Blob) {
+            kind = 'binary';
+            data = new Uint8Array(data);
+        } else if (data instanceof Uint8Array) {
+            kind = 'binary';
+        } else {
+            throw new Error('Unsupported data type');
+        }
+        return { kind, data };
+    }
 }(globalThis))
\ No newline at end of file
@@ -15,7 +15,8 @@
 
     function timerFn(hostFn) {
         return function (f, t) {
-            t = Math.round(t || 0);
+            // Fractional delays are honoured by the host.
+            t = Number(t) || 0;
             if (typeof f == 'string') {
                 return hostFn(() => eval(f), t);
             }
@@ -42,6 +43,24 @@
     };
     g.clearInterval = g.clearTimeout;
     g.setImmediate = (f, ...args) => setTimeout(f, 0, ...args);
+    if (typeof g.queueMicrotask !== 'function') {
+        g.queueMicrotask = (f) => {
+            Promise.resolve().then(() => f());
+        };
+    }
+
+    // Monotonic clock, with `Wapo.hrtime()` returning nanoseconds as a bigint.
+    const hrtimePair = g.Wapo.hrtime;
+    const NS_PER_SEC = BigInt(1e9);
+    function nowMs() {
+        const [s, ns] = hrtimePair();
+        return s * 1e3 + ns / 1e6;
+    }
+    function hrtimeBigint() {
+        const [s, ns] = hrtimePair();
+        return BigInt(s) * NS_PER_SEC + BigInt(ns);
+    }
+    g.Wapo.hrtime = hrtimeBigint;
 
     g.Wapo.inspect = inspect;
 
@@ -115,6 +134,18 @@
         },
         version: "v0.9.0",
         nextTick: setImmediate,
+        hrtime: Object.assign(function hrtime(prev) {
+            let [s, ns] = hrtimePair();
+            if (prev) {
+                s -= prev[0];
+                ns -= prev[1];
+                if (ns < 0) {
+                    s -= 1;
+                    ns += 1e9;
+                }
+            }
+            return [s, ns];
+        }, { bigint: hrtimeBigint }),
         stdout: {
             write: function (s) {
                 return Wapo.print(2, s);
@@ -129,10 +160,141 @@
         },
         cwd: () => "/",
     };
-    g.performance = {
-        now() {
-            return new Date().getTime();
+    // Node style `process.on` and DOM style `addEventListener` for the error events reported
+    // by the host, see `Service::flush_rejections`.
+    const processListeners = {};
+    function onProcess(name, listener) {
+        (processListeners[name] ??= []).push(listener);
+        return g.process;
+    }
+    function offProcess(name, listener) {
+        const listeners = processListeners[name];
+        if (listeners) {
+            processListeners[name] = listeners.filter((l) => l !== listener && l.listener !== listener);
+        }
+        return g.process;
+    }
+    function emitProcess(name, ...args) {
+        const listeners = processListeners[name];
+        if (!listeners || listeners.length === 0) {
+            return false;
+        }
+        for (const listener of listeners.slice()) {
+            listener.apply(g.process, args);
+        }
+        return true;
+    }
+    Object.assign(g.process, {
+        on: onProcess,
+        addListener: onProcess,
+        once(name, listener) {
+            const wrapper = function (...args) {
+                offProcess(name, wrapper);
+                return listener.apply(this, args);
+            };
+            wrapper.listener = listener;
+            return onProcess(name, wrapper);
+        },
+        off: offProcess,
+        removeListener: offProcess,
+        removeAllListeners(name) {
+            if (name === undefined) {
+                for (const key of Object.keys(processListeners)) {
+                    delete processListeners[key];
+                }
+            } else {
+                delete processListeners[name];
+            }
+            return g.process;
+        },
+        listeners: (name) => (processListeners[name] ?? []).map((l) => l.listener ?? l),
+        listenerCount: (name) => (processListeners[name] ?? []).length,
+        emit: emitProcess,
+    });
+
+    const globalListeners = {};
+    if (typeof g.addEventListener !== 'function') {
+        g.addEventListener = function (type, listener) {
+            (globalListeners[type] ??= []).push(listener);
+        };
+        g.removeEventListener = function (type, listener) {
+            const listeners = globalListeners[type];
+            if (listeners) {
+                globalListeners[type] = listeners.filter((l) => l !== listener);
+            }
+        };
+    }
+    function dispatchGlobal(type, init) {
+        const event = {
+            type,
+            defaultPrevented: false,
+            preventDefault() {
+                this.defaultPrevented = true;
+            },
+            ...init,
+        };
+        const handler = g[`on${type}`];
+        const listeners = (globalListeners[type] ?? []).slice();
+        if (typeof handler === 'function') {
+            listeners.push(handler);
+        }
+        for (const listener of listeners) {
+            if (typeof listener === 'function') {
+                listener.call(g, event);
+            } else {
+                listener.handleEvent(event);
+            }
+        }
+        return listeners.length > 0 && event.defaultPrevented;
+    }
+
+    // Rejected promises that have been reported as unhandled, to emit `rejectionHandled` when
+    // one of them gets a handler later.
+    const reportedRejections = new WeakSet();
+    function onHostError(kind, data) {
+        switch (kind) {
+            case "uncaughtException": {
+                const error = data;
+                const prevented = dispatchGlobal("error", {
+                    error,
+                    message: error?.message ?? String(error),
+                });
+                const handled = emitProcess("uncaughtException", error, "uncaughtException");
+                return handled || prevented;
+            }
+            case "rejections": {
+                // The events come in order, so a rejection followed by a handled event of the
+                // same promise was handled in time.
+                const pending = new Map();
+                for (const [promise, reason, isHandled] of data) {
+                    if (!isHandled) {
+                        pending.set(promise, reason);
+                    } else if (pending.has(promise)) {
+                        pending.delete(promise);
+                    } else if (reportedRejections.has(promise)) {
+                        reportedRejections.delete(promise);
+                        dispatchGlobal("rejectionhandled", { promise, reason });
+                        emitProcess("rejectionHandled", promise);
+                    }
+                }
+                const unhandled = [];
+                for (const [promise, reason] of pending) {
+                    reportedRejections.add(promise);
+                    const prevented = dispatchGlobal("unhandledrejection", { promise, reason });
+                    const handled = emitProcess("unhandledRejection", reason, promise);
+                    if (!handled && !prevented) {
+                        unhandled.push(reason);
+                    }
+                }
+                return unhandled;
+            }
         }
+    }
+    Wapo.setErrorHandler(onHostError);
+
+    g.performance = {
+        timeOrigin: Date.now() - nowMs(),
+        now: nowMs,
     };
     g.localStorage = {
         _data: {},
@@ -206,6 +368,99 @@
     }
 
 
+    // Turn the receipts returned by the listen functions into handles that can be closed gracefully.
+    // The handle still coerces to the resource id, so `Wapo.close(handle)` keeps working.
+    function listenerHandle(receipt) {
+        const control = receipt.opaqueControl;
+        return {
+            id: receipt.cancelToken,
+            cancelToken: receipt.cancelToken,
+            valueOf() {
+                return receipt.cancelToken;
+            },
+            get accepting() {
+                return Wapo.listenerStats(control).accepting;
+            },
+            get activeConnections() {
+                return Wapo.listenerStats(control).activeConnections;
+            },
+            close(options) {
+                return new Promise((resolve) => Wapo.listenerShutdown(control, options ?? {}, resolve));
+            },
+        };
+    }
+    for (const name of ["httpsListen", "tcpListen"]) {
+        const listen = g.Wapo[name];
+        if (typeof listen === "function") {
+            g.Wapo[name] = (config, handler) => listenerHandle(listen(config, handler));
+        }
+    }
+    const closeResource = g.Wapo.close;
+    g.Wapo.close = (id) => closeResource(typeof id === "object" && id !== null ? Number(id) : id);
+
+    // Promise based stream helpers on top of the callback based host functions.
+    const streamRead = g.Wapo.streamRead;
+    g.Wapo.streamRead = function (reader, maxBytes, callback) {
+        if (callback) {
+            return streamRead(reader, maxBytes, callback);
+        }
+        return new Promise((resolve, reject) => streamRead(reader, maxBytes, (cmd, data) => {
+            switch (cmd) {
+                case "data":
+                    resolve(data);
+                    break;
+                case "end":
+                    resolve(null);
+                    break;
+                default:
+                    reject(new Error(data));
+                    break;
+            }
+        }));
+    };
+    g.Wapo.streamWrite = function (writer, chunk) {
+        return new Promise((resolve, reject) => {
+            Wapo.streamWriteChunk(writer, chunk, (suc, err) => {
+                if (suc) {
+                    resolve();
+                } else {
+                    reject(new Error(err));
+                }
+            });
+        });
+    };
+    g.Wapo.streamToReadable = function (inputStream) {
+        const reader = Wapo.streamOpenReader(inputStream);
+        return new ReadableStream({
+            async pull(controller) {
+                const chunk = await Wapo.streamRead(reader);
+                if (chunk === null) {
+                    Wapo.streamClose(reader);
+                    controller.close();
+                } else {
+                    controller.enqueue(chunk);
+                }
+            },
+            cancel() {
+                Wapo.streamClose(reader);
+            },
+        });
+    };
+    g.Wapo.streamToWritable = function (outputStream) {
+        const writer = Wapo.streamOpenWrite(outputStream);
+        return new WritableStream({
+            write(chunk) {
+                return Wapo.streamWrite(writer, chunk);
+            },
+            close() {
+                Wapo.streamClose(writer);
+            },
+            abort() {
+                Wapo.streamClose(writer);
+            },
+        });
+    };
+
     // should be called in host mode only.
     g.Wapo.run = async function (code, options) {
         const defaultOptions = {
@@ -226,6 +481,7 @@
                 gasLimit: options.gasLimit,
                 memoryLimit: options.memoryLimit,
                 polyfills: options.polyfills,
+                exitOnUnhandledRejection: options.exitOnUnhandledRejection ?? false,
             }, resolve)).then(([error, value, serialized, logs]) => {
                 if (serialized) {
                     try {
@@ -268,6 +524,35 @@
         }
     }
 
+    // Serializes the result of the script for `wapojs --output`, see `js_eval::encode_output`.
+    function outputReplacer(key, value) {
+        if (value instanceof Uint8Array) {
+            return "0x" + Array.from(value, (b) => b.toString(16).padStart(2, "0")).join("");
+        }
+        if (typeof value === "bigint") {
+            return value.toString();
+        }
+        return value;
+    }
+    g.Wapo.encodeOutput = function (format, scaleTypes, output, error) {
+        const envelope = error
+            ? { ok: false, error: { message: error[0], stack: error[1] } }
+            : { ok: true, output: output === undefined ? null : output };
+        switch (format) {
+            case "json":
+                return JSON.stringify(envelope, outputReplacer);
+            case "scale": {
+                // Append the types of the envelope to the registry, the output being type 0.
+                const types = scaleTypes.split("\n").map((line) => line.trim()).filter(Boolean);
+                const str = types.length;
+                types.push("#str", `{message:${str},stack:${str}}`, `<Ok:0,Err:${str + 1}>`);
+                const codec = Wapo.SCALE.codec(str + 2, Wapo.SCALE.parseTypes(types.join("\n")));
+                return codec.encode(error ? { Err: envelope.error } : { Ok: output });
+            }
+        }
+        throw new Error(`unknown output format: ${format}`);
+    };
+
     // should be called in guest mode only.
     g.Wapo.callModuleEntry = async function callModuleEntry() {
         const fn = globalThis.module?.exports;
@@ -0,0 +1,30 @@
+fn main() {
+    export_engine_revision();
+}
+
+/// Exports the commit of the qjs-sys checkout as `QJS_SYS_REVISION`, which identifies the
+/// bytecode format of the engine for the compile cache.
+fn export_engine_revision() {
+    let git = |args: &[&str]| {
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg("../qjs-sys")
+            .args(args)
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+    };
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        println!("cargo:rerun-if-changed={git_dir}/HEAD");
+        println!("cargo:rerun-if-changed={git_dir}/refs");
+    }
+    println!("cargo:rerun-if-env-changed=QJS_SYS_REVISION");
+    let revision = std::env::var("QJS_SYS_REVISION")
+        .ok()
+        .or_else(|| git(&["rev-parse", "HEAD"]))
+        .unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=QJS_SYS_REVISION={revision}");
+}
@@ -0,0 +1,65 @@
+const address = "127.0.0.1:18080";
+
+// Serves a gzipped JSON body, so that the example doesn't depend on a remote server.
+function serveGzip() {
+    return Wapo.httpsListen({ address }, async (req) => {
+        const body = new TextEncoder().encode(JSON.stringify({ gzipped: true, url: req.url }));
+        const compressed = await transform(new CompressionStream("gzip"), body);
+        Wapo.httpsSendResponseHead(req.opaqueResponseTx, {
+            status: 200,
+            headers: {
+                "Content-Type": "application/json",
+                "Content-Encoding": "gzip",
+                "Content-Length": `${compressed.length}`,
+            },
+        });
+        const writer = Wapo.streamOpenWrite(req.opaqueOutputStream);
+        Wapo.streamWriteChunk(writer, compressed, (suc, err) => {
+            if (!suc) {
+                console.log("write error:", err);
+            }
+            Wapo.streamClose(writer);
+        });
+    });
+}
+
+async function test_fetch_gzip() {
+    const server = serveGzip();
+    console.log("start to get gzipped response...");
+    const response = await fetch(`http://${address}/gzip`);
+    console.log("status:", response.status);
+    console.log("content-encoding:", response.headers.get("content-encoding"));
+    const body = await response.json();
+    console.log("gzipped:", body.gzipped);
+    await server.close();
+}
+
+async function transform(stream, data) {
+    const writer = stream.writable.getWriter();
+    writer.write(data);
+    writer.close();
+    const reader = stream.readable.getReader();
+    const chunks = [];
+    while (true) {
+        const { done, value } = await reader.read();
+        if (done) break;
+        chunks.push(value);
+    }
+    return Wapo.concatU8a(chunks);
+}
+
+async function test_compression_stream() {
+    const input = new TextEncoder().encode("Hello, WapoJS! ".repeat(100));
+    for (const format of ["gzip", "deflate", "deflate-raw", "br"]) {
+        const compressed = await transform(new CompressionStream(format), input);
+        const decompressed = await transform(new DecompressionStream(format), compressed);
+        console.log(`${format}: ${input.length} -> ${compressed.length} -> ${decompressed.length}`);
+    }
+}
+
+async function test_all() {
+    await test_fetch_gzip();
+    await test_compression_stream();
+}
+
+test_all()
@@ -0,0 +1,7 @@
+// Run with `wapojs --import-map examples/esm/importmap.json examples/esm/main.mjs`.
+import { greet } from "greeting";
+import { sleep } from "./lib/sleep.js";
+
+console.log(import.meta.url);
+await sleep(100);
+globalThis.scriptOutput = greet("wapo");
@@ -229,10 +229,19 @@ function addEventListener(type, callback) {
                                 headers: Array.from(response.headers.entries()),
                             });
                             if (response._opaqueBodyStream) {
-                                // offload to host for better performance
+                                // offload to host for better performance, hashing the
+                                // proxied body on the way for auditing.
                                 Wapo.streamBridge({
                                     input: response._opaqueBodyStream,
                                     output: req.opaqueOutputStream,
+                                    transforms: [{ kind: "digest", algorithm: "sha256" }],
+                                }, (cmd, data) => {
+                                    if (cmd === "end") {
+                                        const digest = Wapo.hexEncode(data.transforms[0].digest);
+                                        console.log(`proxied ${data.bytesWritten} bytes, sha256:`, digest);
+                                    } else {
+                                        console.log("proxy error:", data);
+                                    }
                                 });
                             } else {
                                 const writer = toWritableStream(req.opaqueOutputStream);
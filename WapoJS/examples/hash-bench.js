@@ -1,13 +1,14 @@
 const hash = Wapo.hash;
 const iterations = 10000;
 function bench(hashName, iterations) {
-    const t0 = Date.now();
+    const t0 = performance.now();
     var digest = "Hello World!";
     for (var i = 0; i < iterations; i++) {
         digest = hash(hashName, digest);
     }
     const finalDigest = Wapo.hexEncode(digest.slice(0, 4));
-    console.log(`${hashName}(${finalDigest}): ${iterations} iterations in ${Date.now() - t0}ms, ${iterations / (Date.now() - t0) * 1000} calc/sec`);
+    const elapsed = performance.now() - t0;
+    console.log(`${hashName}(${finalDigest}): ${iterations} iterations in ${elapsed.toFixed(3)}ms, ${iterations / elapsed * 1000} calc/sec`);
 }
 bench("sha256", iterations);
 bench("keccak256", iterations);
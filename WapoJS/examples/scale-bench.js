@@ -17,17 +17,18 @@
         types += `{foo:0,bar:1}\n`;
     }
     const iterations = 100;
-    const t0 = Date.now();
+    const t0 = performance.now();
     for (var i = 0; i < iterations; i++) {
         scl.parseTypes(types);
     }
-    console.log(`${envName}: parse ${iterations} iterations in ${Date.now() - t0}ms, ${iterations / (Date.now() - t0) * 1000} ops/sec`);
+    const parseElapsed = performance.now() - t0;
+    console.log(`${envName}: parse ${iterations} iterations in ${parseElapsed.toFixed(3)}ms, ${iterations / parseElapsed * 1000} ops/sec`);
     const parsedTypes = scl.parseTypes(types);
     const data = [];
     for (var i = 0; i < 20; i++) {
         data.push({ foo: 1, bar: 'baz' }); 
     }
-    const t1 = Date.now();
+    const t1 = performance.now();
     const coder = scl.codec(6, parsedTypes);
     for (var i = 0; i < iterations; i++) {
         const encoded = coder.encode(data);
@@ -36,6 +37,7 @@
             throw new Error(`Decoded length mismatch: ${decoded.length} != ${data.length}`);
         }
     }
-    console.log(`${envName}: enc/dec ${iterations} iterations in ${Date.now() - t1}ms, ${iterations / (Date.now() - t1) * 1000} ops/sec`);
+    const codecElapsed = performance.now() - t1;
+    console.log(`${envName}: enc/dec ${iterations} iterations in ${codecElapsed.toFixed(3)}ms, ${iterations / codecElapsed * 1000} ops/sec`);
 }());
 
@@ -0,0 +1,52 @@
+console.log = Wapo.inspect;
+
+// A local echo server to talk to, so that the example doesn't depend on a remote host.
+const host = "127.0.0.1";
+const port = 12345;
+const server = Wapo.tcpListen({ address: `${host}:${port}` }, (conn) => {
+    const writer = Wapo.streamOpenWrite(conn.opaqueOutputStream);
+    Wapo.streamOpenRead(conn.opaqueInputStream, (cmd, data) => {
+        switch (cmd) {
+            case "data":
+                Wapo.streamWriteChunk(writer, data, () => { });
+                break;
+            case "end":
+                Wapo.streamClose(writer);
+                break;
+        }
+    });
+});
+
+console.log(`Connecting to ${host}:${port}`);
+
+Wapo.tcpConnect({ host, port }, (cmd, data) => {
+    switch (cmd) {
+        case "connect":
+            const writer = Wapo.streamOpenWrite(data.opaqueOutputStream);
+            Wapo.streamOpenRead(data.opaqueInputStream, (cmd, data) => {
+                switch (cmd) {
+                    case "data":
+                        console.log("echoed:", new TextDecoder().decode(data));
+                        Wapo.streamClose(writer);
+                        break;
+                    case "end":
+                        console.log("connection closed");
+                        server.close();
+                        break;
+                    case "error":
+                        console.log("read error:", data);
+                        break;
+                }
+            });
+            Wapo.streamWriteChunk(writer, new TextEncoder().encode("Hello, WapoJS!"), (suc, err) => {
+                if (!suc) {
+                    console.log("write error:", err);
+                }
+            });
+            break;
+        case "error":
+            console.log("error:", data);
+            server.close();
+            break;
+    }
+});
@@ -0,0 +1,28 @@
+// A line based echo server. Try it with `nc localhost 1234`.
+console.log = Wapo.inspect;
+
+const address = "127.0.0.1:1234";
+console.log(`Listening on ${address}`);
+
+Wapo.tcpListen({ address }, (conn) => {
+    console.log(`Accepted connection from ${conn.remoteAddr}`);
+    const writer = Wapo.streamOpenWrite(conn.opaqueOutputStream);
+    Wapo.streamOpenRead(conn.opaqueInputStream, (cmd, data) => {
+        switch (cmd) {
+            case "data":
+                Wapo.streamWriteChunk(writer, data, (suc, err) => {
+                    if (!suc) {
+                        console.log("write error:", err);
+                    }
+                });
+                break;
+            case "end":
+                console.log(`${conn.remoteAddr} disconnected`);
+                Wapo.streamClose(writer);
+                break;
+            case "error":
+                console.log("read error:", data);
+                break;
+        }
+    });
+});
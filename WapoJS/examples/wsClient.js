@@ -0,0 +1,31 @@
+// A long-lived WebSocket client that pings the server when idle and reconnects on failure.
+// Try it against `examples/wsServer.js`.
+console.log = Wapo.inspect;
+
+const options = {
+    url: "ws://localhost:8080",
+    pingIntervalMs: 10_000,
+    pongTimeoutMs: 5_000,
+    reconnect: { maxAttempts: 10, initialDelayMs: 500, maxDelayMs: 10_000 },
+};
+
+Wapo.wsOpen(options, (cmd, data) => {
+    switch (cmd) {
+        case "open":
+            console.log("connected");
+            Wapo.wsSend(data, { kind: "text", data: "hello" });
+            break;
+        case "message":
+            console.log("received:", data);
+            break;
+        case "reconnecting":
+            console.log(`reconnecting in ${data.delayMs}ms (attempt ${data.attempt}): ${data.reason}`);
+            break;
+        case "reconnected":
+            console.log(`reconnected after ${data.attempt} attempt(s)`);
+            break;
+        case "error":
+            console.log("ws error:", data);
+            break;
+    }
+});
@@ -0,0 +1,28 @@
+// A WebSocket echo server. Try it with `websocat ws://localhost:8080`.
+console.log = Wapo.inspect;
+
+Wapo.httpsListen({ address: "localhost:8080" }, (req) => {
+    const upgrade = req.headers.find(([k]) => k.toLowerCase() === "upgrade");
+    if (!upgrade || upgrade[1].toLowerCase() !== "websocket") {
+        Wapo.httpsSendResponseHead(req.opaqueResponseTx, { status: 426, headers: [] });
+        Wapo.streamClose(Wapo.streamOpenWrite(req.opaqueOutputStream));
+        return;
+    }
+    let sink;
+    Wapo.wsAccept(req, {}, (cmd, data) => {
+        switch (cmd) {
+            case "open":
+                console.log(`${req.remoteAddr} connected`);
+                sink = data;
+                break;
+            case "message":
+                if (data.kind === "text" || data.kind === "binary") {
+                    Wapo.wsSend(sink, data);
+                }
+                break;
+            case "error":
+                console.log("ws error:", data);
+                break;
+        }
+    });
+});
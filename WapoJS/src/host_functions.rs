@@ -8,9 +8,14 @@ use crate::traits::ResultExt;
 #[cfg(feature = "js-http-listen")]
 #[allow(unused_imports)]
 pub(crate) use http_listen::try_accept_http_request;
+pub(crate) use http_request::{new_http_client, HttpClient};
 #[cfg(feature = "wapo")]
 pub(crate) use query_listen::try_accept_query;
+pub(crate) use commonjs::ModuleRegistry;
+pub(crate) use timer::{drive_timers, TimerQueue};
 
+mod commonjs;
+mod compression;
 mod debug;
 #[cfg(feature = "js-http-listen")]
 mod http_listen;
@@ -35,6 +40,8 @@ mod isolate_eval;
 
 mod env;
 mod stream;
+mod stream_transform;
+mod tcp;
 
 #[cfg(feature = "js-wasm")]
 mod webassambly;
@@ -61,9 +68,12 @@ pub(crate) fn setup_host_functions(ctx: &js::Context, cfg: &ServiceConfig) -> Re
     print::setup(&ns)?;
     timer::setup(&ns)?;
     http_request::setup(&ns)?;
+    compression::setup(&ns)?;
+    commonjs::setup(&ns)?;
     debug::setup(&ns)?;
     ns.define_property_fn("close", close_res)?;
     ns.define_property_fn("exit", exit)?;
+    ns.define_property_fn("setErrorHandler", set_error_handler)?;
 
     #[cfg(feature = "js-url")]
     url::setup(&ns)?;
@@ -90,6 +100,7 @@ pub(crate) fn setup_host_functions(ctx: &js::Context, cfg: &ServiceConfig) -> Re
         http_listen::setup(&ns)?;
         #[cfg(feature = "js-https-listen")]
         https_listen::setup(&ns)?;
+        tcp::setup(&ns)?;
         #[cfg(feature = "isolate")]
         isolate_eval::setup(&ns)?;
         #[cfg(feature = "wapo")]
@@ -135,6 +146,17 @@ fn exit(service: ServiceRef, _this: js::Value) {
     service.close_all();
 }
 
+/// Installs the JS function that dispatches uncaught exceptions and promise rejections to the
+/// `process` and `globalThis` listeners.
+#[js::host_call(with_context)]
+fn set_error_handler(
+    service: ServiceRef,
+    _this: js::Value,
+    handler: crate::service::OwnedJsValue,
+) {
+    service.set_error_handler(handler);
+}
+
 /// This function returns the value of f2 and infer it's type as the return type of f1.
 #[allow(dead_code)]
 fn valueof_f2_as_typeof_f1<F1, I1, F2, O>(f1: F1, f2: F2) -> Option<O>
@@ -0,0 +1,156 @@
+//! The module registry backing `require()` in the nodejs environment.
+//!
+//! Module sources are registered under absolute virtual paths, either from the command line or
+//! from JS with `Wapo.registerModule`, and resolved with the Node.js algorithm. Caching and
+//! the `module.exports` protocol live in `nodeEnv.js`.
+
+use std::{
+    collections::BTreeMap,
+    ffi::{c_int, CString},
+};
+
+use anyhow::{anyhow, Context};
+use js::c;
+
+use super::*;
+use crate::service::join_path;
+
+pub(crate) fn setup(ns: &js::Value) -> Result<()> {
+    ns.define_property_fn("registerModule", register_module)?;
+    ns.define_property_fn("requireResolve", require_resolve)?;
+    ns.define_property_fn("requireSource", require_source)?;
+    ns.define_property_fn("requireCompile", require_compile)?;
+    Ok(())
+}
+
+/// Extensions tried, in order, for a path without one.
+const EXTENSIONS: [&str; 3] = [".js", ".cjs", ".json"];
+
+#[derive(Default)]
+pub(crate) struct ModuleRegistry {
+    sources: BTreeMap<String, String>,
+}
+
+impl ModuleRegistry {
+    pub(crate) fn register(&mut self, path: &str, source: String) {
+        self.sources.insert(join_path("/", path), source);
+    }
+
+    fn source(&self, path: &str) -> Option<&str> {
+        self.sources.get(path).map(String::as_str)
+    }
+
+    /// Resolves `specifier` required from a module in `from_dir` to the path of a registered
+    /// module.
+    fn resolve(&self, specifier: &str, from_dir: &str) -> Option<String> {
+        let is_relative = specifier.starts_with("./")
+            || specifier.starts_with("../")
+            || specifier == "."
+            || specifier == "..";
+        if is_relative || specifier.starts_with('/') {
+            let base = if is_relative { from_dir } else { "/" };
+            let path = join_path(base, specifier);
+            return self.resolve_file(&path).or_else(|| self.resolve_dir(&path));
+        }
+        let mut dir = from_dir;
+        loop {
+            if !dir.ends_with("/node_modules") {
+                let path = join_path(dir, &format!("node_modules/{specifier}"));
+                if let Some(path) = self.resolve_file(&path).or_else(|| self.resolve_dir(&path)) {
+                    return Some(path);
+                }
+            }
+            if dir == "/" {
+                return None;
+            }
+            dir = match dir.rfind('/') {
+                Some(0) | None => "/",
+                Some(pos) => &dir[..pos],
+            };
+        }
+    }
+
+    fn resolve_file(&self, path: &str) -> Option<String> {
+        if self.sources.contains_key(path) {
+            return Some(path.to_string());
+        }
+        EXTENSIONS
+            .iter()
+            .map(|ext| format!("{path}{ext}"))
+            .find(|path| self.sources.contains_key(path))
+    }
+
+    fn resolve_dir(&self, dir: &str) -> Option<String> {
+        if let Some(main) = self.package_main(dir) {
+            let main = join_path(dir, &format!("./{main}"));
+            let index = join_path(&main, "./index");
+            if let Some(path) = self
+                .resolve_file(&main)
+                .or_else(|| self.resolve_file(&index))
+            {
+                return Some(path);
+            }
+        }
+        self.resolve_file(&join_path(dir, "./index"))
+    }
+
+    fn package_main(&self, dir: &str) -> Option<String> {
+        let package = self.source(&join_path(dir, "./package.json"))?;
+        let package: serde_json::Value = serde_json::from_str(package).ok()?;
+        Some(package.get("main")?.as_str()?.to_string())
+    }
+}
+
+#[js::host_call(with_context)]
+fn register_module(service: ServiceRef, _this: js::Value, path: String, source: String) {
+    service.modules().borrow_mut().register(&path, source);
+}
+
+#[js::host_call(with_context)]
+fn require_resolve(
+    service: ServiceRef,
+    _this: js::Value,
+    specifier: String,
+    from_dir: String,
+) -> Option<String> {
+    service.modules().borrow().resolve(&specifier, &from_dir)
+}
+
+#[js::host_call(with_context)]
+fn require_source(service: ServiceRef, _this: js::Value, path: String) -> Result<String> {
+    let modules = service.modules().borrow();
+    let source = modules.source(&path).context("module not found")?;
+    Ok(source.to_string())
+}
+
+/// Compiles the module at `path` into a function taking
+/// `(exports, require, module, __filename, __dirname)`.
+#[js::host_call(with_context)]
+fn require_compile(service: ServiceRef, _this: js::Value, path: String) -> Result<js::Value> {
+    let source = {
+        let modules = service.modules().borrow();
+        let source = modules.source(&path).context("module not found")?;
+        // A shebang line is not valid JS, keep it as a comment so that line numbers hold.
+        let source = match source.strip_prefix("#!") {
+            Some(rest) => format!("//{rest}"),
+            None => source.to_string(),
+        };
+        format!("(function (exports, require, module, __filename, __dirname) {{{source}\n}})")
+    };
+    let source = CString::new(source).context("module source contains a nul byte")?;
+    let filename = CString::new(path).context("module path contains a nul byte")?;
+    let ctx = service.context();
+    let ret = unsafe {
+        c::JS_Eval(
+            ctx.as_ptr(),
+            source.as_ptr(),
+            source.as_bytes().len(),
+            filename.as_ptr(),
+            c::JS_EVAL_TYPE_GLOBAL as c_int,
+        )
+    };
+    if c::is_exception(ret) {
+        return Err(anyhow!("{}", ctx.get_exception_str()));
+    }
+    Ok(js::Value::new_moved(ctx, ret))
+}
@@ -0,0 +1,209 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+use anyhow::{bail, Context};
+use flate2::{write as fl, Compression};
+use js::AsBytes;
+
+use super::*;
+
+pub fn setup(ns: &js::Value) -> Result<()> {
+    ns.define_property_fn("compressionOpen", compression_open)?;
+    ns.define_property_fn("compressionPush", compression_push)?;
+    ns.define_property_fn("compressionFinish", compression_finish)?;
+    Ok(())
+}
+
+/// The most a decompressor may output for a single push or finish, so that a small
+/// compression bomb can't take all the memory.
+pub(crate) const MAX_DECOMPRESSED_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// A buffer shared between a codec and its owner so that the output can be drained after
+/// every write, no matter which codec is writing into it.
+#[derive(Clone)]
+struct SharedBuf {
+    buf: Rc<RefCell<Vec<u8>>>,
+    /// The most the buffer may hold before it is drained.
+    limit: usize,
+}
+
+impl SharedBuf {
+    fn new(limit: usize) -> Self {
+        Self {
+            buf: Default::default(),
+            limit,
+        }
+    }
+
+    fn take(&self) -> Vec<u8> {
+        core::mem::take(&mut *self.buf.borrow_mut())
+    }
+}
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut output = self.buf.borrow_mut();
+        if output.len() + buf.len() > self.limit {
+            return Err(std::io::Error::other(format!(
+                "decompressed chunk exceeds {} bytes",
+                self.limit
+            )));
+        }
+        output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Inner {
+    GzipEncoder(fl::GzEncoder<SharedBuf>),
+    GzipDecoder(fl::GzDecoder<SharedBuf>),
+    ZlibEncoder(fl::ZlibEncoder<SharedBuf>),
+    ZlibDecoder(fl::ZlibDecoder<SharedBuf>),
+    DeflateEncoder(fl::DeflateEncoder<SharedBuf>),
+    DeflateDecoder(fl::DeflateDecoder<SharedBuf>),
+    BrotliEncoder(Box<brotli::CompressorWriter<SharedBuf>>),
+    BrotliDecoder(Box<brotli::DecompressorWriter<SharedBuf>>),
+}
+
+/// An incremental compressor or decompressor.
+///
+/// Supported formats are `gzip`, `deflate` (zlib wrapped, as in HTTP and the Compression
+/// Streams API), `deflate-raw` and `br`.
+pub(crate) struct Codec {
+    inner: Inner,
+    output: SharedBuf,
+}
+
+impl Codec {
+    pub fn new(format: &str, decompress: bool) -> Result<Self> {
+        let output = SharedBuf::new(if decompress {
+            MAX_DECOMPRESSED_CHUNK_SIZE
+        } else {
+            usize::MAX
+        });
+        let out = output.clone();
+        let inner = match (format, decompress) {
+            ("gzip", false) => Inner::GzipEncoder(fl::GzEncoder::new(out, Compression::default())),
+            ("gzip", true) => Inner::GzipDecoder(fl::GzDecoder::new(out)),
+            ("deflate", false) => {
+                Inner::ZlibEncoder(fl::ZlibEncoder::new(out, Compression::default()))
+            }
+            ("deflate", true) => Inner::ZlibDecoder(fl::ZlibDecoder::new(out)),
+            ("deflate-raw", false) => {
+                Inner::DeflateEncoder(fl::DeflateEncoder::new(out, Compression::default()))
+            }
+            ("deflate-raw", true) => Inner::DeflateDecoder(fl::DeflateDecoder::new(out)),
+            ("br", false) => {
+                Inner::BrotliEncoder(Box::new(brotli::CompressorWriter::new(out, 4096, 5, 22)))
+            }
+            ("br", true) => {
+                Inner::BrotliDecoder(Box::new(brotli::DecompressorWriter::new(out, 4096)))
+            }
+            _ => bail!("unsupported compression format: {format}"),
+        };
+        Ok(Self { inner, output })
+    }
+
+    /// Create a decoder for the given HTTP `Content-Encoding`, if it is one we can decode.
+    pub fn for_content_encoding(encoding: &str) -> Option<Self> {
+        let format = match encoding.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => "gzip",
+            "deflate" => "deflate",
+            "br" => "br",
+            _ => return None,
+        };
+        Self::new(format, true).ok()
+    }
+
+    /// Feed `data` into the codec and return whatever output is available so far.
+    ///
+    /// Fails if a decompressor would output more than `MAX_DECOMPRESSED_CHUNK_SIZE` bytes.
+    pub fn push(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match &mut self.inner {
+            Inner::GzipEncoder(w) => w.write_all(data)?,
+            Inner::GzipDecoder(w) => w.write_all(data)?,
+            Inner::ZlibEncoder(w) => w.write_all(data)?,
+            Inner::ZlibDecoder(w) => w.write_all(data)?,
+            Inner::DeflateEncoder(w) => w.write_all(data)?,
+            Inner::DeflateDecoder(w) => w.write_all(data)?,
+            Inner::BrotliEncoder(w) => w.write_all(data)?,
+            Inner::BrotliDecoder(w) => w.write_all(data)?,
+        }
+        Ok(self.output.take())
+    }
+
+    /// Finish the stream and return the remaining output.
+    pub fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self.inner {
+            Inner::GzipEncoder(w) => {
+                w.finish()?;
+            }
+            Inner::GzipDecoder(w) => {
+                w.finish()?;
+            }
+            Inner::ZlibEncoder(w) => {
+                w.finish()?;
+            }
+            Inner::ZlibDecoder(w) => {
+                w.finish()?;
+            }
+            Inner::DeflateEncoder(w) => {
+                w.finish()?;
+            }
+            Inner::DeflateDecoder(w) => {
+                w.finish()?;
+            }
+            Inner::BrotliEncoder(w) => {
+                w.into_inner();
+            }
+            Inner::BrotliDecoder(w) => {
+                if w.into_inner().is_err() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "truncated brotli stream",
+                    ));
+                }
+            }
+        }
+        Ok(self.output.take())
+    }
+}
+
+#[js::host_call(with_context)]
+fn compression_open(
+    service: ServiceRef,
+    _this: js::Value,
+    format: js::JsString,
+    decompress: bool,
+) -> Result<js::Value> {
+    let codec = Codec::new(format.as_str(), decompress)?;
+    Ok(js::Value::new_opaque_object(
+        service.context(),
+        Some("CompressionCodec"),
+        codec,
+    ))
+}
+
+#[js::host_call]
+fn compression_push(codec: js::Value, chunk: js::BytesOrString) -> Result<AsBytes<Vec<u8>>> {
+    let mut guard = codec.opaque_object_data_mut::<Codec>();
+    let codec = guard.get_mut().context("codec already finished")?;
+    let output = codec
+        .push(chunk.as_bytes())
+        .context("failed to process chunk")?;
+    Ok(AsBytes(output))
+}
+
+#[js::host_call]
+fn compression_finish(codec: js::Value) -> Result<AsBytes<Vec<u8>>> {
+    let codec = codec
+        .opaque_object_take_data::<Codec>()
+        .context("codec already finished")?;
+    let output = codec.finish().context("failed to finish stream")?;
+    Ok(AsBytes(output))
+}
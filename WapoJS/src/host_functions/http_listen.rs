@@ -2,6 +2,7 @@ use js::{FromJsValue, ToJsValue};
 use log::{debug, info, trace};
 
 use super::http_request::Headers;
+use super::stream::{InputStream, OutputStream};
 use super::*;
 use crate::service::OwnedJsValue;
 
@@ -58,12 +59,12 @@ pub(crate) fn try_accept_http_request(
         opaque_input_stream: js::Value::new_opaque_object(
             service.context(),
             Some("HttpInputBodyStream"),
-            input_stream,
+            InputStream::new(input_stream),
         ),
         opaque_output_stream: js::Value::new_opaque_object(
             service.context(),
             Some("HttpOutputBodyStream"),
-            output_stream,
+            OutputStream::new(output_stream),
         ),
     };
     if let Err(err) = service.call_function(listener, (req,)) {
@@ -1,9 +1,21 @@
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, bail, Context};
+use core::cell::RefCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::Poll;
+use hyper::service::Service as _;
 use log::{debug, info, log_enabled, trace, warn};
 use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, DuplexStream, ReadHalf, WriteHalf};
 
-use crate::service::OwnedJsValue;
+use super::compression::Codec;
+use super::stream::{InputStream, OutputStream};
+use crate::runtime::{
+    http_connector, http_connector_with_tls, HttpClientConnector, HyperExecutor, TlsClientConfig,
+};
+use crate::service::{HttpClientConfig, OwnedJsValue};
 use js::{Error as ValueError, FromJsValue, ToJsValue};
 
 use super::*;
@@ -75,6 +87,89 @@ pub struct HttpRequest {
     body: js::BytesOrString,
     #[qjs(default)]
     stream_body: bool,
+    /// Timeout in milliseconds for establishing each connection, including the TLS handshake.
+    connect_timeout: Option<u64>,
+    /// Timeout in milliseconds for the whole request, including reading the response body.
+    total_timeout: Option<u64>,
+    #[qjs(default)]
+    redirect: RedirectPolicy,
+    #[qjs(default = "default_max_redirects")]
+    max_redirects: u32,
+    /// The maximum number of (decoded) response body bytes to deliver to the script.
+    /// Unlimited by default, except for decoded bodies which are limited to
+    /// `DEFAULT_MAX_DECODED_RESPONSE_BYTES`.
+    max_response_bytes: Option<u64>,
+    tls: Option<TlsOptions>,
+}
+
+/// TLS settings for outgoing connections.
+#[derive(FromJsValue, Default)]
+#[qjs(rename_all = "camelCase")]
+pub struct TlsOptions {
+    /// PEM encoded root certificates trusted in addition to the built-in ones.
+    #[qjs(default)]
+    ca_certs: Vec<String>,
+    /// PEM encoded client certificate chain for mutual TLS.
+    client_cert: Option<String>,
+    /// PEM encoded private key of `client_cert`.
+    client_key: Option<String>,
+    /// Hex encoded SHA-256 hashes of the accepted server SubjectPublicKeyInfo.
+    #[qjs(default)]
+    pinned_spki_sha256: Vec<String>,
+}
+
+impl core::fmt::Debug for TlsOptions {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // Never log the private key
+        f.debug_struct("TlsOptions")
+            .field("ca_certs", &self.ca_certs.len())
+            .field("client_cert", &self.client_cert.is_some())
+            .field("pinned_spki_sha256", &self.pinned_spki_sha256)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TlsOptions {
+    pub fn build(&self) -> Result<TlsClientConfig> {
+        let identity = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => Some((cert.as_str(), key.as_str())),
+            (None, None) => None,
+            _ => bail!("clientCert and clientKey must be provided together"),
+        };
+        let pins = self
+            .pinned_spki_sha256
+            .iter()
+            .map(|pin| {
+                let pin = pin.trim_start_matches("0x");
+                let bytes = hex::decode(pin).context("invalid SPKI pin")?;
+                <[u8; 32]>::try_from(bytes).map_err(|_| anyhow!("SPKI pin must be 32 bytes"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        TlsClientConfig::new(&self.ca_certs, identity, &pins)
+    }
+}
+
+/// What to do when the server responds with a redirection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum RedirectPolicy {
+    /// Follow the redirection, up to `maxRedirects` times.
+    Follow,
+    /// Return the redirection response to the script.
+    #[default]
+    Manual,
+    /// Fail the request.
+    Error,
+}
+
+impl FromJsValue for RedirectPolicy {
+    fn from_js_value(value: js::Value) -> Result<Self, ValueError> {
+        match js::JsString::from_js_value(value)?.as_str() {
+            "follow" => Ok(Self::Follow),
+            "manual" => Ok(Self::Manual),
+            "error" => Ok(Self::Error),
+            other => Err(ValueError::msg(format!("invalid redirect policy: {other}"))),
+        }
+    }
 }
 
 #[derive(ToJsValue, Debug)]
@@ -91,6 +186,9 @@ struct HttpResponseHead {
     status_text: String,
     version: String,
     headers: Headers,
+    /// The final URL after following redirections.
+    url: String,
+    redirected: bool,
     opaque_body_stream: js::Value,
 }
 
@@ -99,7 +197,74 @@ pub fn setup(ns: &js::Value) -> Result<()> {
     Ok(())
 }
 
+pub(crate) type HttpClient = hyper::Client<TimeoutConnector, hyper::Body>;
+
+pub(crate) fn new_http_client(
+    config: &HttpClientConfig,
+    connect_timeout: Option<Duration>,
+) -> HttpClient {
+    build_http_client(config, http_connector(), connect_timeout)
+}
+
+fn build_http_client(
+    config: &HttpClientConfig,
+    connector: HttpClientConnector,
+    connect_timeout: Option<Duration>,
+) -> HttpClient {
+    hyper::Client::builder()
+        .executor(HyperExecutor)
+        .pool_idle_timeout(config.pool_idle_timeout)
+        .pool_max_idle_per_host(config.pool_max_idle_per_host)
+        .build(TimeoutConnector {
+            inner: connector,
+            timeout: connect_timeout,
+        })
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Fails a connection attempt, including the TLS handshake, that takes longer than `timeout`.
+///
+/// Each new connection gets its own timeout, so it applies anew to every redirect hop while
+/// waiting for the response head is only bounded by the total timeout.
+#[derive(Clone)]
+pub(crate) struct TimeoutConnector {
+    inner: HttpClientConnector,
+    timeout: Option<Duration>,
+}
+
+impl hyper::service::Service<hyper::Uri> for TimeoutConnector {
+    type Response = <HttpClientConnector as hyper::service::Service<hyper::Uri>>::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: hyper::Uri) -> Self::Future {
+        let connecting = self.inner.call(uri);
+        let timeout = self.timeout;
+        Box::pin(async move {
+            let Some(timeout) = timeout else {
+                return connecting.await.map_err(Into::into);
+            };
+            tokio::select! {
+                result = connecting => result.map_err(Into::into),
+                _ = crate::runtime::time::sleep(timeout) => {
+                    Err(anyhow!("connect timed out after {}ms", timeout.as_millis()).into())
+                }
+            }
+        })
+    }
+}
+
 pub(crate) const STREAM_BUF_SIZE: usize = 8192;
+/// Transparently decoded response bodies are limited to this size unless the request sets
+/// `maxResponseBytes`, as a few KiB of compressed data can expand to gigabytes.
+const DEFAULT_MAX_DECODED_RESPONSE_BYTES: u64 = 64 * 1024 * 1024;
+/// Encoded response chunks are fed to the decoder in pieces of this size.
+const DECODE_PIECE_SIZE: usize = 16 * 1024;
 struct Pipes {
     duplex_up: DuplexStream,
     duplex_down_rx: ReadHalf<DuplexStream>,
@@ -119,6 +284,36 @@ impl Pipes {
     }
 }
 
+/// The response body stream handed to the script.
+///
+/// The body is piped in by a background task after the head has been reported, so failures
+/// that happen while reading the body (timeouts, size limit, decoding errors) are recorded
+/// here and surfaced as a read error once the pipe is drained.
+pub(crate) struct HttpBodyReader {
+    pipe: ReadHalf<DuplexStream>,
+    error: Rc<RefCell<Option<String>>>,
+}
+
+impl tokio::io::AsyncRead for HttpBodyReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.pipe).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) if buf.filled().len() == filled => {
+                match this.error.borrow_mut().take() {
+                    Some(err) => Poll::Ready(Err(std::io::Error::other(err))),
+                    None => Poll::Ready(Ok(())),
+                }
+            }
+            other => other,
+        }
+    }
+}
+
 #[js::host_call(with_context)]
 fn http_request(
     service: ServiceRef,
@@ -131,7 +326,7 @@ fn http_request(
         Some(js::Value::new_opaque_object(
             service.context(),
             Some("HttpRequestUpStream"),
-            duplex_down_tx,
+            OutputStream::new(duplex_down_tx),
         ))
     } else {
         None
@@ -149,6 +344,22 @@ fn default_method() -> String {
     "GET".into()
 }
 
+fn default_max_redirects() -> u32 {
+    20
+}
+
+/// Run `fut` until it completes or `deadline` is reached.
+async fn until_deadline<T>(deadline: Option<Instant>, fut: impl Future<Output = T>) -> Option<T> {
+    let Some(deadline) = deadline else {
+        return Some(fut.await);
+    };
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    tokio::select! {
+        output = fut => Some(output),
+        _ = crate::runtime::time::sleep(remaining) => None,
+    }
+}
+
 async fn do_http_request(
     weak_service: ServiceWeakRef,
     id: u64,
@@ -167,28 +378,44 @@ async fn do_http_request(
     }
 }
 
-async fn do_http_request_inner(
-    weak_service: ServiceWeakRef,
-    id: u64,
-    req: HttpRequest,
-    pipes: Pipes,
-) -> Result<()> {
-    use crate::runtime::{http_connector, HyperExecutor};
-    use core::pin::pin;
-    use hyper::{body::HttpBody, Body};
-    use tokio::io::AsyncWriteExt;
-    let connector = http_connector();
-    let client = hyper::Client::builder()
-        .executor(HyperExecutor)
-        .build::<_, Body>(connector);
-    let uri: hyper::Uri = req
-        .url
-        .parse()
-        .with_context(|| format!("failed to parse url: {}", req.url))?;
-    let mut builder = hyper::Request::builder()
-        .method(req.method.to_uppercase().as_str())
-        .uri(&uri);
+/// Headers carrying credentials, which are not forwarded to another origin on redirects.
+const CREDENTIAL_HEADERS: [&str; 3] = ["authorization", "cookie", "proxy-authorization"];
+
+/// The `(scheme, host, port)` origin of a URI, with the default port filled in.
+fn origin_of(uri: &hyper::Uri) -> (Option<&str>, Option<&str>, Option<u16>) {
+    let scheme = uri.scheme_str();
+    let port = uri.port_u16().or(match scheme {
+        Some("https") => Some(443),
+        Some("http") => Some(80),
+        _ => None,
+    });
+    (scheme, uri.host(), port)
+}
+
+fn build_request(
+    req: &HttpRequest,
+    method: &str,
+    uri: &hyper::Uri,
+    body_bytes: Option<&[u8]>,
+    redirected: bool,
+    cross_origin: bool,
+) -> Result<(hyper::Request<hyper::Body>, Option<hyper::body::Sender>)> {
+    use hyper::Body;
+    let mut builder = hyper::Request::builder().method(method).uri(uri);
     for (k, v) in req.headers.pairs.iter() {
+        if redirected {
+            // These describe the original request and are recomputed for the new one
+            let k = k.to_ascii_lowercase();
+            if k == "host" || k == "content-length" {
+                continue;
+            }
+            if body_bytes.map_or(false, |b| b.is_empty()) && k == "content-type" {
+                continue;
+            }
+            if cross_origin && CREDENTIAL_HEADERS.contains(&k.as_str()) {
+                continue;
+            }
+        }
         builder = builder.header(k.as_str(), v.as_str());
     }
     let headers_map = builder
@@ -201,120 +428,296 @@ async fn do_http_request_inner(
     if !headers_map.contains_key("User-Agent") {
         headers_map.insert("User-Agent", "WapoJS/0.1.0".parse()?);
     }
-    // Remove unsupported headers
-    headers_map.remove("Accept-Encoding");
+    // Responses are decoded transparently unless the script asked for specific encodings
+    if !headers_map.contains_key("Accept-Encoding") {
+        headers_map.insert("Accept-Encoding", "gzip, deflate, br".parse()?);
+    }
 
     let (body_tx, body);
-    if req.stream_body {
-        let (tx, b) = Body::channel();
-        body_tx = Some(tx);
-        body = b;
-    } else {
-        let body_bytes = req.body.as_bytes().to_vec();
-        if !headers_map.contains_key("Content-Length") {
-            headers_map.insert("Content-Length", body_bytes.len().to_string().parse()?);
+    match body_bytes {
+        None => {
+            let (tx, b) = Body::channel();
+            body_tx = Some(tx);
+            body = b;
+        }
+        Some(body_bytes) => {
+            if !headers_map.contains_key("Content-Length") {
+                headers_map.insert("Content-Length", body_bytes.len().to_string().parse()?);
+            }
+            body = body_bytes.to_vec().into();
+            body_tx = None;
         }
-        body = body_bytes.into();
-        body_tx = None;
     }
     let request = builder.body(body).context("failed to build request")?;
-    let (mut duplex_up_rx, mut duplex_up_tx) = tokio::io::split(pipes.duplex_up);
-    let url = req.url.clone();
+    Ok((request, body_tx))
+}
+
+async fn do_http_request_inner(
+    weak_service: ServiceWeakRef,
+    id: u64,
+    req: HttpRequest,
+    pipes: Pipes,
+) -> Result<()> {
+    use core::pin::pin;
+    use hyper::body::HttpBody;
+    use tokio::io::AsyncWriteExt;
+    let connect_timeout = req.connect_timeout.map(Duration::from_millis);
+    let client = {
+        let service = weak_service
+            .upgrade()
+            .ok_or_else(|| anyhow!("service dropped before sending request"))?;
+        match &req.tls {
+            // Connections with custom TLS settings are never shared with other requests
+            Some(tls) => {
+                let tls = tls.build().context("invalid tls options")?;
+                build_http_client(
+                    service.http_client_config(),
+                    http_connector_with_tls(&tls),
+                    connect_timeout,
+                )
+            }
+            None => service.http_client(connect_timeout),
+        }
+    };
+    let deadline = req
+        .total_timeout
+        .map(|ms| Instant::now() + Duration::from_millis(ms));
+    let auto_decode = !req
+        .headers
+        .iter()
+        .any(|(k, _)| k.eq_ignore_ascii_case("Accept-Encoding"));
+    let mut uri: hyper::Uri = req
+        .url
+        .parse()
+        .with_context(|| format!("failed to parse url: {}", req.url))?;
+    let mut method = req.method.to_uppercase();
+    let mut body = (!req.stream_body).then(|| req.body.as_bytes().to_vec());
+    let (duplex_up_rx, mut duplex_up_tx) = tokio::io::split(pipes.duplex_up);
+    // The streamed request body can only be sent once
+    let mut duplex_up_rx = Some(duplex_up_rx);
+    let mut redirects = 0;
+    // Once the request has left its origin, credentials are never sent again
+    let mut cross_origin = false;
     const MAX_DBG_BODY_SIZE: usize = 1024 * 64;
-    if let Some(mut body_tx) = body_tx {
-        crate::runtime::spawn(async move {
-            let mut dbg_buf = vec![];
-            loop {
-                let mut buf = bytes::BytesMut::with_capacity(STREAM_BUF_SIZE);
-                let chunk: bytes::Bytes = match duplex_up_rx.read_buf(&mut buf).await {
-                    Ok(n) if n == 0 => break,
-                    Ok(_n) => buf.into(),
-                    Err(err) => {
-                        warn!(target: "js::httpc::body", "failed to read request body from pipe: {err}");
+    let response = loop {
+        let (request, body_tx) = build_request(
+            &req,
+            &method,
+            &uri,
+            body.as_deref(),
+            redirects > 0,
+            cross_origin,
+        )?;
+        if let (Some(mut body_tx), Some(mut duplex_up_rx)) = (body_tx, duplex_up_rx.take()) {
+            let url = req.url.clone();
+            crate::runtime::spawn(async move {
+                let mut dbg_buf = vec![];
+                loop {
+                    let mut buf = bytes::BytesMut::with_capacity(STREAM_BUF_SIZE);
+                    let chunk: bytes::Bytes = match duplex_up_rx.read_buf(&mut buf).await {
+                        Ok(n) if n == 0 => break,
+                        Ok(_n) => buf.into(),
+                        Err(err) => {
+                            warn!(target: "js::httpc::body", "failed to read request body from pipe: {err}");
+                            break;
+                        }
+                    };
+                    trace!(target: "js::httpc::chunk", "sending chunk: {}", hex_fmt::HexFmt(&chunk));
+                    if log_enabled!(target: "js::httpc::chunk", log::Level::Debug) {
+                        if dbg_buf.len() + chunk.len() <= MAX_DBG_BODY_SIZE {
+                            dbg_buf.extend_from_slice(&chunk);
+                        }
+                    }
+                    if body_tx.send_data(chunk).await.is_err() {
+                        warn!(target: "js::httpc::body", "failed to write request body to pipe");
                         break;
                     }
-                };
-                trace!(target: "js::httpc::chunk", "sending chunk: {}", hex_fmt::HexFmt(&chunk));
-                if log_enabled!(target: "js::httpc::chunk", log::Level::Debug) {
-                    if dbg_buf.len() + chunk.len() <= MAX_DBG_BODY_SIZE {
-                        dbg_buf.extend_from_slice(&chunk);
+                }
+                if log_enabled!(target: "js::httpc::body", log::Level::Trace) {
+                    if let Ok(body) = String::from_utf8(dbg_buf) {
+                        trace!(target: "js::httpc::body", "sent body to {url}:\n<<{body}>>\n");
                     }
                 }
-                if body_tx.send_data(chunk).await.is_err() {
-                    warn!(target: "js::httpc::body", "failed to write request body to pipe");
-                    break;
+            });
+        }
+        let response = until_deadline(deadline, client.request(request))
+            .await
+            .ok_or_else(|| anyhow!("timed out waiting for the response"))??;
+        trace!(target: "js::httpc::header", "response head: {response:#?}");
+        let status = response.status();
+        if !status.is_redirection() || req.redirect == RedirectPolicy::Manual {
+            break response;
+        }
+        let Some(location) = response.headers().get(hyper::header::LOCATION) else {
+            break response;
+        };
+        let location = location.to_str().context("invalid redirect location")?;
+        if req.redirect == RedirectPolicy::Error {
+            bail!("unexpected redirect to `{location}`");
+        }
+        redirects += 1;
+        if redirects > req.max_redirects {
+            bail!("too many redirects");
+        }
+        let next = ::url::Url::parse(&uri.to_string())
+            .and_then(|base| base.join(location))
+            .with_context(|| format!("invalid redirect location: {location}"))?;
+        debug!(target: "js::httpc", "redirecting to {next}");
+        let next: hyper::Uri = next.as_str().parse().context("invalid redirect location")?;
+        cross_origin |= origin_of(&next) != origin_of(&uri);
+        uri = next;
+        match status.as_u16() {
+            303 => {
+                if method != "HEAD" {
+                    method = "GET".into();
                 }
+                body = Some(vec![]);
             }
-            if log_enabled!(target: "js::httpc::body", log::Level::Trace) {
-                if let Ok(body) = String::from_utf8(dbg_buf) {
-                    trace!(target: "js::httpc::body", "sent body to {url}:\n<<{body}>>\n");
-                }
+            301 | 302 if method == "POST" => {
+                method = "GET".into();
+                body = Some(vec![]);
             }
-        });
+            _ if body.is_none() => {
+                bail!("can not follow redirect {status} with a streamed request body");
+            }
+            _ => {}
+        }
+    };
+    let mut decoder = None;
+    if auto_decode {
+        if let Some(encoding) = response.headers().get(hyper::header::CONTENT_ENCODING) {
+            decoder = Codec::for_content_encoding(encoding.to_str().unwrap_or_default());
+        }
     }
+    let max_response_bytes = req.max_response_bytes.unwrap_or(match decoder {
+        Some(_) => DEFAULT_MAX_DECODED_RESPONSE_BYTES,
+        None => u64::MAX,
+    });
+    let content_length = response
+        .headers()
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+    if content_length.map_or(false, |len| len > max_response_bytes) {
+        bail!("response body too large: {content_length:?} bytes");
+    }
+    let headers = response
+        .headers()
+        .iter()
+        .filter(|(k, _)| {
+            // The length and encoding no longer describe the body once it is decoded
+            decoder.is_none()
+                || (*k != hyper::header::CONTENT_ENCODING && *k != hyper::header::CONTENT_LENGTH)
+        })
+        .map(|(k, v)| (k.as_str().into(), v.to_str().unwrap_or_default().into()))
+        .collect();
+    let status = response.status().as_u16();
+    let status_text = response
+        .status()
+        .canonical_reason()
+        .unwrap_or_default()
+        .into();
+    let version = format!("{:?}", response.version());
+    let body_error = Rc::new(RefCell::new(None));
     {
-        let response = client.request(request).await?;
-        trace!(target: "js::httpc::header", "response head: {response:#?}");
-        let head = {
-            let headers = response
-                .headers()
-                .iter()
-                .map(|(k, v)| (k.as_str().into(), v.to_str().unwrap_or_default().into()))
-                .collect();
-            let status = response.status().as_u16();
-            let status_text = response
-                .status()
-                .canonical_reason()
-                .unwrap_or_default()
-                .into();
-            let version = format!("{:?}", response.version());
-            let url = req.url.clone();
-            crate::runtime::spawn(async move {
-                let mut response = pin!(response);
-                let mut dbg_buf = vec![];
-                // TODO: add timeout?
-                while let Some(chunk) = response.data().await {
-                    let Ok(chunk) = chunk else {
-                        warn!(target: "js::httpc::body", "failed to read response body");
+        let url = uri.to_string();
+        let body_error = body_error.clone();
+        crate::runtime::spawn(async move {
+            let mut response = pin!(response);
+            let mut dbg_buf = vec![];
+            let mut received = 0_u64;
+            let result: Result<()> = async {
+                loop {
+                    let chunk = until_deadline(deadline, response.data())
+                        .await
+                        .ok_or_else(|| anyhow!("timed out reading the response body"))?;
+                    let Some(chunk) = chunk else {
                         break;
                     };
+                    let chunk = chunk.context("failed to read response body")?;
                     trace!(target: "js::httpc::chunk", "received chunk: {}", hex_fmt::HexFmt(&chunk));
-                    if log_enabled!(target: "js::httpc::body", log::Level::Trace) {
-                        if dbg_buf.len() + chunk.len() <= MAX_DBG_BODY_SIZE {
-                            dbg_buf.extend_from_slice(&chunk);
+                    // Encoded chunks are decoded in pieces, which bounds what each inflates to.
+                    let pieces: Vec<bytes::Bytes> = match decoder {
+                        Some(_) => (0..chunk.len())
+                            .step_by(DECODE_PIECE_SIZE)
+                            .map(|start| {
+                                chunk.slice(start..chunk.len().min(start + DECODE_PIECE_SIZE))
+                            })
+                            .collect(),
+                        None => vec![chunk],
+                    };
+                    for piece in pieces {
+                        let piece: bytes::Bytes = match decoder.as_mut() {
+                            Some(decoder) => decoder
+                                .push(&piece)
+                                .context("failed to decode response body")?
+                                .into(),
+                            None => piece,
+                        };
+                        received += piece.len() as u64;
+                        if received > max_response_bytes {
+                            bail!("response body exceeds {max_response_bytes} bytes");
                         }
-                    }
-                    if duplex_up_tx.write_all(&chunk).await.is_err() {
-                        warn!(target: "js::httpc::body", "failed to write response body to pipe");
-                        break;
+                        if log_enabled!(target: "js::httpc::body", log::Level::Trace) {
+                            if dbg_buf.len() + piece.len() <= MAX_DBG_BODY_SIZE {
+                                dbg_buf.extend_from_slice(&piece);
+                            }
+                        }
+                        duplex_up_tx
+                            .write_all(&piece)
+                            .await
+                            .context("failed to write response body to pipe")?;
                     }
                 }
-                if log_enabled!(target: "js::httpc::body", log::Level::Trace) {
-                    if let Ok(body) = String::from_utf8(dbg_buf) {
-                        trace!(target: "js::httpc::body", "received body from {url}:\n<<{body}>>\n");
+                if let Some(decoder) = decoder.take() {
+                    let rest = decoder.finish().context("failed to decode response body")?;
+                    received += rest.len() as u64;
+                    if received > max_response_bytes {
+                        bail!("response body exceeds {max_response_bytes} bytes");
                     }
+                    duplex_up_tx
+                        .write_all(&rest)
+                        .await
+                        .context("failed to write response body to pipe")?;
                 }
-                duplex_up_tx.shutdown().await.ok();
-            });
-            let service = weak_service
-                .clone()
-                .upgrade()
-                .ok_or_else(|| anyhow!("service dropped while reading response body"))?;
-            let opaque_body_stream = js::Value::new_opaque_object(
-                service.context(),
-                Some("HttpBodyStream"),
-                pipes.duplex_down_rx,
-            );
-            HttpResponseHead {
-                status,
-                status_text,
-                version,
-                headers,
-                opaque_body_stream,
+                Ok(())
             }
-        };
-        invoke_callback(&weak_service, id, "head", &head);
+            .await;
+            if let Err(err) = result {
+                warn!(target: "js::httpc::body", "{url}: {err:?}");
+                body_error.borrow_mut().replace(format!("{err:?}"));
+            }
+            if log_enabled!(target: "js::httpc::body", log::Level::Trace) {
+                if let Ok(body) = String::from_utf8(dbg_buf) {
+                    trace!(target: "js::httpc::body", "received body from {url}:\n<<{body}>>\n");
+                }
+            }
+            duplex_up_tx.shutdown().await.ok();
+        });
     }
+    let head = {
+        let service = weak_service
+            .clone()
+            .upgrade()
+            .ok_or_else(|| anyhow!("service dropped while reading response body"))?;
+        let opaque_body_stream = js::Value::new_opaque_object(
+            service.context(),
+            Some("HttpBodyStream"),
+            InputStream::new(HttpBodyReader {
+                pipe: pipes.duplex_down_rx,
+                error: body_error,
+            }),
+        );
+        HttpResponseHead {
+            status,
+            status_text,
+            version,
+            headers,
+            url: uri.to_string(),
+            redirected: redirects > 0,
+            opaque_body_stream,
+        }
+    };
+    invoke_callback(&weak_service, id, "head", &head);
     Ok(())
 }
 
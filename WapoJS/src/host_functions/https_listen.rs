@@ -1,26 +1,34 @@
+use std::cell::Cell;
 use std::fmt::Debug;
+use std::future::Future;
 use std::net::SocketAddr;
+use std::rc::Rc;
 use std::str::FromStr;
+use std::time::Duration;
 
 use anyhow::{bail, Context, Result};
 use futures::TryStreamExt;
-use http::header::UPGRADE;
+use http::header::{HeaderName, HeaderValue, TRAILER, UPGRADE};
+use http::HeaderMap;
 use http_body_util::BodyExt;
 use http_request::STREAM_BUF_SIZE;
 use hyper1 as hyper;
 use js::{FromJsValue, ToJsValue};
-use log::{info, trace};
+use log::{debug, info, trace};
 use tokio::io::{duplex, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
 
 use hyper::body::{Body, Frame, SizeHint};
-use hyper::server::conn::http1::Builder;
 use hyper::service::service_fn;
-use tokio::sync::oneshot;
+use hyper_util::server::conn::auto::Builder;
+use tokio::sync::{oneshot, watch, Notify};
 use wapo::hyper_rt::HyperTokioIo;
 
 use super::http_request::Headers;
+use super::stream::{InputStream, OutputStream};
 use super::*;
-use crate::runtime::{self, sni_listen, tcp_accept, TcpListener, TcpStream, TlsListener};
+use crate::runtime::{
+    self, sni_listen, tcp_accept, ServerTlsInfo, TcpListener, TcpStream, TlsListener,
+};
 use crate::service::OwnedJsValue;
 
 trait Listener {
@@ -41,9 +49,28 @@ impl Listener for TcpListener {
     }
 }
 
+/// Spawns the per-stream tasks of HTTP/2 connections on the local runtime.
+#[derive(Clone, Copy)]
+struct LocalExecutor;
+
+impl<F: Future + 'static> hyper::rt::Executor<F> for LocalExecutor {
+    fn execute(&self, fut: F) {
+        runtime::spawn(fut);
+    }
+}
+
 #[pin_project::pin_project(project = ResponseBodyProj)]
 enum ResponseBody<T> {
-    Stream(#[pin] T),
+    Stream {
+        #[pin]
+        body: T,
+        /// Trailers sent by JS, taken once the body stream is closed.
+        trailers: Option<oneshot::Receiver<HeaderMap>>,
+        /// Whether the response announced trailers with a `Trailer` header, in which case the
+        /// body waits for them after the stream is closed, until the sender is dropped.
+        wait_for_trailers: bool,
+        body_done: bool,
+    },
     Empty,
 }
 
@@ -59,9 +86,25 @@ where
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        use std::task::Poll;
+
         let result = match self.project() {
-            ResponseBodyProj::Stream(body) => body.poll_frame(cx),
-            ResponseBodyProj::Empty => std::task::Poll::Ready(None),
+            ResponseBodyProj::Stream {
+                body,
+                trailers,
+                wait_for_trailers,
+                body_done,
+            } => match *body_done {
+                true => poll_trailers(trailers, *wait_for_trailers, cx),
+                false => match body.poll_frame(cx) {
+                    Poll::Ready(None) => {
+                        *body_done = true;
+                        poll_trailers(trailers, *wait_for_trailers, cx)
+                    }
+                    other => other,
+                },
+            },
+            ResponseBodyProj::Empty => Poll::Ready(None),
         };
         trace!(target: "js::https", "response body poll_frame: {result:?}");
         result
@@ -69,26 +112,89 @@ where
 
     fn is_end_stream(&self) -> bool {
         match self {
-            ResponseBody::Stream(body) => body.is_end_stream(),
+            ResponseBody::Stream {
+                body,
+                trailers,
+                body_done,
+                ..
+            } => (*body_done || body.is_end_stream()) && trailers.is_none(),
             ResponseBody::Empty => true,
         }
     }
 
     fn size_hint(&self) -> SizeHint {
         match self {
-            ResponseBody::Stream(body) => body.size_hint(),
+            ResponseBody::Stream { body, .. } => body.size_hint(),
             ResponseBody::Empty => SizeHint::with_exact(0),
         }
     }
 }
 
+/// Polls the trailers of a response whose body stream has been closed.
+fn poll_trailers<D, E>(
+    trailers: &mut Option<oneshot::Receiver<HeaderMap>>,
+    wait_for_trailers: bool,
+    cx: &mut std::task::Context<'_>,
+) -> std::task::Poll<Option<Result<Frame<D>, E>>> {
+    use std::task::Poll;
+
+    let Some(rx) = trailers.as_mut() else {
+        return Poll::Ready(None);
+    };
+    let received = if wait_for_trailers {
+        match std::pin::Pin::new(rx).poll(cx) {
+            Poll::Ready(received) => received.ok(),
+            Poll::Pending => return Poll::Pending,
+        }
+    } else {
+        rx.try_recv().ok()
+    };
+    *trailers = None;
+    Poll::Ready(received.map(|map| Ok(Frame::trailers(map))))
+}
+
 #[derive(ToJsValue, Debug)]
 #[qjs(rename_all = "camelCase")]
 pub struct HttpRequest {
     method: String,
     url: String,
     headers: Headers,
+    remote_addr: String,
+    http_version: String,
+    tls: Option<TlsMetadata>,
     opaque_response_tx: js::Value,
+    opaque_trailers_tx: js::Value,
+    opaque_input_stream: js::Value,
+    opaque_output_stream: js::Value,
+}
+
+/// TLS details of an accepted connection, as seen by JS.
+#[derive(ToJsValue, Debug, Clone)]
+#[qjs(rename_all = "camelCase")]
+pub struct TlsMetadata {
+    /// The SNI sent by the client.
+    server_name: Option<String>,
+    /// The negotiated protocol version, e.g. `TLSv1.3`.
+    version: Option<String>,
+    /// The negotiated ALPN protocol, e.g. `h2`.
+    alpn_protocol: Option<String>,
+}
+
+impl From<ServerTlsInfo> for TlsMetadata {
+    fn from(info: ServerTlsInfo) -> Self {
+        Self {
+            server_name: info.server_name,
+            version: info.protocol_version,
+            alpn_protocol: info.alpn_protocol,
+        }
+    }
+}
+
+#[derive(ToJsValue, Debug)]
+#[qjs(rename_all = "camelCase")]
+pub struct TcpConnection {
+    remote_addr: String,
+    tls: Option<TlsMetadata>,
     opaque_input_stream: js::Value,
     opaque_output_stream: js::Value,
 }
@@ -133,33 +239,66 @@ struct HttpResponseHead {
 impl FromStr for HttpResponseHead {
     type Err = anyhow::Error;
 
+    /// Parses an HTTP/1.x response head such as `"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n"`.
+    ///
+    /// Both CRLF and bare LF line endings are accepted and the terminating empty line is
+    /// optional. Obsolete folded header lines are joined with a single space.
     fn from_str(s: &str) -> Result<Self> {
-        let todo = "use standard parser";
-        let mut lines = s.lines();
-        let status = lines
-            .next()
-            .context("failed to parse status line")?
-            .split_whitespace()
-            .nth(1)
-            .context("no status code found")?
-            .parse()
-            .context("failed to parse status code")?;
-        let headers = lines
-            .map(|line| {
-                let mut parts = line.splitn(2, ": ");
-                let key = parts.next().context("failed to parse header key")?;
-                let value = parts.next().context("failed to parse header value")?;
-                Ok((key.to_string(), value.to_string()))
+        let mut head = s.trim_end_matches(['\r', '\n']).to_string();
+        if head.is_empty() {
+            bail!("empty response head");
+        }
+        head.push_str("\r\n\r\n");
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_RESPONSE_HEADERS];
+        let mut response = httparse::Response::new(&mut headers);
+        let status = httparse::ParserConfig::default()
+            .allow_obsolete_multiline_headers_in_responses(true)
+            .parse_response(&mut response, head.as_bytes())
+            .map_err(|err| anyhow::anyhow!("malformed response head: {err}"))?;
+        match status {
+            httparse::Status::Complete(len) if len == head.len() => {}
+            httparse::Status::Complete(_) => bail!("unexpected data after response head"),
+            httparse::Status::Partial => bail!("incomplete response head"),
+        }
+        let status = response.code.context("no status code found")?;
+        let headers = response
+            .headers
+            .iter()
+            .map(|header| {
+                let value = std::str::from_utf8(header.value).with_context(|| {
+                    format!("invalid utf-8 in value of header {:?}", header.name)
+                })?;
+                Ok((header.name.to_string(), unfold_header_value(value)))
             })
             .collect::<Result<Headers>>()?;
         Ok(HttpResponseHead { status, headers })
     }
 }
 
+const MAX_RESPONSE_HEADERS: usize = 128;
+
+/// Joins the lines of an obs-folded header value with single spaces.
+fn unfold_header_value(value: &str) -> String {
+    if !value.contains(['\r', '\n']) {
+        return value.to_string();
+    }
+    value
+        .split(['\r', '\n'])
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
 pub fn setup(ns: &js::Value) -> Result<()> {
     ns.define_property_fn("httpsListen", https_listen)?;
     ns.define_property_fn("httpsSendResponseHead", https_send_response_head)?;
     ns.define_property_fn("httpsSendResponseHeadRaw", https_send_response_head_raw)?;
+    ns.define_property_fn("httpsSendResponseTrailers", https_send_response_trailers)?;
+    ns.define_property_fn("tcpListen", tcp_listen)?;
+    ns.define_property_fn("listenerStats", listener_stats)?;
+    ns.define_property_fn("listenerShutdown", listener_shutdown)?;
     Ok(())
 }
 
@@ -169,34 +308,339 @@ fn https_listen(
     _this: js::Value,
     config: ServerConfig,
     callback: OwnedJsValue,
-) -> Result<u64> {
-    match config {
+) -> Result<ListenerReceipt> {
+    start_listener(&service, config, callback, serve_connection)
+}
+
+#[js::host_call(with_context)]
+fn tcp_listen(
+    service: ServiceRef,
+    _this: js::Value,
+    config: ServerConfig,
+    callback: OwnedJsValue,
+) -> Result<ListenerReceipt> {
+    start_listener(&service, config, callback, serve_tcp_connection)
+}
+
+#[derive(ToJsValue, Debug)]
+#[qjs(rename_all = "camelCase")]
+struct ListenerReceipt {
+    cancel_token: u64,
+    opaque_control: js::Value,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ShutdownState {
+    Running,
+    /// Stop accepting and let in-flight connections finish.
+    Draining,
+    /// Drop all connections immediately.
+    Aborted,
+}
+
+/// Shared between a listener, the connections it accepted and the JS side handle.
+struct ListenerControl {
+    state: watch::Sender<ShutdownState>,
+    active_connections: Cell<usize>,
+    connection_closed: Notify,
+}
+
+impl ListenerControl {
+    fn new() -> Rc<Self> {
+        Rc::new(Self {
+            state: watch::channel(ShutdownState::Running).0,
+            active_connections: Cell::new(0),
+            connection_closed: Notify::new(),
+        })
+    }
+
+    fn state(&self) -> ShutdownState {
+        *self.state.borrow()
+    }
+
+    /// Moves the listener towards `state`. A shutdown can only escalate, never be undone.
+    fn shutdown(&self, state: ShutdownState) {
+        self.state.send_if_modified(|current| {
+            if *current < state {
+                *current = state;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    async fn wait_for_drain(&self) {
+        loop {
+            let notified = self.connection_closed.notified();
+            if self.active_connections.get() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Counts a connection as active until it is dropped.
+struct ConnectionGuard(Rc<ListenerControl>);
+
+impl ConnectionGuard {
+    fn new(control: Rc<ListenerControl>) -> Self {
+        control
+            .active_connections
+            .set(control.active_connections.get() + 1);
+        Self(control)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let control = &self.0;
+        control
+            .active_connections
+            .set(control.active_connections.get() - 1);
+        control.connection_closed.notify_waiters();
+    }
+}
+
+/// Aborts the remaining connections when the listener task goes away, e.g. by `Wapo.close`.
+struct AbortOnDrop(Rc<ListenerControl>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.shutdown(ShutdownState::Aborted);
+    }
+}
+
+/// A connection accepted by a listener.
+struct Accepted {
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+    tls: Option<TlsMetadata>,
+    shutdown: watch::Receiver<ShutdownState>,
+}
+
+fn start_listener<Fut>(
+    service: &Service,
+    config: ServerConfig,
+    callback: OwnedJsValue,
+    serve: fn(ServiceWeakRef, u64, Accepted) -> Fut,
+) -> Result<ListenerReceipt>
+where
+    Fut: Future<Output = ()> + 'static,
+{
+    let control = ListenerControl::new();
+    let cancel_token = match config {
         ServerConfig::Https(config) => {
             let listener = sni_listen(
                 &config.server_name,
                 &config.certificate_chain,
                 &config.private_key,
             )?;
-            let res_id = service.spawn(callback, do_https_listen, listener);
-            Ok(res_id)
+            // Used when the runtime can not tell the details of the TLS session.
+            let default_tls = ServerTlsInfo {
+                server_name: Some(config.server_name.as_str().into()),
+                ..Default::default()
+            };
+            service.spawn(
+                callback,
+                move |weak_service, id, args| do_listen(weak_service, id, args, serve),
+                (listener, control.clone(), Some(default_tls)),
+            )
         }
         ServerConfig::Http(config) => {
-            info!(target: "js::https", "listening HTTP on {}", config.address.as_str());
+            info!(target: "js::https", "listening on {}", config.address.as_str());
             let listener = TcpListener::bind(config.address.as_str());
             let listener =
                 futures::executor::block_on(listener).context("failed to bind tcp listener")?;
-            let res_id = service.spawn(callback, do_https_listen, listener);
-            Ok(res_id)
+            service.spawn(
+                callback,
+                move |weak_service, id, args| do_listen(weak_service, id, args, serve),
+                (listener, control.clone(), None),
+            )
         }
+    };
+    Ok(ListenerReceipt {
+        cancel_token,
+        opaque_control: js::Value::new_opaque_object(
+            service.context(),
+            Some("ListenerControl"),
+            control,
+        ),
+    })
+}
+
+async fn do_listen<Fut>(
+    weak_service: ServiceWeakRef,
+    id: u64,
+    (mut listener, control, default_tls): (
+        impl Listener,
+        Rc<ListenerControl>,
+        Option<ServerTlsInfo>,
+    ),
+    serve: fn(ServiceWeakRef, u64, Accepted) -> Fut,
+) where
+    Fut: Future<Output = ()> + 'static,
+{
+    let _abort_on_drop = AbortOnDrop(control.clone());
+    let mut shutdown = control.state.subscribe();
+    while control.state() == ShutdownState::Running {
+        let (stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    info!(target: "js::https", "{err}");
+                    break;
+                }
+            },
+            _ = shutdown.changed() => break,
+        };
+        trace!(target: "js::https", "connection accepted: {remote_addr:?}");
+        let guard = ConnectionGuard::new(control.clone());
+        let tls = runtime::server_tls_info(&stream)
+            .or_else(|| default_tls.clone())
+            .map(Into::into);
+        let accepted = Accepted {
+            stream,
+            remote_addr,
+            tls,
+            shutdown: control.state.subscribe(),
+        };
+        let connection = serve(weak_service.clone(), id, accepted);
+        runtime::spawn(async move {
+            connection.await;
+            drop(guard);
+        });
     }
+    drop(listener);
+    info!(
+        target: "js::https",
+        "listener stopped accepting, {} connections in flight",
+        control.active_connections.get()
+    );
+    control.wait_for_drain().await;
+    info!(target: "js::https", "listener terminated");
+}
+
+#[derive(FromJsValue, Debug)]
+#[qjs(rename_all = "camelCase")]
+struct ShutdownOptions {
+    /// Drain in-flight connections before closing. Defaults to true; pass false to abort them.
+    #[qjs(default = "default_graceful")]
+    graceful: bool,
+    /// How long to wait for in-flight connections before aborting them.
+    timeout_ms: Option<u64>,
 }
 
-async fn do_https_listen(weak_service: ServiceWeakRef, id: u64, mut listener: impl Listener) {
-    while let Ok((stream, addr)) = listener.accept().await {
-        trace!(target: "js::https", "https connection accepted: {addr:?}");
-        runtime::spawn(serve_connection(weak_service.clone(), id, stream));
+fn default_graceful() -> bool {
+    true
+}
+
+#[derive(ToJsValue, Debug)]
+#[qjs(rename_all = "camelCase")]
+struct ListenerStats {
+    accepting: bool,
+    active_connections: u64,
+}
+
+impl ListenerStats {
+    fn of(control: &ListenerControl) -> Self {
+        Self {
+            accepting: control.state() == ShutdownState::Running,
+            active_connections: control.active_connections.get() as u64,
+        }
+    }
+}
+
+#[js::host_call]
+fn listener_stats(control: js::Value) -> Result<ListenerStats> {
+    let guard = control.opaque_object_data::<Rc<ListenerControl>>();
+    let control = guard.get().context("invalid listener control")?;
+    Ok(ListenerStats::of(control))
+}
+
+#[js::host_call(with_context)]
+fn listener_shutdown(
+    service: ServiceRef,
+    _this: js::Value,
+    control: js::Value,
+    options: ShutdownOptions,
+    callback: OwnedJsValue,
+) -> Result<u64> {
+    let control = {
+        let guard = control.opaque_object_data::<Rc<ListenerControl>>();
+        guard.get().context("invalid listener control")?.clone()
+    };
+    debug!(target: "js::https", "shutting down listener: {options:?}");
+    Ok(service.spawn(callback, do_listener_shutdown, (control, options)))
+}
+
+async fn do_listener_shutdown(
+    weak_service: ServiceWeakRef,
+    id: u64,
+    (control, options): (Rc<ListenerControl>, ShutdownOptions),
+) {
+    if options.graceful {
+        control.shutdown(ShutdownState::Draining);
+        let drain = control.wait_for_drain();
+        match options.timeout_ms {
+            Some(timeout_ms) => {
+                tokio::select! {
+                    _ = drain => {}
+                    _ = runtime::time::sleep(Duration::from_millis(timeout_ms)) => {
+                        info!(
+                            target: "js::https",
+                            "graceful shutdown timed out, aborting {} connections",
+                            control.active_connections.get()
+                        );
+                    }
+                }
+            }
+            None => drain.await,
+        }
+    }
+    let stats = ListenerStats::of(&control);
+    control.shutdown(ShutdownState::Aborted);
+    let Some(service) = weak_service.upgrade() else {
+        return;
+    };
+    let Some(callback) = service.get_resource_value(id) else {
+        return;
+    };
+    if let Err(err) = service.call_function(callback, (stats,)) {
+        error!(target: "js::https", "failed to report listener shutdown: {err}");
+    }
+}
+
+/// Hands the connection over to JS. Once handed over, the connection is owned by the JS
+/// streams and is no longer tracked by the listener.
+async fn serve_tcp_connection(weak_service: ServiceWeakRef, id: u64, accepted: Accepted) {
+    let Some(service) = weak_service.upgrade() else {
+        info!(target: "js::https", "dropped tcp connection because service has been dropped");
+        return;
+    };
+    let Some(callback) = service.get_resource_value(id) else {
+        info!(target: "js::https", "dropped tcp connection because resource has been dropped");
+        return;
+    };
+    let (from_c_rx, to_c_tx) = tokio::io::split(accepted.stream);
+    let connection = TcpConnection {
+        remote_addr: accepted.remote_addr.to_string(),
+        tls: accepted.tls,
+        opaque_input_stream: js::Value::new_opaque_object(
+            service.context(),
+            Some("TcpInputStream"),
+            InputStream::new(from_c_rx),
+        ),
+        opaque_output_stream: js::Value::new_opaque_object(
+            service.context(),
+            Some("TcpOutputStream"),
+            OutputStream::new(to_c_tx),
+        ),
+    };
+    if let Err(err) = service.call_function(callback, (connection,)) {
+        error!(target: "js::https", "failed to fire tcp connection event: {err}");
     }
-    info!(target: "js::https", "https listener terminated");
 }
 
 async fn wait_for_response(
@@ -216,110 +660,172 @@ async fn wait_for_response(
     Ok(builder)
 }
 
-async fn serve_connection(weak_service: ServiceWeakRef, id: u64, connection: TcpStream) {
-    let result = Builder::new()
-        .serve_connection(
-            HyperTokioIo::new(connection),
-            service_fn(move |req| {
-                let weak_service = weak_service.clone();
-                async move {
-                    let (resposne_tx, response_rx) =
-                        oneshot::channel::<HttpResponseHead>();
-                    let (up_end, down_end) = duplex(STREAM_BUF_SIZE);
-                    let (from_c_rx, to_c_tx) = tokio::io::split(up_end);
-                    let (from_s_rx, mut to_s_tx) = tokio::io::split(down_end);
-                    {
-                        let Some(service) = weak_service.upgrade() else {
-                            anyhow::bail!("service dropped");
-                        };
-                        let opaque_response_tx = js::Value::new_opaque_object(
-                            service.context(),
-                            Some("HttpResponseTx"),
-                            resposne_tx,
-                        );
-                        let opaque_input_stream = js::Value::new_opaque_object(
-                            service.context(),
-                            Some("HttpInputBodyStream"),
-                            from_c_rx,
-                        );
-                        let opaque_output_stream = js::Value::new_opaque_object(
-                            service.context(),
-                            Some("HttpOutputBodyStream"),
-                            to_c_tx,
-                        );
-                        let request = HttpRequest {
-                            method: req.method().as_str().to_string(),
-                            url: req.uri().to_string(),
-                            headers: req
-                                .headers()
-                                .into_iter()
-                                .map(|(k, v)| (k.as_str().to_string(), v.to_str().unwrap().to_string()))
-                                .collect::<Vec<_>>()
-                                .into(),
-                            opaque_response_tx,
-                            opaque_input_stream,
-                            opaque_output_stream,
-                        };
-                        trace!(target: "js::https", "http request: {request:#?}");
-                        let Some(callback) = service.get_resource_value(id) else {
-                            info!(target: "js::https", "dropped https reqest because resource has been dropped");
-                            bail!("dropped https reqest because resource has been dropped");
-                        };
-                        if let Err(err) = service.call_function(callback, (request,)) {
-                            error!(target: "js::https", "failed to fire http request event: {err}");
-                            bail!("failed to fire http request event: {err}");
+async fn serve_connection(weak_service: ServiceWeakRef, id: u64, accepted: Accepted) {
+    let Accepted {
+        stream,
+        remote_addr,
+        tls,
+        shutdown,
+    } = accepted;
+    // Clients that negotiated h2 via ALPN speak HTTP/2 right away, the others are served by
+    // the auto builder which also detects the HTTP/2 preface of cleartext h2c.
+    let negotiated_h2 = tls
+        .as_ref()
+        .is_some_and(|tls| tls.alpn_protocol.as_deref() == Some("h2"));
+    let handler = service_fn(move |req| {
+        let weak_service = weak_service.clone();
+        let tls = tls.clone();
+        async move {
+            let (resposne_tx, response_rx) = oneshot::channel::<HttpResponseHead>();
+            let (trailers_tx, trailers_rx) = oneshot::channel::<HeaderMap>();
+            let (up_end, down_end) = duplex(STREAM_BUF_SIZE);
+            let (from_c_rx, to_c_tx) = tokio::io::split(up_end);
+            let (from_s_rx, mut to_s_tx) = tokio::io::split(down_end);
+            {
+                let Some(service) = weak_service.upgrade() else {
+                    anyhow::bail!("service dropped");
+                };
+                let opaque_response_tx = js::Value::new_opaque_object(
+                    service.context(),
+                    Some("HttpResponseTx"),
+                    resposne_tx,
+                );
+                let opaque_trailers_tx = js::Value::new_opaque_object(
+                    service.context(),
+                    Some("HttpTrailersTx"),
+                    trailers_tx,
+                );
+                let opaque_input_stream = js::Value::new_opaque_object(
+                    service.context(),
+                    Some("HttpInputBodyStream"),
+                    InputStream::new(from_c_rx),
+                );
+                let opaque_output_stream = js::Value::new_opaque_object(
+                    service.context(),
+                    Some("HttpOutputBodyStream"),
+                    OutputStream::new(to_c_tx),
+                );
+                let request = HttpRequest {
+                    method: req.method().as_str().to_string(),
+                    url: req.uri().to_string(),
+                    headers: req
+                        .headers()
+                        .into_iter()
+                        .map(|(k, v)| (k.as_str().to_string(), v.to_str().unwrap().to_string()))
+                        .collect::<Vec<_>>()
+                        .into(),
+                    remote_addr: remote_addr.to_string(),
+                    http_version: format!("{:?}", req.version()),
+                    tls,
+                    opaque_response_tx,
+                    opaque_trailers_tx,
+                    opaque_input_stream,
+                    opaque_output_stream,
+                };
+                trace!(target: "js::https", "http request: {request:#?}");
+                let Some(callback) = service.get_resource_value(id) else {
+                    info!(target: "js::https", "dropped https reqest because resource has been dropped");
+                    bail!("dropped https reqest because resource has been dropped");
+                };
+                if let Err(err) = service.call_function(callback, (request,)) {
+                    error!(target: "js::https", "failed to fire http request event: {err}");
+                    bail!("failed to fire http request event: {err}");
+                }
+            };
+            match req.headers().contains_key(UPGRADE) {
+                false => {
+                    let mut body = req.into_data_stream();
+                    // pipe to down_end_tx
+                    runtime::spawn(async move {
+                        loop {
+                            let chunk: bytes::Bytes = match body.try_next().await {
+                                Ok(Some(chunk)) => chunk,
+                                Ok(None) => break,
+                                Err(err) => {
+                                    error!(target: "js::https", "failed to read body: {err}");
+                                    break;
+                                }
+                            };
+                            if let Err(err) = to_s_tx.write_all(&chunk).await {
+                                error!(target: "js::https", "failed to send body: {err}");
+                                break;
+                            }
                         }
+                        to_s_tx.shutdown().await.ok();
+                    });
+                    let builder = wait_for_response(response_rx).await?;
+                    let wait_for_trailers = builder
+                        .headers_ref()
+                        .is_some_and(|headers| headers.contains_key(TRAILER));
+                    let stream = tokio_util::io::ReaderStream::new(from_s_rx);
+                    let stream = stream.map_ok(|chunk| hyper::body::Frame::data(chunk));
+                    let body = ResponseBody::Stream {
+                        body: http_body_util::StreamBody::new(stream),
+                        trailers: Some(trailers_rx),
+                        wait_for_trailers,
+                        body_done: false,
                     };
-                    match req.headers().contains_key(UPGRADE) {
-                        false => {
-                            let mut body = req.into_data_stream();
-                            // pipe to down_end_tx
-                            runtime::spawn(async move {
-                                loop {
-                                    let chunk: bytes::Bytes = match body.try_next().await {
-                                        Ok(Some(chunk)) => chunk,
-                                        Ok(None) => break,
-                                        Err(err) => {
-                                            error!(target: "js::https", "failed to read body: {err}");
-                                            break;
-                                        }
+                    Ok(builder.body(body)?)
+                }
+                true => {
+                    let builder = wait_for_response(response_rx).await?;
+                    let response = builder
+                        .body(ResponseBody::Empty)
+                        .context("failed to build response")?;
+                    if response.headers().contains_key(UPGRADE) {
+                        runtime::spawn(async move {
+                            let mut req = req;
+                            match hyper::upgrade::on(&mut req).await {
+                                Ok(upgraded) => {
+                                    if let Err(e) =
+                                        server_upgraded_io(upgraded, to_s_tx, from_s_rx).await
+                                    {
+                                        error!(target: "js::https", "server io error: {}", e)
                                     };
-                                    if let Err(err) = to_s_tx.write_all(&chunk).await {
-                                        error!(target: "js::https", "failed to send body: {err}");
-                                        break;
-                                    }
                                 }
-                                to_s_tx.shutdown().await.ok();
-                            });
-                            let builder = wait_for_response(response_rx).await?;
-                            let stream = tokio_util::io::ReaderStream::new(from_s_rx);
-                            let stream = stream.map_ok(|chunk| hyper::body::Frame::data(chunk));
-                            let body = ResponseBody::Stream(http_body_util::StreamBody::new(stream));
-                            Ok(builder.body(body)?)
-                        }
-                        true => {
-                            let builder = wait_for_response(response_rx).await?;
-                            let response = builder.body(ResponseBody::Empty).context("failed to build response")?;
-                            if response.headers().contains_key(UPGRADE) {
-                                runtime::spawn(async move {
-                                    let mut req = req;
-                                    match hyper::upgrade::on(&mut req).await {
-                                        Ok(upgraded) => {
-                                            if let Err(e) = server_upgraded_io(upgraded, to_s_tx, from_s_rx).await {
-                                                error!(target: "js::https", "server io error: {}", e)
-                                            };
-                                        }
-                                        Err(e) => error!(target: "js::https", "upgrade error: {}", e),
-                                    }
-                                });
+                                Err(e) => error!(target: "js::https", "upgrade error: {}", e),
                             }
-                            Ok(response)
-                        }
+                        });
                     }
-                }}),
-        )
-        .with_upgrades()
-        .await;
+                    Ok(response)
+                }
+            }
+        }
+    });
+    let io = HyperTokioIo::new(stream);
+    if negotiated_h2 {
+        let connection =
+            hyper::server::conn::http2::Builder::new(LocalExecutor).serve_connection(io, handler);
+        drive_connection(connection, |conn| conn.graceful_shutdown(), shutdown).await;
+    } else {
+        let connection = Builder::new(LocalExecutor).serve_connection_with_upgrades(io, handler);
+        drive_connection(connection, |conn| conn.graceful_shutdown(), shutdown).await;
+    }
+}
+
+/// Runs a connection to completion, shutting it down gracefully or aborting it as the listener
+/// asks.
+async fn drive_connection<C, E>(
+    connection: C,
+    graceful_shutdown: fn(std::pin::Pin<&mut C>),
+    mut shutdown: watch::Receiver<ShutdownState>,
+) where
+    C: Future<Output = Result<(), E>>,
+    E: std::fmt::Display,
+{
+    tokio::pin!(connection);
+    let result = loop {
+        tokio::select! {
+            result = connection.as_mut() => break result,
+            changed = shutdown.changed() => {
+                if changed.is_err() || *shutdown.borrow() == ShutdownState::Aborted {
+                    info!(target: "js::https", "connection aborted by listener shutdown");
+                    return;
+                }
+                graceful_shutdown(connection.as_mut());
+            }
+        }
+    };
     if let Err(err) = result {
         error!(target: "js::https", "failed to serve connection: {err}");
     }
@@ -358,6 +864,22 @@ fn https_send_response_head_raw(tx: js::Value, head: js::JsString) -> Result<()>
     do_https_send_response_head(tx, head.parse().context("failed to parse response head")?)
 }
 
+/// Sends the response head of a request received by `httpsListen` on behalf of JS.
+#[cfg(feature = "js-websocket")]
+pub(crate) fn send_response_head(
+    tx: js::Value,
+    status: u16,
+    headers: Vec<(String, String)>,
+) -> Result<()> {
+    do_https_send_response_head(
+        tx,
+        HttpResponseHead {
+            status,
+            headers: headers.into(),
+        },
+    )
+}
+
 fn do_https_send_response_head(tx: js::Value, response: HttpResponseHead) -> Result<()> {
     trace!(target: "js::https", "sending http response: {response:#?}");
     let response_tx = match tx.opaque_object_take_data::<oneshot::Sender<HttpResponseHead>>() {
@@ -373,3 +895,139 @@ fn do_https_send_response_head(tx: js::Value, response: HttpResponseHead) -> Res
     }
     Ok(())
 }
+
+/// Sends the trailers of a response. If the response head has a `Trailer` header, the response
+/// waits for them after the output stream is closed. Otherwise they must be sent before the
+/// stream is closed, or the response ends without trailers.
+#[js::host_call]
+fn https_send_response_trailers(tx: js::Value, trailers: Headers) -> Result<()> {
+    let mut map = HeaderMap::new();
+    for (k, v) in trailers.iter() {
+        let name = HeaderName::from_bytes(k.as_bytes())
+            .with_context(|| format!("invalid trailer name: {k:?}"))?;
+        let value =
+            HeaderValue::from_str(v).with_context(|| format!("invalid trailer value: {v:?}"))?;
+        map.append(name, value);
+    }
+    let trailers_tx = tx
+        .opaque_object_take_data::<oneshot::Sender<HeaderMap>>()
+        .context("trailers already sent")?;
+    if trailers_tx.send(map).is_err() {
+        info!(target: "js::https", "failed to send trailers");
+        bail!("failed to send trailers, the response has been finished");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(head: &str) -> Result<(u16, Vec<(String, String)>)> {
+        let head: HttpResponseHead = head.parse()?;
+        let headers = head
+            .headers
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        Ok((head.status, headers))
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_crlf_head() {
+        let (status, headers) =
+            parse("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nX-Foo: bar\r\n\r\n").unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(
+            headers,
+            pairs(&[("Content-Type", "text/plain"), ("X-Foo", "bar")])
+        );
+    }
+
+    #[test]
+    fn accepts_lf_and_missing_terminator() {
+        let (status, headers) = parse("HTTP/1.0 404 Not Found\nX-Foo: bar").unwrap();
+        assert_eq!(status, 404);
+        assert_eq!(headers, pairs(&[("X-Foo", "bar")]));
+    }
+
+    #[test]
+    fn reason_phrase_is_optional_and_may_contain_spaces() {
+        assert_eq!(parse("HTTP/1.1 204\r\n").unwrap().0, 204);
+        assert_eq!(parse("HTTP/1.1 204 \r\n").unwrap().0, 204);
+        assert_eq!(parse("HTTP/1.1 418 I'm a teapot\r\n").unwrap().0, 418);
+    }
+
+    #[test]
+    fn header_without_space_after_colon() {
+        let (_, headers) = parse("HTTP/1.1 200 OK\r\nX-Foo:bar\r\nX-Empty:\r\n").unwrap();
+        assert_eq!(headers, pairs(&[("X-Foo", "bar"), ("X-Empty", "")]));
+    }
+
+    #[test]
+    fn header_value_keeps_inner_colons_and_duplicates() {
+        let (_, headers) = parse(
+            "HTTP/1.1 302 Found\r\nLocation: http://a.b:8080/c\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            headers,
+            pairs(&[
+                ("Location", "http://a.b:8080/c"),
+                ("Set-Cookie", "a=1"),
+                ("Set-Cookie", "b=2"),
+            ])
+        );
+    }
+
+    #[test]
+    fn folded_header_lines_are_joined() {
+        let (_, headers) =
+            parse("HTTP/1.1 200 OK\r\nX-Long: first\r\n  second\r\n\tthird\r\nX-Next: 1\r\n")
+                .unwrap();
+        assert_eq!(
+            headers,
+            pairs(&[("X-Long", "first second third"), ("X-Next", "1")])
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_status_line() {
+        assert!(parse("").is_err());
+        assert!(parse("\r\n").is_err());
+        assert!(parse("200 OK\r\n").is_err());
+        assert!(parse("HTTP/2 200\r\n").is_err());
+        assert!(parse("HTTP/1.1 20 OK\r\n").is_err());
+        assert!(parse("HTTP/1.1 abc OK\r\n").is_err());
+        assert!(parse("HTTP/1.1\r\n").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        assert!(parse("HTTP/1.1 200 OK\r\nNo-Colon\r\n").is_err());
+        assert!(parse("HTTP/1.1 200 OK\r\nBad Name: x\r\n").is_err());
+        assert!(parse("HTTP/1.1 200 OK\r\n: no-name\r\n").is_err());
+        assert!(parse("HTTP/1.1 200 OK\r\nX-Foo: a\0b\r\n").is_err());
+    }
+
+    #[test]
+    fn rejects_data_after_head() {
+        assert!(parse("HTTP/1.1 200 OK\r\n\r\nbody").is_err());
+    }
+
+    #[test]
+    fn rejects_too_many_headers() {
+        let mut head = "HTTP/1.1 200 OK\r\n".to_string();
+        for i in 0..=MAX_RESPONSE_HEADERS {
+            head.push_str(&format!("X-{i}: {i}\r\n"));
+        }
+        assert!(parse(&head).is_err());
+    }
+}
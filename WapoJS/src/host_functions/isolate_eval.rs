@@ -66,6 +66,8 @@ pub struct EvalArgs {
     memory_limit: Option<u32>,
     time_limit: Option<u64>,
     polyfills: Vec<String>,
+    #[qjs(default)]
+    exit_on_unhandled_rejection: bool,
 }
 
 pub(crate) fn setup(ns: &js::Value) -> Result<()> {
@@ -105,6 +107,9 @@ fn isolate_eval(
         },
         is_sandbox: true,
         worker_secret: inner_worker_secret,
+        http_client: service.http_client_config().clone(),
+        exit_on_unhandled_rejection: args.exit_on_unhandled_rejection,
+        import_map: Default::default(),
     };
     let child_service = Service::new_ref(config);
     child_service
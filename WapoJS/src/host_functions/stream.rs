@@ -1,127 +1,307 @@
 use super::*;
 
+use super::stream_transform::{Pipeline, TransformResult, TransformSpec};
 use crate::service::OwnedJsValue;
-use js::FromJsValue;
+use js::{FromJsValue, ToJsValue};
 use log::{info, trace, warn};
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
-    sync::mpsc::Sender,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::mpsc::UnboundedSender,
 };
 
+/// Bytes queued on a `WriteStream` above which `streamWriteChunk` asks the caller to slow down.
+const WRITE_HIGH_WATER_MARK: usize = 128 * 1024;
+/// Bytes queued on a `WriteStream` above which `streamWriteChunk` rejects further chunks.
+const WRITE_BUFFER_LIMIT: usize = 16 * 1024 * 1024;
+/// Upper bound of a single `streamRead`.
+const MAX_READ_SIZE: usize = 1024 * 1024;
+/// Chunk size used by `streamBridge` when transforms are applied.
+const BRIDGE_CHUNK_SIZE: usize = 64 * 1024;
+/// The most input fed to the transforms at once, so that a decompressed step stays well below
+/// the limit of its codec.
+const PIPELINE_STEP_SIZE: usize = 16 * 1024;
+
 pub fn setup(ns: &js::Value) -> Result<()> {
     ns.define_property_fn("streamBridge", bridge)?;
     ns.define_property_fn("streamOpenWrite", stream_make_writer)?;
     ns.define_property_fn("streamWriteChunk", stream_write_chunk)?;
     ns.define_property_fn("streamOpenRead", stream_make_reader)?;
+    ns.define_property_fn("streamOpenReader", stream_make_pull_reader)?;
+    ns.define_property_fn("streamRead", stream_read)?;
     ns.define_property_fn("streamClose", stream_close)?;
     Ok(())
 }
 
-#[derive(FromJsValue, Debug)]
+#[derive(FromJsValue)]
 #[qjs(rename_all = "camelCase")]
 struct Args {
     input: js::Value,
     output: js::Value,
+    /// Transforms applied, in order, to the bytes on their way from `input` to `output`.
+    #[qjs(default)]
+    transforms: Vec<TransformSpec>,
 }
 
-enum Reader {
-    TcpStream(ReadHalf<crate::runtime::TcpStream>),
-    DuplexStream(ReadHalf<tokio::io::DuplexStream>),
+/// Reported to the callback of `streamBridge` once all data has been copied.
+#[derive(ToJsValue)]
+#[qjs(rename_all = "camelCase")]
+struct BridgeSummary {
+    bytes_read: u64,
+    bytes_written: u64,
+    transforms: Vec<TransformResult>,
 }
 
-impl Reader {
-    fn take(value: &js::Value) -> Option<Self> {
-        if let Some(r) = value.opaque_object_take_data() {
-            return Some(Reader::TcpStream(r));
-        }
-        if let Some(r) = value.opaque_object_take_data() {
-            return Some(Reader::DuplexStream(r));
-        }
-        None
+/// Object safe `AsyncRead` that can be downcast back to its concrete type.
+pub(crate) trait DynRead: AsyncRead + Unpin {
+    fn as_any(&self) -> &dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<T: AsyncRead + Unpin + 'static> DynRead for T {
+    fn as_any(&self) -> &dyn Any {
+        self
     }
-    fn dyn_reader(&mut self) -> &mut (dyn tokio::io::AsyncRead + Unpin) {
-        match self {
-            Reader::TcpStream(reader) => reader,
-            Reader::DuplexStream(reader) => reader,
-        }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
     }
 }
 
-enum Writer {
-    TcpStream(WriteHalf<crate::runtime::TcpStream>),
-    DuplexStream(WriteHalf<tokio::io::DuplexStream>),
+/// Object safe `AsyncWrite` that can be downcast back to its concrete type.
+pub(crate) trait DynWrite: AsyncWrite + Unpin {
+    fn as_any(&self) -> &dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
 }
 
-impl Writer {
-    fn take(value: &js::Value) -> Option<Self> {
-        if let Some(w) = value.opaque_object_take_data() {
-            return Some(Writer::TcpStream(w));
-        }
-        if let Some(w) = value.opaque_object_take_data() {
-            return Some(Writer::DuplexStream(w));
-        }
-        None
+impl<T: AsyncWrite + Unpin + 'static> DynWrite for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+/// The readable end of a byte stream, handed to JS in an opaque object.
+///
+/// Any host function can produce a stream by wrapping its source with `InputStream::new`, and
+/// it will work with `streamBridge`, `streamOpenRead` and `streamOpenReader`.
+pub(crate) struct InputStream(Box<dyn DynRead>);
+
+impl InputStream {
+    pub(crate) fn new(reader: impl AsyncRead + Unpin + 'static) -> Self {
+        Self(Box::new(reader))
+    }
+
+    pub(crate) fn take(value: &js::Value) -> Option<Self> {
+        value.opaque_object_take_data()
+    }
+
+    /// Borrows the concrete reader, if it is a `T`.
+    pub(crate) fn downcast_ref<T: 'static>(&self) -> Option<&T> {
+        (*self.0).as_any().downcast_ref::<T>()
     }
-    fn dyn_writer(&mut self) -> &mut (dyn tokio::io::AsyncWrite + Unpin) {
-        match self {
-            Writer::TcpStream(writer) => writer,
-            Writer::DuplexStream(writer) => writer,
+
+    /// Recovers the concrete reader, for host functions that need more than `AsyncRead`.
+    pub(crate) fn downcast<T: 'static>(self) -> Result<T, Self> {
+        if !(*self.0).as_any().is::<T>() {
+            return Err(self);
         }
+        Ok(*self
+            .0
+            .into_any()
+            .downcast::<T>()
+            .expect("type has been checked"))
+    }
+}
+
+impl AsyncRead for InputStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.0).poll_read(cx, buf)
+    }
+}
+
+/// The writable end of a byte stream, handed to JS in an opaque object.
+///
+/// Works with `streamBridge` and `streamOpenWrite`, see `InputStream`.
+pub(crate) struct OutputStream(Box<dyn DynWrite>);
+
+impl OutputStream {
+    pub(crate) fn new(writer: impl AsyncWrite + Unpin + 'static) -> Self {
+        Self(Box::new(writer))
+    }
+
+    pub(crate) fn take(value: &js::Value) -> Option<Self> {
+        value.opaque_object_take_data()
     }
 
-    async fn shutdown(&mut self) -> std::io::Result<()> {
-        match self {
-            Writer::TcpStream(writer) => writer.shutdown().await,
-            Writer::DuplexStream(writer) => writer.shutdown().await,
+    /// Borrows the concrete writer, if it is a `T`.
+    pub(crate) fn downcast_ref<T: 'static>(&self) -> Option<&T> {
+        (*self.0).as_any().downcast_ref::<T>()
+    }
+
+    /// Recovers the concrete writer, for host functions that need more than `AsyncWrite`.
+    pub(crate) fn downcast<T: 'static>(self) -> Result<T, Self> {
+        if !(*self.0).as_any().is::<T>() {
+            return Err(self);
         }
+        Ok(*self
+            .0
+            .into_any()
+            .downcast::<T>()
+            .expect("type has been checked"))
+    }
+}
+
+impl AsyncWrite for OutputStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut *self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.0).poll_shutdown(cx)
     }
 }
 
+/// Copies `input` to `output` natively, optionally through a pipeline of transforms.
+///
+/// If a callback is given it receives `("end", summary)` when done, or `("error", message)`.
 #[js::host_call(with_context)]
-fn bridge(service: ServiceRef, _this: js::Value, args: Args) -> anyhow::Result<u64> {
-    let Some(mut read_half) = Reader::take(&args.input) else {
+fn bridge(
+    service: ServiceRef,
+    _this: js::Value,
+    args: Args,
+    callback: Option<OwnedJsValue>,
+) -> anyhow::Result<u64> {
+    let mut pipeline = Pipeline::new(args.transforms)?;
+    let Some(mut read_half) = InputStream::take(&args.input) else {
         anyhow::bail!("failed to get input stream from {:?}", args.input);
     };
-    let Some(mut write_half) = Writer::take(&args.output) else {
+    let Some(mut write_half) = OutputStream::take(&args.output) else {
         anyhow::bail!("failed to get output stream, from {:?}", args.output);
     };
+    let has_callback = callback.is_some();
     let id = service.spawn(
-        OwnedJsValue::Undefined,
-        |_weak_service, _id, _| async move {
-            if let Err(err) = tokio::io::copy(read_half.dyn_reader(), write_half.dyn_writer()).await
-            {
-                warn!(target: "js::stream", "io_bridge: failed to copy data: {err}");
-            }
+        callback.unwrap_or(OwnedJsValue::Undefined),
+        move |weak_service, id, _| async move {
+            let result = do_bridge(&mut read_half, &mut write_half, &mut pipeline).await;
             write_half.shutdown().await.ok();
+            if let Err(err) = &result {
+                warn!(target: "js::stream", "io_bridge: failed to copy data: {err:?}");
+            }
+            if !has_callback {
+                return;
+            }
+            match result {
+                Ok(summary) => invoke_callback(&weak_service, id, "end", &summary),
+                Err(err) => invoke_callback(&weak_service, id, "error", &format!("{err:?}")),
+            }
         },
         (),
     );
     Ok(id)
 }
 
-#[derive(FromJsValue)]
+async fn do_bridge(
+    reader: &mut InputStream,
+    writer: &mut OutputStream,
+    pipeline: &mut Pipeline,
+) -> Result<BridgeSummary> {
+    let mut bytes_read = 0;
+    let mut bytes_written = 0;
+    if pipeline.is_empty() {
+        bytes_read = tokio::io::copy(reader, writer).await?;
+        bytes_written = bytes_read;
+    } else {
+        loop {
+            let mut chunk = Vec::with_capacity(BRIDGE_CHUNK_SIZE);
+            let n = reader.read_buf(&mut chunk).await?;
+            if n == 0 {
+                let output = pipeline.finish()?;
+                writer.write_all(&output).await?;
+                bytes_written += output.len() as u64;
+                break;
+            }
+            bytes_read += n as u64;
+            // The chunk goes through the pipeline in steps, each written out before the next,
+            // which bounds what a decompress stage holds in memory at once.
+            for step in chunk.chunks(PIPELINE_STEP_SIZE) {
+                let output = pipeline.push(step.to_vec())?;
+                writer.write_all(&output).await?;
+                bytes_written += output.len() as u64;
+            }
+        }
+    }
+    Ok(BridgeSummary {
+        bytes_read,
+        bytes_written,
+        transforms: pipeline.results(),
+    })
+}
+
+fn invoke_callback(weak_service: &ServiceWeakRef, id: u64, name: &str, data: &dyn ToJsValue) {
+    let Some(service) = weak_service.upgrade() else {
+        info!(target: "js::stream", "stream {id} exited because the service has been dropped");
+        return;
+    };
+    let Some(callback) = service.get_resource_value(id) else {
+        info!(target: "js::stream", "stream {id} exited because the resource has been dropped");
+        return;
+    };
+    if let Err(err) = service.call_function(callback, (name, data)) {
+        error!(target: "js::stream", "[{id}] failed to report stream event {name}: {err:?}");
+    }
+}
+
 struct WriteChunk {
     data: js::Bytes,
     callback: js::Value,
 }
 
+/// The writing end handed to JS by `streamOpenWrite`.
+struct WriteStream {
+    tx: UnboundedSender<WriteChunk>,
+    /// Bytes queued but not written yet.
+    buffered: Rc<Cell<usize>>,
+}
+
 #[js::host_call(with_context)]
 fn stream_make_writer(
     service: ServiceRef,
     _this: js::Value,
     output_stream: js::Value,
 ) -> anyhow::Result<js::Value> {
-    let Some(mut write_half) = Writer::take(&output_stream) else {
+    let Some(mut write_half) = OutputStream::take(&output_stream) else {
         anyhow::bail!("failed to get output_stream from {output_stream:?}");
     };
-    let (tx, rx) = tokio::sync::mpsc::channel::<WriteChunk>(128);
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<WriteChunk>();
+    let buffered = Rc::new(Cell::new(0));
+    let writer_buffered = buffered.clone();
     let _id = service.spawn(
         OwnedJsValue::Null,
         |weak_srv, _id, _| async move {
             let mut rx = rx;
-            let write_half = write_half.dyn_writer();
             while let Some(chunk) = rx.recv().await {
                 let result = write_half.write_all(chunk.data.as_bytes()).await;
+                writer_buffered.set(writer_buffered.get().saturating_sub(chunk.data.len()));
                 trace!(target: "js::stream", "{} bytes written, result: {result:?}", chunk.data.len());
                 let Some(service) = weak_srv.upgrade() else {
                     warn!(target: "js::stream", "service dropped while writing to stream");
@@ -143,10 +323,15 @@ fn stream_make_writer(
     Ok(js::Value::new_opaque_object(
         service.context(),
         Some("WriteStream"),
-        tx,
+        WriteStream { tx, buffered },
     ))
 }
 
+/// Queues a chunk for writing. `callback` is called once the chunk has been written.
+///
+/// Returns false if the queued bytes exceed the high water mark. Callers should then wait for
+/// pending callbacks before writing more. A chunk that would take the queued bytes above
+/// `WRITE_BUFFER_LIMIT` is rejected, unless nothing is queued.
 #[js::host_call(with_context)]
 fn stream_write_chunk(
     service: ServiceRef,
@@ -154,36 +339,54 @@ fn stream_write_chunk(
     writer: js::Value,
     chunk: js::Bytes,
     callback: js::Value,
-) -> Result<()> {
+) -> Result<bool> {
     let result = {
-        let guard = writer.opaque_object_data::<Sender<WriteChunk>>();
-        let Some(tx) = guard.get() else {
+        let guard = writer.opaque_object_data::<WriteStream>();
+        let Some(stream) = guard.get() else {
             anyhow::bail!("failed to get writer");
         };
-        tx.try_send(WriteChunk {
-            data: chunk,
-            callback: callback.clone(),
-        })
+        let len = chunk.len();
+        let buffered = stream.buffered.get();
+        if buffered > 0 && buffered + len > WRITE_BUFFER_LIMIT {
+            let message = "write buffer is full, wait for pending writes";
+            if let Err(err) = service.call_function(callback, (false, message)) {
+                info!(target: "js::stream", "failed to report write result: {err:?}");
+            }
+            anyhow::bail!("{message}");
+        }
+        stream
+            .tx
+            .send(WriteChunk {
+                data: chunk,
+                callback: callback.clone(),
+            })
+            .map(|_| {
+                stream.buffered.set(stream.buffered.get() + len);
+                stream.buffered.get() <= WRITE_HIGH_WATER_MARK
+            })
     };
-    if result.is_err() {
-        if let Err(err) = service.call_function(callback, (false, "failed to send chunk")) {
-            info!(target: "js::stream", "failed to report write result: {err:?}");
+    match result {
+        Ok(below_high_water_mark) => Ok(below_high_water_mark),
+        Err(_) => {
+            if let Err(err) = service.call_function(callback, (false, "stream closed")) {
+                info!(target: "js::stream", "failed to report write result: {err:?}");
+            }
+            anyhow::bail!("failed to send chunk: stream closed");
         }
-        anyhow::bail!("failed to send chunk: {result:?}");
     }
-    Ok(())
 }
 
+/// Closes a `WriteStream` after the queued chunks are written, or releases a `StreamReader`.
 #[js::host_call]
-fn stream_close(writer: js::Value) {
-    trace!(target: "js::stream", "closing writer");
-    if writer
-        .opaque_object_take_data::<Sender<WriteChunk>>()
-        .is_none()
-    {
-        warn!(target: "js::stream", "double drop of writer");
+fn stream_close(stream: js::Value) {
+    trace!(target: "js::stream", "closing stream");
+    if stream.opaque_object_take_data::<WriteStream>().is_some() {
         return;
-    };
+    }
+    if stream.opaque_object_take_data::<ReaderSlot>().is_some() {
+        return;
+    }
+    warn!(target: "js::stream", "double drop of stream");
 }
 
 #[js::host_call(with_context)]
@@ -193,7 +396,7 @@ fn stream_make_reader(
     input_stream: js::Value,
     callback: OwnedJsValue,
 ) -> Result<u64> {
-    let Some(mut read_half) = Reader::take(&input_stream) else {
+    let Some(mut read_half) = InputStream::take(&input_stream) else {
         anyhow::bail!("failed to get input_stream from {input_stream:?}");
     };
 
@@ -201,7 +404,6 @@ fn stream_make_reader(
         callback,
         |weak_srv, id, _| async move {
             let mut buf = bytes::BytesMut::with_capacity(super::http_request::STREAM_BUF_SIZE);
-            let read_half = read_half.dyn_reader();
             loop {
                 buf.clear();
                 let result = read_half.read_buf(&mut buf).await;
@@ -234,3 +436,96 @@ fn stream_make_reader(
     );
     Ok(id)
 }
+
+type ReaderSlot = Rc<RefCell<Option<InputStream>>>;
+
+/// Holds the reader of a `StreamReader` while a `streamRead` is in flight, and puts it back
+/// when the read completes or is cancelled.
+struct ReaderLease {
+    slot: ReaderSlot,
+    reader: Option<InputStream>,
+}
+
+impl Drop for ReaderLease {
+    fn drop(&mut self) {
+        *self.slot.borrow_mut() = self.reader.take();
+    }
+}
+
+/// Opens an input stream for pull-based reading with `streamRead`.
+#[js::host_call(with_context)]
+fn stream_make_pull_reader(
+    service: ServiceRef,
+    _this: js::Value,
+    input_stream: js::Value,
+) -> Result<js::Value> {
+    let Some(reader) = InputStream::take(&input_stream) else {
+        anyhow::bail!("failed to get input_stream from {input_stream:?}");
+    };
+    let slot: ReaderSlot = Rc::new(RefCell::new(Some(reader)));
+    Ok(js::Value::new_opaque_object(
+        service.context(),
+        Some("StreamReader"),
+        slot,
+    ))
+}
+
+/// Reads at most `max_bytes` from a `StreamReader` and reports the result to `callback` with
+/// the same events as `streamOpenRead`. Nothing is read from the source until asked, so the
+/// pace of the caller propagates back to the peer.
+#[js::host_call(with_context)]
+fn stream_read(
+    service: ServiceRef,
+    _this: js::Value,
+    reader: js::Value,
+    max_bytes: Option<usize>,
+    callback: OwnedJsValue,
+) -> Result<u64> {
+    let slot = {
+        let guard = reader.opaque_object_data::<ReaderSlot>();
+        let Some(slot) = guard.get() else {
+            anyhow::bail!("reader closed");
+        };
+        slot.clone()
+    };
+    let Some(reader) = slot.borrow_mut().take() else {
+        anyhow::bail!("another read is pending");
+    };
+    let lease = ReaderLease {
+        slot,
+        reader: Some(reader),
+    };
+    let max_bytes = max_bytes
+        .unwrap_or(super::http_request::STREAM_BUF_SIZE)
+        .clamp(1, MAX_READ_SIZE);
+    let id = service.spawn(
+        callback,
+        |weak_srv, id, (mut lease, max_bytes)| async move {
+            let mut buf = vec![0u8; max_bytes];
+            let Some(reader) = lease.reader.as_mut() else {
+                return;
+            };
+            let result = reader.read(&mut buf).await;
+            // Release the reader before calling back so that the callback can read again.
+            drop(lease);
+            let Some(service) = weak_srv.upgrade() else {
+                warn!(target: "js::stream", "service dropped while reading from stream");
+                return;
+            };
+            let Some(callback) = service.get_resource_value(id) else {
+                warn!(target: "js::stream", "callback dropped while reading from stream");
+                return;
+            };
+            let result = match result {
+                Ok(0) => service.call_function(callback, ("end", js::Value::Null)),
+                Ok(n) => service.call_function(callback, ("data", js::AsBytes(&buf[..n]))),
+                Err(err) => service.call_function(callback, ("error", err.to_string())),
+            };
+            if let Err(err) = result {
+                warn!(target: "js::stream", "failed to report read result: {err:?}");
+            }
+        },
+        (lease, max_bytes),
+    );
+    Ok(id)
+}
@@ -0,0 +1,483 @@
+//! In-line transforms applied by `streamBridge` while piping bytes natively.
+
+use anyhow::{bail, Context};
+use js::{AsBytes, FromJsValue, ToJsValue};
+
+use super::compression::Codec;
+use super::*;
+
+/// A transform stage as described by JS, e.g. `{ kind: "digest", algorithm: "sha256" }`.
+#[derive(FromJsValue)]
+#[qjs(rename_all = "camelCase")]
+pub(crate) struct TransformSpec {
+    /// One of `count`, `digest`, `compress`, `decompress`, `encrypt` or `decrypt`.
+    kind: String,
+    /// The digest algorithm, named as in `Wapo.hash`.
+    algorithm: Option<String>,
+    /// The compression format, named as in `CompressionStream`.
+    format: Option<String>,
+    /// AES-GCM key, 16 or 32 bytes.
+    key: Option<js::Bytes>,
+    /// AES-GCM base nonce, 12 bytes.
+    nonce: Option<js::Bytes>,
+}
+
+/// What a stage reports to JS once the bridge has finished.
+#[derive(ToJsValue)]
+#[qjs(rename_all = "camelCase")]
+pub(crate) struct TransformResult {
+    kind: String,
+    /// Bytes that reached this stage.
+    bytes: u64,
+    digest: Option<AsBytes<Vec<u8>>>,
+}
+
+trait Transform {
+    /// Processes a chunk and returns the bytes to pass downstream.
+    fn push(&mut self, data: Vec<u8>) -> Result<Vec<u8>>;
+    /// Flushes the stage at the end of the input.
+    fn finish(&mut self) -> Result<Vec<u8>>;
+    fn result(&mut self) -> TransformResult;
+}
+
+/// A chain of transforms, each feeding the next.
+pub(crate) struct Pipeline {
+    stages: Vec<Box<dyn Transform>>,
+}
+
+impl Pipeline {
+    pub fn new(specs: Vec<TransformSpec>) -> Result<Self> {
+        let stages = specs
+            .into_iter()
+            .map(new_transform)
+            .collect::<Result<_>>()?;
+        Ok(Self { stages })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    pub fn push(&mut self, data: Vec<u8>) -> Result<Vec<u8>> {
+        push_through(&mut self.stages, data)
+    }
+
+    /// Finishes the stages in order, passing the tail of each one through the rest.
+    pub fn finish(&mut self) -> Result<Vec<u8>> {
+        let mut output = vec![];
+        for i in 0..self.stages.len() {
+            let (head, rest) = self.stages.split_at_mut(i + 1);
+            let tail = head[i].finish()?;
+            output.extend(push_through(rest, tail)?);
+        }
+        Ok(output)
+    }
+
+    pub fn results(&mut self) -> Vec<TransformResult> {
+        self.stages.iter_mut().map(|stage| stage.result()).collect()
+    }
+}
+
+fn push_through(stages: &mut [Box<dyn Transform>], data: Vec<u8>) -> Result<Vec<u8>> {
+    stages
+        .iter_mut()
+        .try_fold(data, |data, stage| stage.push(data))
+}
+
+fn new_transform(spec: TransformSpec) -> Result<Box<dyn Transform>> {
+    let transform: Box<dyn Transform> = match spec.kind.as_str() {
+        "count" => Box::new(Count { bytes: 0 }),
+        "digest" => {
+            let algorithm = spec.algorithm.context("missing digest algorithm")?;
+            Box::new(Digest::new(&algorithm)?)
+        }
+        "compress" | "decompress" => {
+            let format = spec.format.context("missing compression format")?;
+            let codec = Codec::new(&format, spec.kind == "decompress")?;
+            Box::new(Compression {
+                kind: spec.kind,
+                codec: Some(codec),
+                bytes: 0,
+            })
+        }
+        "encrypt" | "decrypt" => {
+            let key = spec.key.context("missing key")?;
+            let nonce = spec.nonce.context("missing nonce")?;
+            aead::new(&spec.kind, key.as_bytes(), nonce.as_bytes())?
+        }
+        kind => bail!("unsupported transform: {kind}"),
+    };
+    Ok(transform)
+}
+
+/// Counts the bytes passing through without touching them.
+struct Count {
+    bytes: u64,
+}
+
+impl Transform for Count {
+    fn push(&mut self, data: Vec<u8>) -> Result<Vec<u8>> {
+        self.bytes += data.len() as u64;
+        Ok(data)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        Ok(vec![])
+    }
+
+    fn result(&mut self) -> TransformResult {
+        TransformResult {
+            kind: "count".into(),
+            bytes: self.bytes,
+            digest: None,
+        }
+    }
+}
+
+/// Hashes the bytes passing through incrementally.
+struct Digest {
+    #[cfg(feature = "js-hash")]
+    hasher: Option<Box<dyn blake2::digest::DynDigest>>,
+    digest: Vec<u8>,
+    bytes: u64,
+}
+
+impl Digest {
+    #[cfg(feature = "js-hash")]
+    fn new(algorithm: &str) -> Result<Self> {
+        use blake2::{
+            digest::typenum::{U16, U32, U64},
+            Blake2b, Digest as _,
+        };
+        let hasher: Box<dyn blake2::digest::DynDigest> = match algorithm {
+            "sha256" => Box::new(sha2::Sha256::new()),
+            "keccak256" => Box::new(sha3::Keccak256::new()),
+            "blake2b128" => Box::new(Blake2b::<U16>::new()),
+            "blake2b256" => Box::new(Blake2b::<U32>::new()),
+            "blake2b512" => Box::new(Blake2b::<U64>::new()),
+            _ => bail!("unsupported hash algorithm: {algorithm}"),
+        };
+        Ok(Self {
+            hasher: Some(hasher),
+            digest: vec![],
+            bytes: 0,
+        })
+    }
+
+    #[cfg(not(feature = "js-hash"))]
+    fn new(_algorithm: &str) -> Result<Self> {
+        bail!("digest transforms require the js-hash feature")
+    }
+}
+
+impl Transform for Digest {
+    fn push(&mut self, data: Vec<u8>) -> Result<Vec<u8>> {
+        #[cfg(feature = "js-hash")]
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&data);
+        }
+        self.bytes += data.len() as u64;
+        Ok(data)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        #[cfg(feature = "js-hash")]
+        if let Some(hasher) = self.hasher.take() {
+            self.digest = hasher.finalize().into_vec();
+        }
+        Ok(vec![])
+    }
+
+    fn result(&mut self) -> TransformResult {
+        TransformResult {
+            kind: "digest".into(),
+            bytes: self.bytes,
+            digest: Some(AsBytes(core::mem::take(&mut self.digest))),
+        }
+    }
+}
+
+/// A compress or decompress stage. What a decompressor outputs for each push is bounded by
+/// the codec, so a small compressed chunk can't inflate to an unbounded buffer.
+struct Compression {
+    kind: String,
+    codec: Option<Codec>,
+    bytes: u64,
+}
+
+impl Transform for Compression {
+    fn push(&mut self, data: Vec<u8>) -> Result<Vec<u8>> {
+        self.bytes += data.len() as u64;
+        let codec = self.codec.as_mut().context("codec already finished")?;
+        codec.push(&data).context("failed to process chunk")
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        let codec = self.codec.take().context("codec already finished")?;
+        codec.finish().context("failed to finish stream")
+    }
+
+    fn result(&mut self) -> TransformResult {
+        TransformResult {
+            kind: core::mem::take(&mut self.kind),
+            bytes: self.bytes,
+            digest: None,
+        }
+    }
+}
+
+#[cfg(not(feature = "js-crypto"))]
+mod aead {
+    use super::*;
+
+    pub(super) fn new(_kind: &str, _key: &[u8], _nonce: &[u8]) -> Result<Box<dyn Transform>> {
+        bail!("encryption transforms require the js-crypto feature")
+    }
+}
+
+/// Chunked AES-GCM.
+///
+/// The plaintext is split into records of up to `RECORD_SIZE` bytes. Each record is sealed
+/// with the base nonce XORed with its big-endian index in the last four bytes, and with the
+/// AAD set to `[1]` for the final record and `[0]` otherwise, so that reordering, dropping or
+/// truncating records fails to decrypt. On the wire every record is the ciphertext length as
+/// a big-endian u32 followed by the ciphertext and tag.
+#[cfg(feature = "js-crypto")]
+mod aead {
+    use super::*;
+    use aes_gcm::{
+        aead::{Aead, KeyInit, Payload},
+        Aes128Gcm, Aes256Gcm, Nonce,
+    };
+
+    const RECORD_SIZE: usize = 64 * 1024;
+    const TAG_SIZE: usize = 16;
+
+    enum Cipher {
+        Aes128(Box<Aes128Gcm>),
+        Aes256(Box<Aes256Gcm>),
+    }
+
+    impl Cipher {
+        fn new(key: &[u8]) -> Result<Self> {
+            Ok(match key.len() {
+                16 => Cipher::Aes128(Box::new(Aes128Gcm::new(key.into()))),
+                32 => Cipher::Aes256(Box::new(Aes256Gcm::new(key.into()))),
+                len => bail!("invalid AES-GCM key length: {len}"),
+            })
+        }
+
+        fn seal(&self, nonce: &[u8; 12], msg: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+            let nonce = Nonce::from_slice(nonce);
+            let payload = Payload { msg, aad };
+            let result = match self {
+                Cipher::Aes128(cipher) => cipher.encrypt(nonce, payload),
+                Cipher::Aes256(cipher) => cipher.encrypt(nonce, payload),
+            };
+            result.ok().context("failed to encrypt record")
+        }
+
+        fn open(&self, nonce: &[u8; 12], msg: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+            let nonce = Nonce::from_slice(nonce);
+            let payload = Payload { msg, aad };
+            let result = match self {
+                Cipher::Aes128(cipher) => cipher.decrypt(nonce, payload),
+                Cipher::Aes256(cipher) => cipher.decrypt(nonce, payload),
+            };
+            result.ok().context("failed to decrypt record")
+        }
+    }
+
+    struct Aead {
+        decrypt: bool,
+        cipher: Cipher,
+        base_nonce: [u8; 12],
+        counter: u32,
+        buffer: Vec<u8>,
+        bytes: u64,
+    }
+
+    pub(super) fn new(kind: &str, key: &[u8], nonce: &[u8]) -> Result<Box<dyn Transform>> {
+        let base_nonce = nonce
+            .try_into()
+            .ok()
+            .context("AES-GCM nonce must be 12 bytes")?;
+        Ok(Box::new(Aead {
+            decrypt: kind == "decrypt",
+            cipher: Cipher::new(key)?,
+            base_nonce,
+            counter: 0,
+            buffer: vec![],
+            bytes: 0,
+        }))
+    }
+
+    impl Aead {
+        fn next_nonce(&mut self) -> Result<[u8; 12]> {
+            let mut nonce = self.base_nonce;
+            for (b, c) in nonce[8..].iter_mut().zip(self.counter.to_be_bytes()) {
+                *b ^= c;
+            }
+            self.counter = self.counter.checked_add(1).context("too many records")?;
+            Ok(nonce)
+        }
+
+        fn seal_record(&mut self, len: usize, last: bool, output: &mut Vec<u8>) -> Result<()> {
+            let nonce = self.next_nonce()?;
+            let record: Vec<u8> = self.buffer.drain(..len).collect();
+            let sealed = self.cipher.seal(&nonce, &record, &[last as u8])?;
+            output.extend_from_slice(&(sealed.len() as u32).to_be_bytes());
+            output.extend(sealed);
+            Ok(())
+        }
+
+        /// The length of the first complete record in the buffer, including its header.
+        fn complete_record(&self) -> Result<Option<usize>> {
+            let Some(header) = self.buffer.get(..4) else {
+                return Ok(None);
+            };
+            let len = u32::from_be_bytes(header.try_into().expect("4 bytes")) as usize;
+            if !(TAG_SIZE..=RECORD_SIZE + TAG_SIZE).contains(&len) {
+                bail!("invalid record length: {len}");
+            }
+            Ok((self.buffer.len() >= 4 + len).then_some(4 + len))
+        }
+
+        fn open_record(&mut self, len: usize, last: bool, output: &mut Vec<u8>) -> Result<()> {
+            let nonce = self.next_nonce()?;
+            let record: Vec<u8> = self.buffer.drain(..len).collect();
+            output.extend(self.cipher.open(&nonce, &record[4..], &[last as u8])?);
+            Ok(())
+        }
+    }
+
+    impl Transform for Aead {
+        fn push(&mut self, data: Vec<u8>) -> Result<Vec<u8>> {
+            self.bytes += data.len() as u64;
+            self.buffer.extend(data);
+            let mut output = vec![];
+            if self.decrypt {
+                // A record is only known not to be the last one once more data follows it.
+                while let Some(len) = self.complete_record()? {
+                    if self.buffer.len() == len {
+                        break;
+                    }
+                    self.open_record(len, false, &mut output)?;
+                }
+            } else {
+                // Keep at least one byte back so that the final record is never empty
+                // unless the whole stream is.
+                while self.buffer.len() > RECORD_SIZE {
+                    self.seal_record(RECORD_SIZE, false, &mut output)?;
+                }
+            }
+            Ok(output)
+        }
+
+        fn finish(&mut self) -> Result<Vec<u8>> {
+            let mut output = vec![];
+            if self.decrypt {
+                match self.complete_record()? {
+                    Some(len) if len == self.buffer.len() => {
+                        self.open_record(len, true, &mut output)?
+                    }
+                    _ => bail!("truncated encrypted stream"),
+                }
+            } else {
+                self.seal_record(self.buffer.len(), true, &mut output)?;
+            }
+            Ok(output)
+        }
+
+        fn result(&mut self) -> TransformResult {
+            TransformResult {
+                kind: if self.decrypt { "decrypt" } else { "encrypt" }.into(),
+                bytes: self.bytes,
+                digest: None,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const KEY: [u8; 16] = [7; 16];
+        const NONCE: [u8; 12] = [9; 12];
+
+        fn run(kind: &str, data: &[u8], chunk_size: usize) -> Result<Vec<u8>> {
+            let mut transform = new(kind, &KEY, &NONCE)?;
+            let mut output = vec![];
+            for chunk in data.chunks(chunk_size) {
+                output.extend(transform.push(chunk.to_vec())?);
+            }
+            output.extend(transform.finish()?);
+            Ok(output)
+        }
+
+        /// Splits an encrypted stream into its records, headers included.
+        fn records(mut data: &[u8]) -> Vec<Vec<u8>> {
+            let mut records = vec![];
+            while !data.is_empty() {
+                let len = 4 + u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+                records.push(data[..len].to_vec());
+                data = &data[len..];
+            }
+            records
+        }
+
+        fn plaintext() -> Vec<u8> {
+            (0..2 * RECORD_SIZE + 100).map(|i| i as u8).collect()
+        }
+
+        #[test]
+        fn round_trip_across_chunk_boundaries() {
+            let plaintext = plaintext();
+            let sealed = run("encrypt", &plaintext, 1000).unwrap();
+            assert_eq!(records(&sealed).len(), 3);
+            for chunk_size in [1, 777, RECORD_SIZE + 4 + TAG_SIZE, sealed.len()] {
+                assert_eq!(run("decrypt", &sealed, chunk_size).unwrap(), plaintext);
+            }
+        }
+
+        #[test]
+        fn empty_stream() {
+            let sealed = run("encrypt", &[], 1).unwrap();
+            assert_eq!(sealed.len(), 4 + TAG_SIZE);
+            assert_eq!(run("decrypt", &sealed, 1).unwrap(), b"");
+            // Not even the final record.
+            assert!(run("decrypt", &[], 1).is_err());
+        }
+
+        #[test]
+        fn rejects_truncated_stream() {
+            let sealed = run("encrypt", &plaintext(), RECORD_SIZE).unwrap();
+            let records = records(&sealed);
+            let without_final = records[..2].concat();
+            assert!(run("decrypt", &without_final, 1000).is_err());
+            let cut = &sealed[..sealed.len() - 1];
+            assert!(run("decrypt", cut, 1000).is_err());
+        }
+
+        #[test]
+        fn rejects_reordered_or_duplicated_records() {
+            let sealed = run("encrypt", &plaintext(), RECORD_SIZE).unwrap();
+            let records = records(&sealed);
+            let reordered = [&records[1], &records[0], &records[2]]
+                .map(|r| &r[..])
+                .concat();
+            assert!(run("decrypt", &reordered, 1000).is_err());
+            let duplicated = [&records[0], &records[0], &records[1], &records[2]]
+                .map(|r| &r[..])
+                .concat();
+            assert!(run("decrypt", &duplicated, 1000).is_err());
+        }
+
+        #[test]
+        fn rejects_tampered_tag() {
+            let mut sealed = run("encrypt", b"hello", 1).unwrap();
+            *sealed.last_mut().unwrap() ^= 1;
+            assert!(run("decrypt", &sealed, 1).is_err());
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use anyhow::Context;
+use js::{Error as ValueError, FromJsValue, ToJsValue};
+use log::{debug, info, trace, warn};
+
+use super::http_request::TlsOptions;
+use super::stream::{InputStream, OutputStream};
+use super::*;
+use crate::{runtime, service::OwnedJsValue};
+
+pub fn setup(ns: &js::Value) -> Result<()> {
+    ns.define_property_fn("tcpConnect", tcp_connect)?;
+    Ok(())
+}
+
+#[derive(FromJsValue, Debug)]
+#[qjs(rename_all = "camelCase")]
+pub struct ConnectOptions {
+    host: String,
+    port: u16,
+    #[qjs(default)]
+    tls: TlsSetting,
+}
+
+/// The `tls` option accepts either a boolean or a `TlsOptions` object.
+#[derive(Debug, Default)]
+pub enum TlsSetting {
+    #[default]
+    Disabled,
+    Enabled,
+    Custom(TlsOptions),
+}
+
+impl FromJsValue for TlsSetting {
+    fn from_js_value(value: js::Value) -> Result<Self, ValueError> {
+        if value.is_null_or_undefined() {
+            return Ok(Self::Disabled);
+        }
+        if value.is_object() {
+            return Ok(Self::Custom(TlsOptions::from_js_value(value)?));
+        }
+        Ok(if bool::from_js_value(value)? {
+            Self::Enabled
+        } else {
+            Self::Disabled
+        })
+    }
+}
+
+#[derive(ToJsValue, Debug)]
+#[qjs(rename_all = "camelCase")]
+struct Connection {
+    opaque_input_stream: js::Value,
+    opaque_output_stream: js::Value,
+}
+
+#[js::host_call(with_context)]
+fn tcp_connect(
+    service: ServiceRef,
+    _this: js::Value,
+    options: ConnectOptions,
+    callback: OwnedJsValue,
+) -> Result<u64> {
+    debug!(target: "js::tcp", "connecting to {}:{}", options.host, options.port);
+    Ok(service.spawn(callback, do_tcp_connect, options))
+}
+
+async fn do_tcp_connect(weak_service: ServiceWeakRef, id: u64, options: ConnectOptions) {
+    let addr = format!("{}:{}", options.host, options.port);
+    if let Err(err) = do_tcp_connect_inner(weak_service.clone(), id, options).await {
+        warn!(target: "js::tcp", "failed to connect to {addr}: {err:?}");
+        invoke_callback(
+            &weak_service,
+            id,
+            "error",
+            &format!("failed to connect to {addr}: {err:?}"),
+        );
+    }
+}
+
+async fn do_tcp_connect_inner(
+    weak_service: ServiceWeakRef,
+    id: u64,
+    options: ConnectOptions,
+) -> Result<()> {
+    let host = options.host.as_str();
+    let stream = match &options.tls {
+        TlsSetting::Disabled => runtime::TcpStream::connect(host, options.port, false).await?,
+        TlsSetting::Enabled => runtime::TcpStream::connect(host, options.port, true).await?,
+        TlsSetting::Custom(tls) => {
+            let tls = tls.build().context("invalid tls options")?;
+            runtime::tls_connect(host, options.port, &tls).await?
+        }
+    };
+    trace!(target: "js::tcp", "connected to {}:{}", options.host, options.port);
+    let (read_half, write_half) = tokio::io::split(stream);
+    let connection = {
+        let service = weak_service.upgrade().context("service dropped")?;
+        Connection {
+            opaque_input_stream: js::Value::new_opaque_object(
+                service.context(),
+                Some("TcpInputStream"),
+                InputStream::new(read_half),
+            ),
+            opaque_output_stream: js::Value::new_opaque_object(
+                service.context(),
+                Some("TcpOutputStream"),
+                OutputStream::new(write_half),
+            ),
+        }
+    };
+    invoke_callback(&weak_service, id, "connect", &connection);
+    Ok(())
+}
+
+fn invoke_callback(weak_service: &Weak<Service>, id: u64, name: &str, data: &dyn ToJsValue) {
+    let Some(service) = weak_service.upgrade() else {
+        info!(target: "js::tcp", "tcp connection {id} exited because the service has been dropped");
+        return;
+    };
+    let Some(callback) = service.get_resource_value(id) else {
+        info!(target: "js::tcp", "tcp connection {id} exited because the resource has been dropped");
+        return;
+    };
+    if let Err(err) = service.call_function(callback, (name, data)) {
+        error!(target: "js::tcp", "[{id}] failed to report tcp event {name}: {err:?}");
+    }
+}
@@ -1,21 +1,49 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Notify;
+
 use super::*;
-use crate::{runtime::time::sleep, service::OwnedJsValue};
+use crate::{
+    runtime::time::sleep,
+    service::{OwnedJsValue, Resource},
+};
 
 pub(crate) fn setup(ns: &js::Value) -> Result<()> {
     // `clearTimeout` and `clearInterval` are implemented by `close` on the guest side
     ns.define_property_fn("setTimeout", set_timeout)?;
     ns.define_property_fn("setInterval", set_interval)?;
+    ns.define_property_fn("hrtime", hrtime)?;
     Ok(())
 }
 
+/// Intervals shorter than this would spin the event loop.
+const MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Converts a JS delay in (possibly fractional) milliseconds, treating NaN and negative
+/// values as zero.
+fn delay_from_ms(timeout_ms: f64) -> Duration {
+    Duration::try_from_secs_f64(timeout_ms / 1000.0).unwrap_or_default()
+}
+
 #[js::host_call(with_context)]
 fn set_timeout(
     service: ServiceRef,
     _this: js::Value,
     callback: OwnedJsValue,
-    timeout_ms: u64,
+    timeout_ms: f64,
 ) -> Result<u64> {
-    Ok(service.spawn(callback, do_set_timeout, timeout_ms.max(4)))
+    Ok(schedule_timer(
+        &service,
+        callback,
+        delay_from_ms(timeout_ms),
+        None,
+    ))
 }
 
 #[js::host_call(with_context)]
@@ -23,34 +51,199 @@ fn set_interval(
     service: ServiceRef,
     _this: js::Value,
     callback: OwnedJsValue,
-    timeout_ms: u64,
+    timeout_ms: f64,
 ) -> Result<u64> {
-    Ok(service.spawn(callback, do_set_interval, timeout_ms.max(4)))
+    let interval = delay_from_ms(timeout_ms).max(MIN_INTERVAL);
+    Ok(schedule_timer(&service, callback, interval, Some(interval)))
 }
 
-fn try_fire_timer(service: &Weak<Service>, id: u64) -> Result<()> {
-    let Some(service) = service.upgrade() else {
-        anyhow::bail!("timer {id} exited because the service has been dropped");
-    };
+/// Monotonic time as `(seconds, nanoseconds)`, measured from the first call. Only differences
+/// between readings are meaningful.
+#[js::host_call]
+fn hrtime() -> (u64, u32) {
+    static ORIGIN: OnceLock<Instant> = OnceLock::new();
+    let elapsed = ORIGIN.get_or_init(Instant::now).elapsed();
+    (elapsed.as_secs(), elapsed.subsec_nanos())
+}
+
+fn schedule_timer(
+    service: &Service,
+    callback: OwnedJsValue,
+    delay: Duration,
+    interval: Option<Duration>,
+) -> u64 {
+    let queue = service.timers();
+    let id = service.push_resource_with(|id| {
+        let handle = TimerHandle {
+            queue: Rc::downgrade(&queue),
+            id,
+        };
+        Resource::new(callback, Some(Box::new(handle)))
+    });
+    queue.schedule(id, Instant::now() + delay, interval);
+    id
+}
+
+/// All pending JS timers of a service, ordered by deadline.
+///
+/// The timers are fired by a single driver task instead of one spawned task per timer. The
+/// callback of a timer lives in the service resource table, so closing the resource (e.g. via
+/// `Wapo.close`) drops its [`TimerHandle`] which in turn unschedules the timer.
+#[derive(Default)]
+pub(crate) struct TimerQueue {
+    state: RefCell<QueueState>,
+    wakeup: Notify,
+}
+
+#[derive(Default)]
+struct QueueState {
+    /// Keyed by `(deadline, id)` so that timers with the same deadline fire in creation order.
+    entries: BTreeMap<(Instant, u64), Option<Duration>>,
+    deadlines: HashMap<u64, Instant>,
+}
+
+struct DueTimer {
+    id: u64,
+    deadline: Instant,
+    interval: Option<Duration>,
+}
+
+impl TimerQueue {
+    fn schedule(&self, id: u64, deadline: Instant, interval: Option<Duration>) {
+        let mut state = self.state.borrow_mut();
+        if let Some(prev) = state.deadlines.insert(id, deadline) {
+            state.entries.remove(&(prev, id));
+        }
+        state.entries.insert((deadline, id), interval);
+        let is_earliest = state
+            .entries
+            .first_key_value()
+            .is_some_and(|(&(first, first_id), _)| (first, first_id) == (deadline, id));
+        drop(state);
+        if is_earliest {
+            self.wakeup.notify_one();
+        }
+    }
+
+    fn cancel(&self, id: u64) {
+        let mut state = self.state.borrow_mut();
+        if let Some(deadline) = state.deadlines.remove(&id) {
+            state.entries.remove(&(deadline, id));
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let state = self.state.borrow();
+        state
+            .entries
+            .first_key_value()
+            .map(|(&(deadline, _), _)| deadline)
+    }
+
+    /// Removes and returns all timers whose deadline is not later than `now`.
+    fn take_due(&self, now: Instant) -> Vec<DueTimer> {
+        let mut state = self.state.borrow_mut();
+        let pending = state.entries.split_off(&(now, u64::MAX));
+        let due = core::mem::replace(&mut state.entries, pending);
+        due.into_iter()
+            .map(|((deadline, id), interval)| {
+                state.deadlines.remove(&id);
+                DueTimer {
+                    id,
+                    deadline,
+                    interval,
+                }
+            })
+            .collect()
+    }
+
+    /// Wakes the driver up so that it notices the service has gone away.
+    pub(crate) fn shutdown(&self) {
+        self.wakeup.notify_one();
+    }
+}
+
+/// Unschedules the timer when its resource is closed.
+struct TimerHandle {
+    queue: Weak<TimerQueue>,
+    id: u64,
+}
+
+impl Drop for TimerHandle {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue.upgrade() {
+            queue.cancel(self.id);
+        }
+    }
+}
+
+/// Returns the next deadline of an interval, computed from the previous deadline rather than
+/// from the firing time so that the interval doesn't drift. Ticks that were missed because the
+/// event loop was busy are skipped instead of fired in a burst.
+fn next_tick(deadline: Instant, interval: Duration, now: Instant) -> Instant {
+    let next = deadline + interval;
+    if next > now {
+        return next;
+    }
+    let behind = now.duration_since(deadline).as_nanos();
+    let missed = behind / interval.as_nanos();
+    let skip = u32::try_from(missed + 1).unwrap_or(u32::MAX);
+    deadline + interval.saturating_mul(skip)
+}
+
+fn fire_timer(service: &Service, timer: &DueTimer, now: Instant) -> Result<()> {
+    let id = timer.id;
     let Some(callback) = service.get_resource_value(id) else {
-        anyhow::bail!("timer {id} exited because the resource has been dropped");
+        anyhow::bail!("timer {id} fired after its resource has been dropped");
     };
-    if let Err(err) = service.call_function(callback, ()) {
+    let result = service.call_function(callback, ());
+    match timer.interval {
+        None => {
+            service.remove_resource(id);
+        }
+        // The callback may have cleared the interval itself.
+        Some(interval) if service.get_resource_value(id).is_some() => {
+            let queue = service.timers();
+            queue.schedule(id, next_tick(timer.deadline, interval, now), Some(interval));
+        }
+        Some(_) => {}
+    }
+    if let Err(err) = result {
         error!(target: "js::timer", "failed to fire timer {id}: {err}");
     }
     Ok(())
 }
 
-async fn do_set_timeout(service: ServiceWeakRef, id: u64, timeout_ms: u64) {
-    sleep(std::time::Duration::from_millis(timeout_ms)).await;
-    try_fire_timer(&service, id).ignore();
-}
-
-async fn do_set_interval(service: ServiceWeakRef, id: u64, timeout_ms: u64) {
+/// Fires the timers of `queue` as they become due, until the service is dropped.
+pub(crate) async fn drive_timers(service: ServiceWeakRef, queue: Rc<TimerQueue>) {
     loop {
-        sleep(std::time::Duration::from_millis(timeout_ms)).await;
-        if try_fire_timer(&service, id).log_err().is_err() {
+        if service.strong_count() == 0 {
+            break;
+        }
+        match queue.next_deadline() {
+            None => queue.wakeup.notified().await,
+            Some(deadline) => {
+                let now = Instant::now();
+                if deadline > now {
+                    tokio::select! {
+                        _ = sleep(deadline - now) => {}
+                        // A new timer became the earliest one, recompute the deadline.
+                        _ = queue.wakeup.notified() => continue,
+                    }
+                }
+            }
+        }
+        let Some(service) = service.upgrade() else {
             break;
+        };
+        let now = Instant::now();
+        for timer in queue.take_due(now) {
+            fire_timer(&service, &timer, now).ignore();
         }
+        drop(service);
+        // A chain of zero delay timers is always due, so without yielding here it would
+        // starve the I/O and every other task on the thread.
+        crate::runtime::yield_now().await;
     }
+    log::debug!(target: "js::timer", "timer driver exited");
 }
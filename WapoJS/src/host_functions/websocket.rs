@@ -1,16 +1,43 @@
 use anyhow::{bail, Context};
-use async_tungstenite::tungstenite::{protocol::WebSocketConfig, Message};
+use async_tungstenite::tungstenite::{
+    protocol::{CloseFrame, WebSocketConfig},
+    Message,
+};
 use futures::{SinkExt as _, StreamExt};
 use log::{debug, info, trace, warn};
-use std::collections::BTreeMap;
+use std::{cell::Cell, collections::BTreeMap, rc::Rc, time::Duration};
+use tokio::sync::watch;
 use tokio_util::compat::TokioAsyncReadCompatExt as _;
 
+use super::http_request::TlsOptions;
 use crate::{runtime, service::OwnedJsValue};
 use js::{Error as ValueError, FromJsValue, ToJsValue};
 
 use super::*;
 
-type WsSink = tokio::sync::mpsc::Sender<Message>;
+mod deflate;
+
+/// The number of bytes that can be queued before `wsSend` asks the caller to wait for `drain`.
+const DEFAULT_HIGH_WATER_MARK: usize = 1024 * 1024;
+
+/// The sending half of a ws connection, handed to JS as an opaque object.
+struct WsSink {
+    tx: tokio::sync::mpsc::UnboundedSender<Outgoing>,
+    state: Rc<SendState>,
+}
+
+struct SendState {
+    /// Bytes queued but not flushed to the socket yet.
+    buffered: Cell<usize>,
+    high_water_mark: usize,
+    /// Set when a send exceeded the high water mark, cleared once `drain` is reported.
+    need_drain: Cell<bool>,
+}
+
+struct Outgoing {
+    msg: Message,
+    callback: Option<js::Value>,
+}
 
 #[derive(Debug, Default)]
 pub struct Headers {
@@ -66,6 +93,8 @@ pub struct WsMessage {
     pub kind: String,
     #[qjs(default)]
     pub data: js::BytesOrString,
+    /// The status code of a `close` message. `data` holds the reason.
+    pub code: Option<u16>,
 }
 
 impl From<Message> for WsMessage {
@@ -74,26 +103,37 @@ impl From<Message> for WsMessage {
             Message::Text(text) => Self {
                 kind: "text".to_string(),
                 data: js::BytesOrString::String(text.into()),
+                code: None,
             },
             Message::Binary(bin) => Self {
                 kind: "binary".to_string(),
                 data: js::BytesOrString::Bytes(bin.into()),
+                code: None,
             },
             Message::Ping(data) => Self {
                 kind: "ping".to_string(),
                 data: js::BytesOrString::Bytes(data.into()),
+                code: None,
             },
             Message::Pong(data) => Self {
                 kind: "pong".to_string(),
                 data: js::BytesOrString::Bytes(data.into()),
+                code: None,
             },
-            Message::Close(_) => Self {
+            Message::Close(frame) => Self {
                 kind: "close".to_string(),
-                data: js::BytesOrString::String("".into()),
+                code: frame.as_ref().map(|frame| frame.code.into()),
+                data: js::BytesOrString::String(
+                    frame
+                        .map(|frame| frame.reason.into_owned())
+                        .unwrap_or_default()
+                        .into(),
+                ),
             },
             Message::Frame(data) => Self {
                 kind: "frame".to_string(),
                 data: js::BytesOrString::Bytes(data.into_data().into()),
+                code: None,
             },
         }
     }
@@ -110,14 +150,17 @@ impl TryFrom<WsMessage> for Message {
             "binary" => Ok(Message::Binary(value.data.as_bytes().to_vec())),
             "ping" => Ok(Message::Ping(value.data.as_bytes().to_vec())),
             "pong" => Ok(Message::Pong(value.data.as_bytes().to_vec())),
-            "close" => Ok(Message::Close(None)),
+            "close" => Ok(Message::Close(value.code.map(|code| CloseFrame {
+                code: code.into(),
+                reason: value.data.as_str().unwrap_or_default().to_string().into(),
+            }))),
             "frame" => Ok(Message::Binary(value.data.as_bytes().to_vec())),
             _ => Err(js::Error::msg("invalid message kind")),
         }
     }
 }
 
-#[derive(FromJsValue, Debug)]
+#[derive(FromJsValue, Debug, Clone)]
 #[qjs(rename_all = "camelCase")]
 pub struct WsConfig {
     pub write_buffer_size: Option<usize>,
@@ -125,6 +168,10 @@ pub struct WsConfig {
     pub max_message_size: Option<usize>,
     pub max_frame_size: Option<usize>,
     pub accept_unmasked_frames: Option<bool>,
+    /// Bytes queued for sending above which `wsSend` returns false.
+    pub high_water_mark: Option<usize>,
+    /// Offers, or accepts when offered by the client, the permessage-deflate extension.
+    pub per_message_deflate: Option<bool>,
 }
 
 impl From<WsConfig> for WebSocketConfig {
@@ -156,11 +203,111 @@ pub struct OpenOptions {
     #[qjs(default)]
     headers: Headers,
     config: Option<WsConfig>,
+    tls: Option<TlsOptions>,
+    /// Subprotocols offered in `Sec-WebSocket-Protocol`.
+    #[qjs(default)]
+    protocols: Vec<String>,
+    /// Sends a ping after the connection has been idle for this long.
+    ping_interval_ms: Option<u64>,
+    /// Drops the connection if nothing is received this long after a ping.
+    /// Defaults to `ping_interval_ms`.
+    pong_timeout_ms: Option<u64>,
+    /// Reconnects when the connection fails or is closed by the peer.
+    reconnect: Option<ReconnectPolicy>,
+}
+
+impl OpenOptions {
+    fn per_message_deflate(&self) -> bool {
+        self.config
+            .as_ref()
+            .and_then(|config| config.per_message_deflate)
+            .unwrap_or(false)
+    }
+
+    fn keepalive(&self) -> Option<Keepalive> {
+        let ping_interval_ms = self.ping_interval_ms?;
+        Some(Keepalive {
+            ping_interval: Duration::from_millis(ping_interval_ms),
+            pong_timeout: Duration::from_millis(self.pong_timeout_ms.unwrap_or(ping_interval_ms)),
+        })
+    }
+}
+
+#[derive(FromJsValue, Debug)]
+#[qjs(rename_all = "camelCase")]
+struct ReconnectPolicy {
+    /// Gives up after this many consecutive failed attempts. Retries forever if not set.
+    max_attempts: Option<u32>,
+    #[qjs(default = "default_initial_delay_ms")]
+    initial_delay_ms: u64,
+    #[qjs(default = "default_max_delay_ms")]
+    max_delay_ms: u64,
+}
+
+fn default_initial_delay_ms() -> u64 {
+    1000
+}
+
+fn default_max_delay_ms() -> u64 {
+    30_000
+}
+
+impl ReconnectPolicy {
+    /// The delay before the given retry, doubling on every attempt.
+    fn delay(&self, attempt: u32) -> Option<Duration> {
+        if self.max_attempts.is_some_and(|max| attempt >= max) {
+            return None;
+        }
+        let delay = self
+            .initial_delay_ms
+            .saturating_mul(1u64 << attempt.min(63))
+            .min(self.max_delay_ms);
+        Some(Duration::from_millis(delay))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Keepalive {
+    ping_interval: Duration,
+    pong_timeout: Duration,
+}
+
+#[derive(ToJsValue, Debug)]
+#[qjs(rename_all = "camelCase")]
+struct Reconnecting {
+    attempt: u32,
+    delay_ms: u64,
+    reason: String,
+}
+
+#[derive(ToJsValue, Debug)]
+#[qjs(rename_all = "camelCase")]
+struct Reconnected {
+    attempt: u32,
+}
+
+/// How a connection ended, if it ended without an error.
+#[derive(Debug, PartialEq, Eq)]
+enum Closed {
+    /// The `WsSink` was closed or dropped on our side.
+    Locally,
+    Remotely,
+}
+
+/// Negotiated connection parameters, reported along with the `open` event.
+#[derive(ToJsValue, Debug, Default)]
+#[qjs(rename_all = "camelCase")]
+struct OpenInfo {
+    protocol: String,
+    extensions: String,
 }
 
 pub fn setup(ns: &js::Value) -> Result<()> {
     ns.define_property_fn("wsOpen", ws_open)?;
+    #[cfg(feature = "js-https-listen")]
+    ns.define_property_fn("wsAccept", ws_accept)?;
     ns.define_property_fn("wsSend", ws_send)?;
+    ns.define_property_fn("wsBufferedAmount", ws_buffered_amount)?;
     ns.define_property_fn("wsClose", ws_close)?;
     Ok(())
 }
@@ -180,67 +327,271 @@ fn ws_open(
 }
 
 async fn do_ws_open(weak_service: ServiceWeakRef, id: u64, options: OpenOptions) {
-    let url = options.url.clone();
-    let result = do_ws_open_inner(weak_service.clone(), id, options).await;
-    if let Err(err) = result {
-        warn!(target: "js::ws", "failed to open ws `{url}`: {err:?}");
-        invoke_callback(
-            &weak_service,
-            id,
-            "error",
-            &format!("failed to request `{url}`: {err:?}"),
-        );
+    let url = &options.url;
+    let mut attempt = 0;
+    loop {
+        let result = do_ws_open_inner(weak_service.clone(), id, &options, &mut attempt).await;
+        let reason = match &result {
+            Ok(Closed::Locally) => return,
+            Ok(Closed::Remotely) => "closed by peer".to_string(),
+            Err(err) => format!("{err:?}"),
+        };
+        let delay = options
+            .reconnect
+            .as_ref()
+            .and_then(|policy| policy.delay(attempt));
+        let Some(delay) = delay else {
+            if let Err(err) = result {
+                warn!(target: "js::ws", "failed to open ws `{url}`: {err:?}");
+                invoke_callback(
+                    &weak_service,
+                    id,
+                    "error",
+                    &format!("failed to request `{url}`: {err:?}"),
+                );
+            }
+            return;
+        };
+        attempt += 1;
+        info!(target: "js::ws", "ws {id} reconnecting in {delay:?} (attempt {attempt}): {reason}");
+        let event = Reconnecting {
+            attempt,
+            delay_ms: delay.as_millis() as u64,
+            reason,
+        };
+        invoke_callback(&weak_service, id, "reconnecting", &event);
+        runtime::time::sleep(delay).await;
     }
 }
 
+/// Connects and serves a single ws session. `attempt` is reset once the handshake succeeds.
 async fn do_ws_open_inner(
     weak_service: ServiceWeakRef,
     id: u64,
-    options: OpenOptions,
-) -> Result<()> {
+    options: &OpenOptions,
+    attempt: &mut u32,
+) -> Result<Closed> {
     let request = {
         let mut builder = http::Request::builder().method("GET").uri(&options.url);
-        for (name, value) in options.headers.pairs {
+        for (name, value) in &options.headers.pairs {
             builder = builder.header(name, value);
         }
+        if !options.protocols.is_empty() {
+            builder = builder.header("Sec-WebSocket-Protocol", options.protocols.join(", "));
+        }
+        if options.per_message_deflate() {
+            builder = builder.header("Sec-WebSocket-Extensions", deflate::EXTENSION);
+        }
         builder.body(()).context("failed to build request")?
     };
-    let ws_config = options.config.map(Into::into);
     let url: http::Uri = options.url.parse().context("invalid url")?;
     let use_tls = url.scheme_str() == Some("wss");
     let host = url.host().context("missing host")?;
     let port = url.port_u16().unwrap_or(if use_tls { 443 } else { 80 });
-    let stream = runtime::TcpStream::connect(host, port, use_tls)
-        .await
-        .context("failed to connect to ws server")?;
+    let stream = match (&options.tls, use_tls) {
+        (Some(tls), true) => {
+            let tls = tls.build().context("invalid tls options")?;
+            runtime::tls_connect(host, port, &tls).await
+        }
+        _ => runtime::TcpStream::connect(host, port, use_tls).await,
+    }
+    .context("failed to connect to ws server")?;
     trace!(target: "js::ws", "tcp connected to ws server: {url}");
-    let (ws_stream, _response) =
+    if !options.per_message_deflate() {
+        return handshake_and_serve(weak_service, id, options, attempt, request, stream, None)
+            .await;
+    }
+    // The handshake goes through the relay, which learns its outcome once the response has
+    // been checked.
+    let (outcome_tx, outcome_rx) = watch::channel(deflate::Outcome::Pending);
+    let stream = deflate::spawn_relay(
+        stream,
+        deflate::Negotiation::Handshake(outcome_rx),
+        max_message_size(&options.config),
+    );
+    handshake_and_serve(
+        weak_service,
+        id,
+        options,
+        attempt,
+        request,
+        stream,
+        Some(outcome_tx),
+    )
+    .await
+}
+
+async fn handshake_and_serve<S>(
+    weak_service: ServiceWeakRef,
+    id: u64,
+    options: &OpenOptions,
+    attempt: &mut u32,
+    request: http::Request<()>,
+    stream: S,
+    deflate_outcome: Option<watch::Sender<deflate::Outcome>>,
+) -> Result<Closed>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + 'static,
+{
+    let high_water_mark = high_water_mark(&options.config);
+    let ws_config = options.config.clone().map(Into::into);
+    let (ws_stream, response) =
         async_tungstenite::client_async_with_config(request, stream.compat(), ws_config)
             .await
             .context("failed to open ws connection")?;
     trace!(target: "js::ws", "ws {id} handshake down");
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let info = OpenInfo {
+        protocol: header("sec-websocket-protocol"),
+        extensions: header("sec-websocket-extensions"),
+    };
+    if let Some(outcome_tx) = deflate_outcome {
+        let outcome = match deflate::accept_response(&info.extensions)? {
+            Some(params) => deflate::Outcome::Deflate(params),
+            None => deflate::Outcome::Plain,
+        };
+        let _ = outcome_tx.send(outcome);
+    }
+    if *attempt > 0 {
+        let event = Reconnected { attempt: *attempt };
+        invoke_callback(&weak_service, id, "reconnected", &event);
+        *attempt = 0;
+    }
+    let keepalive = options.keepalive();
+    serve_ws(
+        weak_service,
+        id,
+        ws_stream,
+        info,
+        high_water_mark,
+        keepalive,
+    )
+    .await
+}
+
+fn high_water_mark(config: &Option<WsConfig>) -> usize {
+    config
+        .as_ref()
+        .and_then(|config| config.high_water_mark)
+        .unwrap_or(DEFAULT_HIGH_WATER_MARK)
+}
+
+fn max_message_size(config: &Option<WsConfig>) -> usize {
+    config
+        .as_ref()
+        .and_then(|config| config.max_message_size)
+        .unwrap_or(deflate::DEFAULT_MAX_MESSAGE_SIZE)
+}
+
+/// Reports the `open` event with a `WsSink` and then forwards received messages to JS until
+/// the connection is closed.
+async fn serve_ws<S>(
+    weak_service: ServiceWeakRef,
+    id: u64,
+    ws_stream: async_tungstenite::WebSocketStream<S>,
+    info: OpenInfo,
+    high_water_mark: usize,
+    keepalive: Option<Keepalive>,
+) -> Result<Closed>
+where
+    S: futures::AsyncRead + futures::AsyncWrite + Unpin + 'static,
+{
     let (mut tx, mut rx) = ws_stream.split();
-    {
+    let sink_tx = {
         let service = weak_service.upgrade().context("service dropped")?;
-        let (ch_tx, mut ch_rx) = tokio::sync::mpsc::channel(10);
+        let (ch_tx, mut ch_rx) = tokio::sync::mpsc::unbounded_channel::<Outgoing>();
+        let state = Rc::new(SendState {
+            buffered: Cell::new(0),
+            high_water_mark,
+            need_drain: Cell::new(false),
+        });
+        let writer_state = state.clone();
+        let writer_service = weak_service.clone();
         runtime::spawn(async move {
-            while let Some(msg) = ch_rx.recv().await {
+            let state = writer_state;
+            let mut close_sent = false;
+            while let Some(Outgoing { msg, callback }) = ch_rx.recv().await {
                 trace!(target: "js::ws", "sending ws message: {:?}", msg);
-                if let Err(err) = tx.send(msg).await {
+                let len = msg.len();
+                close_sent |= msg.is_close();
+                let result = tx.send(msg).await;
+                state.buffered.set(state.buffered.get().saturating_sub(len));
+                let failed = result.is_err();
+                if let Err(err) = &result {
                     warn!(target: "js::ws", "failed to send ws message: {err:?}");
+                }
+                report_send_result(&writer_service, callback, result.map_err(|e| e.to_string()));
+                if failed {
                     break;
                 }
+                if state.need_drain.get() && state.buffered.get() == 0 {
+                    state.need_drain.set(false);
+                    invoke_callback(&writer_service, id, "drain", &js::Value::Undefined);
+                }
             }
             trace!(target: "js::ws", "ws {id} closed");
-            tx.send(Message::Close(None)).await.ok();
+            // Fail whatever is still queued so that no send is left pending forever.
+            ch_rx.close();
+            while let Some(Outgoing { msg, callback }) = ch_rx.recv().await {
+                state
+                    .buffered
+                    .set(state.buffered.get().saturating_sub(msg.len()));
+                report_send_result(&writer_service, callback, Err("connection closed".into()));
+            }
+            if !close_sent {
+                tx.send(Message::Close(None)).await.ok();
+            }
         });
-        let js_tx =
-            js::Value::new_opaque_object::<WsSink>(service.context(), Some("WsSink"), ch_tx);
-        trace!(target: "js::ws", "ws {id} opened");
-        invoke_callback(&weak_service, id, "open", &js_tx);
-    }
+        let weak_tx = ch_tx.downgrade();
+        let sink = WsSink { tx: ch_tx, state };
+        let js_tx = js::Value::new_opaque_object(service.context(), Some("WsSink"), sink);
+        trace!(target: "js::ws", "ws {id} opened: {info:?}");
+        let Some(callback) = service.get_resource_value(id) else {
+            info!(target: "js::ws", "ws {id} exited because the resource has been dropped");
+            return Ok(Closed::Locally);
+        };
+        if let Err(err) = service.call_function(callback, ("open", &js_tx, &info)) {
+            error!(target: "js::ws", "[{id}] failed to report ws event open: {err:?}");
+        }
+        weak_tx
+    };
+    let mut awaiting_pong = false;
     loop {
-        let Some(msg) = rx.next().await else {
+        let msg = match &keepalive {
+            None => rx.next().await,
+            Some(keepalive) => {
+                let idle = if awaiting_pong {
+                    keepalive.pong_timeout
+                } else {
+                    keepalive.ping_interval
+                };
+                tokio::select! {
+                    msg = rx.next() => msg,
+                    _ = runtime::time::sleep(idle) => {
+                        if awaiting_pong {
+                            bail!("no response to ping within {idle:?}");
+                        }
+                        if let Some(tx) = sink_tx.upgrade() {
+                            trace!(target: "js::ws", "ws {id} idle, sending ping");
+                            let msg = Message::Ping(vec![]);
+                            tx.send(Outgoing { msg, callback: None }).ok();
+                            awaiting_pong = true;
+                        }
+                        continue;
+                    }
+                }
+            }
+        };
+        // Anything from the peer proves the connection is alive.
+        awaiting_pong = false;
+        let Some(msg) = msg else {
             info!(target: "js::ws", "client closed ws {id}");
             break;
         };
@@ -251,12 +602,179 @@ async fn do_ws_open_inner(
                 invoke_callback(&weak_service, id, "message", &msg);
             }
             Err(err) => {
+                if sink_tx.upgrade().is_none() {
+                    break;
+                }
                 warn!(target: "js::ws", "ws {id} failed to receive message: {err:?}");
-                break;
+                return Err(err).context("failed to receive message");
             }
         }
     }
-    Ok(())
+    if sink_tx.upgrade().is_none() {
+        Ok(Closed::Locally)
+    } else {
+        Ok(Closed::Remotely)
+    }
+}
+
+#[cfg(feature = "js-https-listen")]
+#[derive(FromJsValue, Debug)]
+#[qjs(rename_all = "camelCase")]
+pub struct AcceptRequest {
+    headers: Headers,
+    opaque_response_tx: js::Value,
+    opaque_input_stream: js::Value,
+    opaque_output_stream: js::Value,
+}
+
+#[cfg(feature = "js-https-listen")]
+#[derive(FromJsValue, Debug, Default)]
+#[qjs(rename_all = "camelCase")]
+pub struct AcceptOptions {
+    /// Extra headers to send along with the `101 Switching Protocols` response.
+    #[qjs(default)]
+    headers: Headers,
+    config: Option<WsConfig>,
+    /// The subprotocol selected from the ones offered by the client.
+    protocol: Option<String>,
+}
+
+/// Completes the WebSocket handshake of a request received by `httpsListen` and serves the
+/// connection with the same events as `wsOpen`.
+#[cfg(feature = "js-https-listen")]
+#[js::host_call(with_context)]
+fn ws_accept(
+    service: ServiceRef,
+    _this: js::Value,
+    request: AcceptRequest,
+    options: AcceptOptions,
+    callback: OwnedJsValue,
+) -> Result<u64> {
+    use super::stream::{InputStream, OutputStream};
+    use async_tungstenite::tungstenite::handshake::derive_accept_key;
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+    let header = |name: &str| {
+        request
+            .headers
+            .pairs
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    };
+    let is_websocket = header("upgrade")
+        .map(|v| v.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false);
+    if !is_websocket {
+        bail!("not a websocket upgrade request");
+    }
+    if header("sec-websocket-version") != Some("13") {
+        bail!("unsupported websocket version");
+    }
+    let key = header("sec-websocket-key").context("missing Sec-WebSocket-Key")?;
+    let accept_key = derive_accept_key(key.as_bytes());
+
+    if let Some(protocol) = &options.protocol {
+        let offered = header("sec-websocket-protocol").unwrap_or_default();
+        if !offered.split(',').any(|p| p.trim() == protocol) {
+            bail!("subprotocol {protocol:?} was not offered by the client");
+        }
+    }
+
+    {
+        let input = request
+            .opaque_input_stream
+            .opaque_object_data::<InputStream>();
+        let output = request
+            .opaque_output_stream
+            .opaque_object_data::<OutputStream>();
+        let reader = input
+            .get()
+            .context("input stream already taken")?
+            .downcast_ref::<ReadHalf<DuplexStream>>()
+            .context("input stream is not an upgradable request body")?;
+        let writer = output
+            .get()
+            .context("output stream already taken")?
+            .downcast_ref::<WriteHalf<DuplexStream>>()
+            .context("output stream is not an upgradable response body")?;
+        if !reader.is_pair_of(writer) {
+            bail!("input and output streams do not belong to the same request");
+        }
+    }
+
+    let mut headers: Vec<(String, String)> = vec![
+        ("Upgrade".into(), "websocket".into()),
+        ("Connection".into(), "Upgrade".into()),
+        ("Sec-WebSocket-Accept".into(), accept_key),
+    ];
+    if let Some(protocol) = &options.protocol {
+        headers.push(("Sec-WebSocket-Protocol".into(), protocol.clone()));
+    }
+    let per_message_deflate = options
+        .config
+        .as_ref()
+        .and_then(|config| config.per_message_deflate)
+        .unwrap_or(false);
+    let deflate = match header("sec-websocket-extensions") {
+        Some(offers) if per_message_deflate => deflate::accept_offer(offers),
+        _ => None,
+    };
+    if let Some((_, extension)) = &deflate {
+        headers.push(("Sec-WebSocket-Extensions".into(), extension.clone()));
+    }
+    headers.extend(options.headers.pairs);
+    super::https_listen::send_response_head(request.opaque_response_tx, 101, headers)?;
+
+    // The streams are only taken once the response head is sent, so a request that fails to
+    // upgrade keeps its body streams for a regular response.
+    let reader = InputStream::take(&request.opaque_input_stream)
+        .and_then(|stream| stream.downcast::<ReadHalf<DuplexStream>>().ok())
+        .context("input stream is gone")?;
+    let writer = OutputStream::take(&request.opaque_output_stream)
+        .and_then(|stream| stream.downcast::<WriteHalf<DuplexStream>>().ok())
+        .context("output stream is gone")?;
+
+    let mut stream = reader.unsplit(writer);
+    let high_water_mark = high_water_mark(&options.config);
+    let mut extensions = String::new();
+    if let Some((params, extension)) = deflate {
+        let negotiation = deflate::Negotiation::Done(params);
+        stream = deflate::spawn_relay(stream, negotiation, max_message_size(&options.config));
+        extensions = extension;
+    }
+    let ws_config = options.config.map(Into::into);
+    let info = OpenInfo {
+        protocol: options.protocol.unwrap_or_default(),
+        extensions,
+    };
+    debug!(target: "js::ws", "accepting ws connection");
+    Ok(service.spawn(
+        callback,
+        |weak_service, id, (stream, ws_config, info, high_water_mark)| async move {
+            use async_tungstenite::tungstenite::protocol::Role;
+            let ws_stream = async_tungstenite::WebSocketStream::from_raw_socket(
+                stream.compat(),
+                Role::Server,
+                ws_config,
+            )
+            .await;
+            let result = serve_ws(
+                weak_service.clone(),
+                id,
+                ws_stream,
+                info,
+                high_water_mark,
+                None,
+            )
+            .await;
+            if let Err(err) = result {
+                warn!(target: "js::ws", "ws {id} failed: {err:?}");
+                invoke_callback(&weak_service, id, "error", &format!("{err:?}"));
+            }
+        },
+        (stream, ws_config, info, high_water_mark),
+    ))
 }
 
 fn invoke_callback(weak_service: &Weak<Service>, id: u64, name: &str, data: &dyn ToJsValue) {
@@ -273,24 +791,82 @@ fn invoke_callback(weak_service: &Weak<Service>, id: u64, name: &str, data: &dyn
     }
 }
 
+fn report_send_result(
+    weak_service: &ServiceWeakRef,
+    callback: Option<js::Value>,
+    result: Result<(), String>,
+) {
+    let Some(callback) = callback else {
+        return;
+    };
+    let Some(service) = weak_service.upgrade() else {
+        return;
+    };
+    let result = match result {
+        Ok(()) => service.call_function(callback, (true, js::Value::Null)),
+        Err(err) => service.call_function(callback, (false, err)),
+    };
+    if let Err(err) = result {
+        warn!(target: "js::ws", "failed to report send result: {err:?}");
+    }
+}
+
+/// Queues a message for sending.
+///
+/// The optional `callback` is called with `(true, null)` once the frame has been flushed to the
+/// socket, or `(false, error)` if it could not be sent. Returns false if the queued bytes
+/// exceed the high water mark, in which case a `drain` event is reported once the queue is
+/// flushed.
 #[js::host_call]
-fn ws_send(tx: js::Value, msg: WsMessage) -> Result<()> {
+fn ws_send(tx: js::Value, msg: WsMessage, callback: Option<js::Value>) -> Result<bool> {
     trace!(target: "js::ws", "sending ws message: {msg:?}");
-    let mut tx = tx.opaque_object_data_mut::<WsSink>();
-    let msg = msg.try_into().context("invalid message")?;
-    tx.get_mut()
-        .context("closed")?
-        .try_send(msg)
-        .context("failed to send message")?;
-    Ok(())
+    let guard = tx.opaque_object_data::<WsSink>();
+    let sink = guard.get().context("closed")?;
+    let msg: Message = msg.try_into().context("invalid message")?;
+    let len = msg.len();
+    if sink.tx.send(Outgoing { msg, callback }).is_err() {
+        bail!("failed to send message: connection closed");
+    }
+    let state = &sink.state;
+    state.buffered.set(state.buffered.get() + len);
+    if state.buffered.get() > state.high_water_mark {
+        state.need_drain.set(true);
+        return Ok(false);
+    }
+    Ok(true)
 }
 
+/// Returns the number of bytes queued but not yet flushed to the socket.
 #[js::host_call]
-fn ws_close(tx: js::Value) -> Result<()> {
-    trace!(target: "js::ws", "closing ws");
+fn ws_buffered_amount(tx: js::Value) -> usize {
+    let guard = tx.opaque_object_data::<WsSink>();
+    guard
+        .get()
+        .map(|sink| sink.state.buffered.get())
+        .unwrap_or(0)
+}
+
+/// Closes the connection, sending a close frame with `code` and `reason` if a code is given.
+#[js::host_call]
+fn ws_close(tx: js::Value, code: Option<u16>, reason: Option<String>) -> Result<()> {
+    trace!(target: "js::ws", "closing ws: {code:?} {reason:?}");
     let Some(tx) = tx.opaque_object_take_data::<WsSink>() else {
         bail!("already closed");
     };
-    drop(tx);
+    if let Some(code) = code {
+        let frame = CloseFrame {
+            code: code.into(),
+            reason: reason.unwrap_or_default().into(),
+        };
+        let msg = Message::Close(Some(frame));
+        tx.state.buffered.set(tx.state.buffered.get() + msg.len());
+        tx.tx
+            .send(Outgoing {
+                msg,
+                callback: None,
+            })
+            .ok()
+            .context("failed to send close frame")?;
+    }
     Ok(())
 }
@@ -0,0 +1,558 @@
+//! The permessage-deflate extension ([RFC 7692](https://www.rfc-editor.org/rfc/rfc7692)).
+//!
+//! tungstenite rejects frames with the RSV1 bit that marks compressed messages, so compression
+//! is done by a relay between the socket and tungstenite. The relay inflates the messages
+//! received from the peer and deflates the ones sent by tungstenite, passing control frames
+//! through untouched.
+//!
+//! Window sizes other than the default 32K are never negotiated, as the rust deflate backend
+//! can't limit the window of the compressor.
+
+use std::io::{self, Write as _};
+
+use flate2::{write::DeflateDecoder, write::DeflateEncoder, Compression};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::watch;
+
+use super::*;
+
+/// The extension name, and the offer of a client.
+pub(super) const EXTENSION: &str = "permessage-deflate";
+
+/// A sync flush ends with this empty stored block, which is stripped from messages.
+const FLUSH_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+const HANDSHAKE_MAX_SIZE: usize = 16 * 1024;
+const RELAY_BUF_SIZE: usize = 8192;
+/// Inflated messages are handed to tungstenite in fragments of at most this size, to stay
+/// below its frame size limit.
+const MAX_FRAGMENT_SIZE: usize = 64 * 1024;
+/// The default message size limit of tungstenite.
+pub(super) const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const RSV1: u8 = 0x40;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(super) struct DeflateParams {
+    /// Our compression context is reset after each message.
+    local_no_context_takeover: bool,
+    /// The peer resets its compression context after each message.
+    remote_no_context_takeover: bool,
+}
+
+/// The outcome of a handshake that is relayed while it is in progress.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Outcome {
+    Pending,
+    Plain,
+    Deflate(DeflateParams),
+}
+
+#[derive(Clone)]
+pub(super) enum Negotiation {
+    /// The HTTP handshake goes through the relay, which waits for its outcome before touching
+    /// any frame.
+    Handshake(watch::Receiver<Outcome>),
+    Done(DeflateParams),
+}
+
+/// Splits a `Sec-WebSocket-Extensions` value into extensions and their parameters.
+fn parse_extensions(header: &str) -> impl Iterator<Item = (&str, Vec<(&str, Option<&str>)>)> {
+    header.split(',').filter_map(|extension| {
+        let mut parts = extension.split(';').map(str::trim);
+        let name = parts.next().filter(|name| !name.is_empty())?;
+        let params = parts
+            .filter(|param| !param.is_empty())
+            .map(|param| match param.split_once('=') {
+                Some((key, value)) => (key.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            })
+            .collect();
+        Some((name, params))
+    })
+}
+
+/// Checks the extensions accepted by a server in response to our offer.
+pub(super) fn accept_response(header: &str) -> Result<Option<DeflateParams>> {
+    let Some((name, params)) = parse_extensions(header).next() else {
+        return Ok(None);
+    };
+    if name != EXTENSION {
+        bail!("server accepted the extension {name} which was not offered");
+    }
+    let mut negotiated = DeflateParams::default();
+    for (key, _) in params {
+        match key {
+            "server_no_context_takeover" => negotiated.remote_no_context_takeover = true,
+            "client_no_context_takeover" => negotiated.local_no_context_takeover = true,
+            // Inflating with the full window handles any smaller window.
+            "server_max_window_bits" => {}
+            _ => bail!("unsupported {EXTENSION} parameter {key}"),
+        }
+    }
+    Ok(Some(negotiated))
+}
+
+/// Picks the first acceptable permessage-deflate offer of a client, returning the negotiated
+/// parameters and the extension to respond with.
+pub(super) fn accept_offer(header: &str) -> Option<(DeflateParams, String)> {
+    'offers: for (name, params) in parse_extensions(header) {
+        if name != EXTENSION {
+            continue;
+        }
+        let mut negotiated = DeflateParams::default();
+        let mut response = String::from(EXTENSION);
+        for (key, value) in params {
+            match key {
+                "server_no_context_takeover" => {
+                    negotiated.local_no_context_takeover = true;
+                    response.push_str("; server_no_context_takeover");
+                }
+                "client_no_context_takeover" => {
+                    negotiated.remote_no_context_takeover = true;
+                    response.push_str("; client_no_context_takeover");
+                }
+                // Not echoing the hint keeps the client at the full window.
+                "client_max_window_bits" => {}
+                "server_max_window_bits" if value == Some("15") => {
+                    response.push_str("; server_max_window_bits=15");
+                }
+                _ => continue 'offers,
+            }
+        }
+        return Some((negotiated, response));
+    }
+    None
+}
+
+struct Frame {
+    fin: bool,
+    /// The RSV1-3 bits.
+    rsv: u8,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    /// The unmasked payload.
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn is_control(&self) -> bool {
+        self.opcode & 0x8 != 0
+    }
+}
+
+/// A new masking key for a frame that is masked, as each frame needs its own unpredictable key
+/// ([RFC 6455 §5.3](https://www.rfc-editor.org/rfc/rfc6455#section-5.3)).
+fn fresh_mask(mask: Option<[u8; 4]>) -> Option<[u8; 4]> {
+    mask.map(|_| {
+        let mut mask = [0u8; 4];
+        crate::runtime::getrandom(&mut mask).expect("failed to get random bytes");
+        mask
+    })
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(
+    rx: &mut R,
+    max_size: usize,
+) -> io::Result<Option<Frame>> {
+    let mut head = [0u8; 2];
+    match rx.read_exact(&mut head).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = match head[1] & 0x7f {
+        126 => rx.read_u16().await? as u64,
+        127 => rx.read_u64().await?,
+        len => len as u64,
+    };
+    if len > max_size as u64 {
+        return Err(io::Error::other(format!(
+            "frame of {len} bytes is too large"
+        )));
+    }
+    let mask = if head[1] & 0x80 != 0 {
+        let mut mask = [0u8; 4];
+        rx.read_exact(&mut mask).await?;
+        Some(mask)
+    } else {
+        None
+    };
+    let mut payload = vec![0u8; len as usize];
+    rx.read_exact(&mut payload).await?;
+    if let Some(mask) = mask {
+        apply_mask(&mut payload, mask);
+    }
+    Ok(Some(Frame {
+        fin: head[0] & 0x80 != 0,
+        rsv: head[0] & 0x70,
+        opcode: head[0] & 0x0f,
+        mask,
+        payload,
+    }))
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(tx: &mut W, frame: Frame) -> io::Result<()> {
+    let Frame {
+        fin,
+        rsv,
+        opcode,
+        mask,
+        mut payload,
+    } = frame;
+    let mut buf = Vec::with_capacity(payload.len() + 14);
+    buf.push(if fin { 0x80 } else { 0 } | rsv | opcode);
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => buf.push(mask_bit | len as u8),
+        len @ 126..=0xffff => {
+            buf.push(mask_bit | 126);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            buf.push(mask_bit | 127);
+            buf.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    if let Some(mask) = mask {
+        buf.extend_from_slice(&mask);
+        apply_mask(&mut payload, mask);
+    }
+    buf.extend_from_slice(&payload);
+    tx.write_all(&buf).await
+}
+
+struct Deflater {
+    encoder: DeflateEncoder<Vec<u8>>,
+    no_context_takeover: bool,
+}
+
+impl Deflater {
+    fn new(no_context_takeover: bool) -> Self {
+        Self {
+            encoder: DeflateEncoder::new(Vec::new(), Compression::default()),
+            no_context_takeover,
+        }
+    }
+
+    fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.encoder.write_all(data)?;
+        // Flushing a deflate writer is a sync flush.
+        self.encoder.flush()?;
+        let mut compressed = core::mem::take(self.encoder.get_mut());
+        if compressed.ends_with(&FLUSH_TAIL) {
+            compressed.truncate(compressed.len() - FLUSH_TAIL.len());
+        }
+        if self.no_context_takeover {
+            self.encoder.reset(Vec::new())?;
+        }
+        Ok(compressed)
+    }
+}
+
+struct Inflater {
+    decoder: DeflateDecoder<Vec<u8>>,
+    no_context_takeover: bool,
+    max_size: usize,
+}
+
+impl Inflater {
+    fn new(no_context_takeover: bool, max_size: usize) -> Self {
+        Self {
+            decoder: DeflateDecoder::new(Vec::new()),
+            no_context_takeover,
+            max_size,
+        }
+    }
+
+    fn decompress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        // Fed in small chunks so that a compression bomb is caught early.
+        for chunk in data.chunks(4096).chain([&FLUSH_TAIL[..]]) {
+            self.decoder.write_all(chunk)?;
+            if self.decoder.get_ref().len() > self.max_size {
+                return Err(io::Error::other("inflated message is too large"));
+            }
+        }
+        self.decoder.flush()?;
+        let inflated = core::mem::take(self.decoder.get_mut());
+        if inflated.len() > self.max_size {
+            return Err(io::Error::other("inflated message is too large"));
+        }
+        if self.no_context_takeover {
+            self.decoder.reset(Vec::new())?;
+        }
+        Ok(inflated)
+    }
+}
+
+/// Relays `stream` through a duplex pipe whose other end speaks plain WebSocket to
+/// tungstenite.
+pub(super) fn spawn_relay<S>(
+    stream: S,
+    negotiation: Negotiation,
+    max_message_size: usize,
+) -> DuplexStream
+where
+    S: AsyncRead + AsyncWrite + 'static,
+{
+    let (app_end, relay_end) = tokio::io::duplex(RELAY_BUF_SIZE);
+    let (peer_rx, peer_tx) = tokio::io::split(stream);
+    let (app_rx, app_tx) = tokio::io::split(relay_end);
+    let inbound_negotiation = negotiation.clone();
+    runtime::spawn(async move {
+        if let Err(err) = inbound(peer_rx, app_tx, inbound_negotiation, max_message_size).await {
+            debug!(target: "js::ws", "deflate relay from peer ended: {err}");
+        }
+    });
+    runtime::spawn(async move {
+        if let Err(err) = outbound(app_rx, peer_tx, negotiation).await {
+            debug!(target: "js::ws", "deflate relay to peer ended: {err}");
+        }
+    });
+    app_end
+}
+
+/// Forwards the HTTP head of the handshake, returning the bytes read past its end.
+async fn relay_head<R, W>(rx: &mut R, tx: &mut W) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = Vec::new();
+    loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(pos + 4);
+            tx.write_all(&buf).await?;
+            return Ok(rest);
+        }
+        if buf.len() > HANDSHAKE_MAX_SIZE {
+            return Err(io::Error::other("handshake is too large"));
+        }
+        if rx.read_buf(&mut buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+/// Relays the handshake if it goes through the relay, then returns the negotiated parameters
+/// and the bytes read past the handshake.
+async fn negotiate<R, W>(
+    rx: &mut R,
+    tx: &mut W,
+    negotiation: Negotiation,
+) -> io::Result<(Option<DeflateParams>, Vec<u8>)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match negotiation {
+        Negotiation::Done(params) => Ok((Some(params), vec![])),
+        Negotiation::Handshake(mut outcome) => {
+            let rest = relay_head(rx, tx).await?;
+            let outcome = *outcome
+                .wait_for(|outcome| !matches!(outcome, Outcome::Pending))
+                .await
+                .map_err(|_| io::Error::other("handshake failed"))?;
+            let params = match outcome {
+                Outcome::Deflate(params) => Some(params),
+                _ => None,
+            };
+            Ok((params, rest))
+        }
+    }
+}
+
+async fn inbound<R, W>(
+    mut rx: R,
+    mut tx: W,
+    negotiation: Negotiation,
+    max_message_size: usize,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (params, rest) = negotiate(&mut rx, &mut tx, negotiation).await?;
+    let mut rx = io::Cursor::new(rest).chain(rx);
+    let Some(params) = params else {
+        tokio::io::copy(&mut rx, &mut tx).await?;
+        return tx.shutdown().await;
+    };
+    let mut inflater = Inflater::new(params.remote_no_context_takeover, max_message_size);
+    // The first frame and the payload so far of a compressed message.
+    let mut message: Option<(Frame, Vec<u8>)> = None;
+    while let Some(frame) = read_frame(&mut rx, max_message_size).await? {
+        if frame.is_control() {
+            write_frame(&mut tx, frame).await?;
+            continue;
+        }
+        let fin = frame.fin;
+        match message.as_mut() {
+            Some((_, payload)) if frame.opcode == OPCODE_CONTINUATION => {
+                if payload.len() + frame.payload.len() > max_message_size {
+                    return Err(io::Error::other("message is too large"));
+                }
+                payload.extend_from_slice(&frame.payload);
+            }
+            _ if frame.opcode != OPCODE_CONTINUATION && frame.rsv & RSV1 != 0 => {
+                let mut frame = frame;
+                let payload = core::mem::take(&mut frame.payload);
+                message = Some((frame, payload));
+            }
+            // An uncompressed message, or a fragment of one.
+            _ => {
+                write_frame(&mut tx, frame).await?;
+                continue;
+            }
+        }
+        if !fin {
+            continue;
+        }
+        let Some((first, payload)) = message.take() else {
+            continue;
+        };
+        let inflated = inflater.decompress(&payload)?;
+        let fragments: Vec<&[u8]> = if inflated.is_empty() {
+            vec![&[]]
+        } else {
+            inflated.chunks(MAX_FRAGMENT_SIZE).collect()
+        };
+        let last = fragments.len() - 1;
+        for (i, fragment) in fragments.into_iter().enumerate() {
+            let frame = Frame {
+                fin: i == last,
+                rsv: first.rsv & !RSV1,
+                opcode: if i == 0 {
+                    first.opcode
+                } else {
+                    OPCODE_CONTINUATION
+                },
+                mask: fresh_mask(first.mask),
+                payload: fragment.to_vec(),
+            };
+            write_frame(&mut tx, frame).await?;
+        }
+    }
+    tx.shutdown().await
+}
+
+async fn outbound<R, W>(mut rx: R, mut tx: W, negotiation: Negotiation) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (params, rest) = negotiate(&mut rx, &mut tx, negotiation).await?;
+    let mut rx = io::Cursor::new(rest).chain(rx);
+    let Some(params) = params else {
+        tokio::io::copy(&mut rx, &mut tx).await?;
+        return tx.shutdown().await;
+    };
+    let mut deflater = Deflater::new(params.local_no_context_takeover);
+    // The first frame and the payload so far of the message being sent.
+    let mut message: Option<(Frame, Vec<u8>)> = None;
+    // tungstenite enforces its own limits on what it sends, this only bounds the buffering.
+    while let Some(frame) = read_frame(&mut rx, usize::MAX).await? {
+        if frame.is_control() {
+            write_frame(&mut tx, frame).await?;
+            continue;
+        }
+        let fin = frame.fin;
+        match message.as_mut() {
+            Some((_, payload)) if frame.opcode == OPCODE_CONTINUATION => {
+                payload.extend_from_slice(&frame.payload);
+            }
+            _ => {
+                let mut frame = frame;
+                let payload = core::mem::take(&mut frame.payload);
+                message = Some((frame, payload));
+            }
+        }
+        if !fin {
+            continue;
+        }
+        let Some((first, payload)) = message.take() else {
+            continue;
+        };
+        // An empty message can't be represented compressed, so it is sent as is.
+        let (rsv, payload) = if payload.is_empty() {
+            (first.rsv, payload)
+        } else {
+            (first.rsv | RSV1, deflater.compress(&payload)?)
+        };
+        let frame = Frame {
+            fin: true,
+            rsv,
+            opcode: first.opcode,
+            mask: fresh_mask(first.mask),
+            payload,
+        };
+        write_frame(&mut tx, frame).await?;
+    }
+    tx.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compress_round_trip_with_context_takeover() {
+        let mut deflater = Deflater::new(false);
+        let mut inflater = Inflater::new(false, DEFAULT_MAX_MESSAGE_SIZE);
+        for message in ["hello hello hello", "hello again", ""] {
+            let compressed = deflater.compress(message.as_bytes()).unwrap();
+            assert!(!compressed.ends_with(&FLUSH_TAIL));
+            let inflated = inflater.decompress(&compressed).unwrap();
+            assert_eq!(inflated, message.as_bytes());
+        }
+    }
+
+    #[test]
+    fn inflates_rfc_example() {
+        // "Hello" compressed, from RFC 7692 section 7.2.3.1.
+        let compressed = [0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
+        let mut inflater = Inflater::new(true, DEFAULT_MAX_MESSAGE_SIZE);
+        assert_eq!(inflater.decompress(&compressed).unwrap(), b"Hello");
+    }
+
+    #[test]
+    fn rejects_oversized_messages() {
+        let mut deflater = Deflater::new(true);
+        let compressed = deflater.compress(&[0u8; 100_000]).unwrap();
+        let mut inflater = Inflater::new(true, 1000);
+        assert!(inflater.decompress(&compressed).is_err());
+    }
+
+    #[test]
+    fn negotiates_offers() {
+        assert_eq!(accept_offer("x-webkit-deflate-frame"), None);
+        let (params, response) =
+            accept_offer("permessage-deflate; client_max_window_bits").unwrap();
+        assert_eq!(params, DeflateParams::default());
+        assert_eq!(response, "permessage-deflate");
+        // The first offer limits our window, which is not supported.
+        let (params, response) = accept_offer(
+            "permessage-deflate; server_max_window_bits=10, permessage-deflate; server_no_context_takeover",
+        )
+        .unwrap();
+        assert!(params.local_no_context_takeover);
+        assert_eq!(response, "permessage-deflate; server_no_context_takeover");
+    }
+
+    #[test]
+    fn checks_responses() {
+        assert_eq!(accept_response("").unwrap(), None);
+        let params = accept_response("permessage-deflate; client_no_context_takeover")
+            .unwrap()
+            .unwrap();
+        assert!(params.local_no_context_takeover);
+        assert!(accept_response("permessage-deflate; client_max_window_bits=10").is_err());
+        assert!(accept_response("x-other").is_err());
+    }
+}
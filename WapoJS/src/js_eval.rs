@@ -4,7 +4,7 @@ use std::env;
 use js::ToJsValue;
 
 use crate::{
-    service::{ServiceConfig, ServiceRef},
+    service::{HttpClientConfig, ImportMap, ServiceConfig, ServiceRef},
     Service,
 };
 use anyhow::{anyhow, bail, Context, Result};
@@ -14,12 +14,71 @@ use dotenv;
 
 use pink_types::js::{JsCode, JsValue};
 
+/// A script given on the command line.
+enum Script {
+    Classic(JsCode),
+    /// A script file, named by its path in stack traces.
+    File {
+        path: String,
+        source: String,
+    },
+    /// An ES module, named by its path or `hash:<code_hash>` to resolve its relative imports.
+    Module {
+        name: String,
+        source: String,
+    },
+}
+
+impl Script {
+    /// The name of the script in stack traces, if it has a meaningful one.
+    fn name(&self) -> Option<&str> {
+        match self {
+            Script::Classic(_) => None,
+            Script::File { path, .. } => Some(path),
+            Script::Module { name, .. } => Some(name),
+        }
+    }
+
+    fn source(&self) -> Option<&str> {
+        match self {
+            Script::Classic(JsCode::Source(source)) => Some(source),
+            Script::Classic(JsCode::Bytecode(_)) => None,
+            Script::File { source, .. } | Script::Module { source, .. } => Some(source),
+        }
+    }
+}
+
+/// How the result of the script is emitted through `runtime::set_output`.
+#[derive(Clone, Default)]
+enum OutputFormat {
+    /// Nothing is emitted, the result is only returned to the caller of [`run`].
+    #[default]
+    None,
+    /// `{"ok":true,"output":...}` or `{"ok":false,"error":{"message":...,"stack":...}}`, with
+    /// bytes as `0x` prefixed hex strings.
+    Json,
+    /// `Result<T, {message: str, stack: str}>`, where `T` is the type 0 of the given type
+    /// registry.
+    Scale(String),
+}
+
 struct Args {
     #[cfg(feature = "native")]
     tls_port: u16,
-    codes: Vec<JsCode>,
+    codes: Vec<Script>,
     js_args: Vec<String>,
     worker_secret: String,
+    http_client: HttpClientConfig,
+    exit_on_unhandled_rejection: bool,
+    import_map: ImportMap,
+    /// CommonJS module sources keyed by their virtual path.
+    cjs_modules: Vec<(String, String)>,
+    /// Where compiled scripts are cached, keyed by the hash of their source.
+    #[cfg(feature = "native")]
+    cache_dir: Option<std::path::PathBuf>,
+    output_format: OutputFormat,
+    /// Source maps keyed by the name of the script they map.
+    source_maps: Vec<(String, String)>,
 }
 
 #[cfg(feature = "wapo")]
@@ -36,6 +95,15 @@ fn parse_args(args: impl Iterator<Item = String>) -> Result<Args> {
     #[cfg(feature = "native")]
     let mut tls_port = 443_u16;
     let mut worker_secret: Option<String> = None;
+    let mut http_client = HttpClientConfig::default();
+    let mut exit_on_unhandled_rejection = false;
+    let mut import_map = ImportMap::default();
+    let mut cjs_modules = vec![];
+    let mut output_format = OutputFormat::None;
+    let mut scale_types = None;
+    let mut source_maps = vec![];
+    #[cfg(feature = "native")]
+    let mut cache_dir = std::env::var_os("WAPOJS_CACHE_DIR").map(std::path::PathBuf::from);
     while let Some(arg) = iter.next() {
         if arg.starts_with("-") {
             if arg == "--" {
@@ -49,7 +117,38 @@ fn parse_args(args: impl Iterator<Item = String>) -> Result<Args> {
                         .ok_or(anyhow!("missing value after --code-hash"))?;
                     let code =
                         load_code(&code_hash).context("failed to load code with given hash")?;
-                    codes.push(JsCode::Source(code));
+                    codes.push(Script::Classic(JsCode::Source(code)));
+                }
+                #[cfg(feature = "wapo")]
+                "--module-hash" => {
+                    let code_hash = iter
+                        .next()
+                        .ok_or(anyhow!("missing value after --module-hash"))?;
+                    let source =
+                        load_code(&code_hash).context("failed to load module with given hash")?;
+                    codes.push(Script::Module {
+                        name: format!("hash:{code_hash}"),
+                        source,
+                    });
+                }
+                #[cfg(feature = "wapo")]
+                "--require-bundle-hash" => {
+                    let code_hash = iter
+                        .next()
+                        .ok_or(anyhow!("missing value after --require-bundle-hash"))?;
+                    let bundle = load_code(&code_hash).context("failed to load module bundle")?;
+                    let bundle: std::collections::BTreeMap<String, String> =
+                        serde_json::from_str(&bundle).context("invalid module bundle")?;
+                    cjs_modules.extend(bundle);
+                }
+                #[cfg(feature = "native")]
+                "--require-root" => {
+                    let root = iter
+                        .next()
+                        .ok_or(anyhow!("missing path after --require-root"))?;
+                    let root = std::path::Path::new(&root);
+                    collect_modules(root, root, &mut cjs_modules)
+                        .context("failed to load modules from --require-root")?;
                 }
                 #[cfg(feature = "native")]
                 "--tls-port" => {
@@ -60,7 +159,45 @@ fn parse_args(args: impl Iterator<Item = String>) -> Result<Args> {
                 }
                 "-c" => {
                     let code = iter.next().ok_or(anyhow!("missing code after -c"))?;
-                    codes.push(JsCode::Source(code));
+                    codes.push(Script::Classic(JsCode::Source(code)));
+                }
+                "-b" | "--bytecode" => {
+                    let path = iter.next().ok_or(anyhow!("missing path after {arg}"))?;
+                    let bytecode = std::fs::read(path).context("failed to read bytecode file")?;
+                    codes.push(Script::Classic(JsCode::Bytecode(bytecode)));
+                }
+                #[cfg(feature = "native")]
+                "--cache-dir" => {
+                    let dir = iter
+                        .next()
+                        .ok_or(anyhow!("missing path after --cache-dir"))?;
+                    cache_dir = Some(dir.into());
+                }
+                "--source-map" => {
+                    let path = iter
+                        .next()
+                        .ok_or(anyhow!("missing path after --source-map"))?;
+                    let name = codes
+                        .last()
+                        .and_then(Script::name)
+                        .ok_or(anyhow!("--source-map must follow a script file or module"))?;
+                    let json =
+                        std::fs::read_to_string(&path).context("failed to read source map file")?;
+                    source_maps.push((name.to_string(), json));
+                }
+                "-m" | "--module" => {
+                    let path = iter.next().ok_or(anyhow!("missing path after {arg}"))?;
+                    codes.push(read_module(path)?);
+                }
+                "--import-map" => {
+                    let path = iter
+                        .next()
+                        .ok_or(anyhow!("missing path after --import-map"))?;
+                    let json =
+                        std::fs::read_to_string(&path).context("failed to read import map file")?;
+                    let path = absolute_path(path);
+                    let base_dir = path.rsplit_once('/').map_or(".", |(dir, _)| dir);
+                    import_map = ImportMap::parse(&json, base_dir)?;
                 }
                 #[cfg(feature = "native")]
                 "-e" => {
@@ -80,15 +217,51 @@ fn parse_args(args: impl Iterator<Item = String>) -> Result<Args> {
                         .ok_or(anyhow!("missing value after --worker-secret"))?;
                     worker_secret = Some(secret);
                 }
+                "--http-pool-idle-timeout" => {
+                    let timeout_ms: u64 = iter
+                        .next()
+                        .ok_or(anyhow!("missing value after --http-pool-idle-timeout"))?
+                        .parse()?;
+                    http_client.pool_idle_timeout = std::time::Duration::from_millis(timeout_ms);
+                }
+                "--http-pool-max-idle" => {
+                    http_client.pool_max_idle_per_host = iter
+                        .next()
+                        .ok_or(anyhow!("missing value after --http-pool-max-idle"))?
+                        .parse()?;
+                }
+                "--output" => {
+                    output_format = match iter
+                        .next()
+                        .ok_or(anyhow!("missing value after --output"))?
+                        .as_str()
+                    {
+                        "none" => OutputFormat::None,
+                        "json" => OutputFormat::Json,
+                        "scale" => OutputFormat::Scale(String::new()),
+                        format => bail!("unknown output format: {format}"),
+                    };
+                }
+                "--output-scale-types" => {
+                    scale_types = Some(
+                        iter.next()
+                            .ok_or(anyhow!("missing value after --output-scale-types"))?,
+                    );
+                }
+                "--exit-on-unhandled-rejection" => {
+                    exit_on_unhandled_rejection = true;
+                }
                 _ => {
                     print_usage();
                     bail!("unknown option: {}", arg);
                 }
             }
+        } else if arg.ends_with(".mjs") {
+            codes.push(read_module(arg)?);
         } else {
             // File name
-            let code = std::fs::read_to_string(arg).context("failed to read script file")?;
-            codes.push(JsCode::Source(code));
+            let source = std::fs::read_to_string(&arg).context("failed to read script file")?;
+            codes.push(Script::File { path: arg, source });
         }
     }
     if codes.is_empty() {
@@ -99,6 +272,35 @@ fn parse_args(args: impl Iterator<Item = String>) -> Result<Args> {
     if worker_secret.is_none() {
         log::warn!("worker secret is not provided, using default worker secret: wapo-testnet");
     }
+    for code in codes.iter() {
+        let (Some(name), Some(source)) = (code.name(), code.source()) else {
+            continue;
+        };
+        if source_maps.iter().any(|(mapped, _)| mapped == name) {
+            continue;
+        }
+        match crate::service::find_source_map(name, source) {
+            Ok(Some(json)) => source_maps.push((name.to_string(), json)),
+            Ok(None) => {}
+            Err(err) => log::warn!(target: "js", "ignored the source map of {name}: {err:#}"),
+        }
+    }
+    #[cfg(feature = "native")]
+    if let Some(cache_dir) = &cache_dir {
+        for code in codes.iter_mut() {
+            let bytecode = match code {
+                Script::Classic(JsCode::Source(source)) => {
+                    compile_cached(cache_dir, source, "<eval>")?
+                }
+                Script::File { path, source } => compile_cached(cache_dir, source, path)?,
+                _ => continue,
+            };
+            *code = Script::Classic(JsCode::Bytecode(bytecode));
+        }
+    }
+    if let OutputFormat::Scale(types) = &mut output_format {
+        *types = scale_types.ok_or(anyhow!("--output scale requires --output-scale-types"))?;
+    }
     let js_args = iter.collect();
     Ok(Args {
         codes,
@@ -106,22 +308,206 @@ fn parse_args(args: impl Iterator<Item = String>) -> Result<Args> {
         #[cfg(feature = "native")]
         tls_port,
         worker_secret: worker_secret.unwrap_or_else(|| String::from("wapo-testnet")),
+        http_client,
+        exit_on_unhandled_rejection,
+        import_map,
+        cjs_modules,
+        #[cfg(feature = "native")]
+        cache_dir,
+        output_format,
+        source_maps,
+    })
+}
+
+fn compile_source(source: &str, name: &str) -> Result<Vec<u8>> {
+    js::compile(source, name).map_err(|err| anyhow!("failed to compile {name}: {err:?}"))
+}
+
+/// The qjs-sys commit the engine was built from, which determines the bytecode format.
+#[cfg(feature = "native")]
+const ENGINE_REVISION: &str = env!("QJS_SYS_REVISION");
+
+/// Compiles `source`, reusing the bytecode cached in `cache_dir` by an earlier run.
+///
+/// Each cache file holds the SHA-256 of the bytecode followed by the bytecode, and files whose
+/// checksum doesn't match are discarded.
+#[cfg(feature = "native")]
+fn compile_cached(cache_dir: &std::path::Path, source: &str, name: &str) -> Result<Vec<u8>> {
+    use sha2::{Digest, Sha256};
+
+    const CHECKSUM_LEN: usize = 32;
+
+    if ENGINE_REVISION == "unknown" {
+        log::debug!(target: "js", "engine revision unknown, not caching bytecode");
+        return compile_source(source, name);
+    }
+    // The bytecode format is tied to the engine, hence the engine revision in the key.
+    let mut hasher = Sha256::new();
+    hasher.update(env!("CARGO_PKG_VERSION"));
+    hasher.update([0]);
+    hasher.update(ENGINE_REVISION);
+    hasher.update([0]);
+    hasher.update(name);
+    hasher.update([0]);
+    hasher.update(source);
+    let key = hex::encode(hasher.finalize());
+    let path = cache_dir.join(format!("{key}.jsc"));
+    if let Ok(cached) = std::fs::read(&path) {
+        if cached.len() >= CHECKSUM_LEN {
+            let (checksum, bytecode) = cached.split_at(CHECKSUM_LEN);
+            if Sha256::digest(bytecode).as_slice() == checksum {
+                log::debug!(target: "js", "using cached bytecode {}", path.display());
+                return Ok(bytecode.to_vec());
+            }
+        }
+        log::warn!(target: "js", "discarding corrupt cached bytecode {}", path.display());
+        std::fs::remove_file(&path).ok();
+    }
+    let bytecode = compile_source(source, name)?;
+    let mut cached = Sha256::digest(&bytecode).to_vec();
+    cached.extend_from_slice(&bytecode);
+    // Write to a temporary file first so that a concurrent run never reads a partial file.
+    let tmp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+    let stored = std::fs::create_dir_all(cache_dir)
+        .and_then(|_| std::fs::write(&tmp_path, &cached))
+        .and_then(|_| std::fs::rename(&tmp_path, &path));
+    if let Err(err) = stored {
+        log::warn!(target: "js", "failed to cache bytecode at {}: {err}", path.display());
+    }
+    Ok(bytecode)
+}
+
+/// `wapojs compile [-o <output>] <script..>`, writing the bytecode of each script next to it
+/// with the `.jsc` extension unless `-o` is given.
+fn compile_scripts(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut output = None;
+    let mut inputs = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().ok_or(anyhow!("missing path after -o"))?),
+            _ if arg.starts_with('-') => {
+                print_usage();
+                bail!("unknown option: {arg}");
+            }
+            _ => inputs.push(arg),
+        }
+    }
+    if inputs.is_empty() {
+        print_usage();
+        bail!("no script file provided");
+    }
+    if output.is_some() && inputs.len() > 1 {
+        bail!("-o can not be used with multiple scripts");
+    }
+    for input in inputs {
+        let source = std::fs::read_to_string(&input).context("failed to read script file")?;
+        let bytecode = compile_source(&source, &input)?;
+        let output = output.take().unwrap_or_else(|| {
+            let stem = input.strip_suffix(".js").unwrap_or(&input);
+            format!("{stem}.jsc")
+        });
+        std::fs::write(&output, bytecode).context("failed to write bytecode file")?;
+        eprintln!("compiled {input} to {output}");
+    }
+    Ok(())
+}
+
+/// Collects the `.js`, `.cjs` and `.json` files under `dir`, keyed by their path relative to
+/// `root`. Symlinked directories are not followed, so a link cycle can't recurse forever.
+#[cfg(feature = "native")]
+fn collect_modules(
+    root: &std::path::Path,
+    dir: &std::path::Path,
+    modules: &mut Vec<(String, String)>,
+) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_modules(root, &path, modules)?;
+            continue;
+        }
+        if file_type.is_symlink() && path.is_dir() {
+            continue;
+        }
+        let is_module = matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("js" | "cjs" | "json")
+        );
+        if !is_module {
+            continue;
+        }
+        let Ok(relative) = path.strip_prefix(root) else {
+            continue;
+        };
+        let source = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        modules.push((format!("/{}", relative.to_string_lossy()), source));
+    }
+    Ok(())
+}
+
+fn absolute_path(path: String) -> String {
+    match std::fs::canonicalize(&path) {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(_) => path,
+    }
+}
+
+fn read_module(path: String) -> Result<Script> {
+    let source = std::fs::read_to_string(&path).context("failed to read module file")?;
+    Ok(Script::Module {
+        name: absolute_path(path),
+        source,
     })
 }
 
 fn print_usage() {
     println!("wapojs v{}", env!("CARGO_PKG_VERSION"));
     println!("Usage: wapojs [options] --worker-secret <secret> [script..] [-- [args]]");
+    println!("       wapojs compile [-o <output.jsc>] <script..>");
     println!("");
     println!("Options:");
     println!("  -c <code>        Execute code");
+    println!("  -b, --bytecode <path>  Execute a script compiled by `wapojs compile`");
+    #[cfg(feature = "native")]
+    println!(
+        "  --cache-dir <dir>  Cache compiled scripts in dir (default: $WAPOJS_CACHE_DIR, disabled if unset)"
+    );
+    println!("  -m, --module <path>  Execute an ES module, implied for .mjs files");
+    println!(
+        "  --source-map <path>  Source map of the preceding script, overriding its sourceMappingURL"
+    );
+    println!("  --import-map <path>  Import map resolving the bare specifiers of ES modules");
     #[cfg(feature = "wapo")]
     println!("  --code-hash <code_hash>  Execute code");
+    #[cfg(feature = "wapo")]
+    println!("  --module-hash <code_hash>  Execute an ES module");
     #[cfg(feature = "native")]
     println!("  --tls-port <port>  TLS listen port (default: 443)");
     #[cfg(feature = "native")]
     println!("  -e <path>        dotenv file provides additional env variables");
+    #[cfg(feature = "native")]
+    println!("  --require-root <dir>  Make the modules under dir available to require()");
+    #[cfg(feature = "wapo")]
+    println!(
+        "  --require-bundle-hash <code_hash>  Make the modules of a JSON path->source bundle available to require()"
+    );
     println!("  --worker-secret <secret>    Worker secret");
+    println!(
+        "  --http-pool-idle-timeout <ms>  Idle timeout of pooled HTTP connections (default: 90000)"
+    );
+    println!("  --http-pool-max-idle <n>   Max idle HTTP connections per host (default: 32)");
+    println!(
+        "  --exit-on-unhandled-rejection  Exit on a promise rejection that no listener handles"
+    );
+    println!(
+        "  --output <none|json|scale>  Emit the result with an error envelope (default: none)"
+    );
+    println!(
+        "  --output-scale-types <types>  SCALE type registry of --output scale, output is type 0"
+    );
     println!("  --               Stop processing options");
 }
 
@@ -131,39 +517,120 @@ pub async fn run(args: impl Iterator<Item = String>) -> Result<JsValue> {
     #[cfg(feature = "env-nodejs")]
     const DEFAULT_BOOTCODE: &[u8] = bootcode::BOOT_CODE_NODEJS;
 
+    let args: Vec<String> = args.collect();
+    if args.get(1).map(String::as_str) == Some("compile") {
+        compile_scripts(args.into_iter().skip(2))?;
+        return Ok(JsValue::Undefined);
+    }
+    let parsed_args = parse_args(args.into_iter())?;
+
     #[cfg(feature = "external-bootcode")]
     let bootcode: Cow<'_, [u8]> = if let Ok(bootcode_path) = std::env::var("WAPOJS_BOOTCODE") {
         let source = std::fs::read_to_string(bootcode_path).expect("failed to read bootcode");
-        let code = js::compile(&source, "<bootcode>").expect("failed to compile bootcode");
+        #[cfg(feature = "native")]
+        let code = match &parsed_args.cache_dir {
+            Some(cache_dir) => compile_cached(cache_dir, &source, "<bootcode>"),
+            None => compile_source(&source, "<bootcode>"),
+        };
+        #[cfg(not(feature = "native"))]
+        let code = compile_source(&source, "<bootcode>");
 
-        Cow::Owned(code)
+        Cow::Owned(code.expect("failed to compile bootcode"))
     } else {
         Cow::Borrowed(DEFAULT_BOOTCODE)
     };
     #[cfg(not(feature = "external-bootcode"))]
     let bootcode = Cow::Borrowed(DEFAULT_BOOTCODE);
 
-    let parsed_args = parse_args(args)?;
-
     let config = ServiceConfig {
         is_sandbox: false,
         engine_config: Default::default(),
         worker_secret: parsed_args.worker_secret.clone(),
+        http_client: parsed_args.http_client.clone(),
+        exit_on_unhandled_rejection: parsed_args.exit_on_unhandled_rejection,
+        import_map: parsed_args.import_map.clone(),
     };
 
     let service = Service::new_ref(config);
     service.boot(Some(&bootcode))?;
 
+    let output_format = parsed_args.output_format.clone();
     let rv = run_with_service(service.clone(), parsed_args).await;
+    if let Some(output) = encode_output(&service, &output_format, &rv) {
+        crate::runtime::set_output(output);
+    }
+    let rv = rv.and_then(|output| convert(output).context("failed to convert output"));
     service.shutdown().await;
     rv
 }
 
-async fn run_with_service(service: ServiceRef, args: Args) -> Result<JsValue> {
+/// Splits an error formatted with `[stack]` by the service into its message and stack.
+fn split_error(err: &anyhow::Error) -> (String, String) {
+    let err = format!("{err:#}");
+    match err.split_once("\n[stack]\n") {
+        Some((message, stack)) => (message.to_string(), stack.to_string()),
+        None => (err, String::new()),
+    }
+}
+
+/// Encodes the result of the script with `Wapo.encodeOutput` of the bootcode.
+fn encode_output(
+    service: &ServiceRef,
+    format: &OutputFormat,
+    result: &Result<js::Value>,
+) -> Option<Vec<u8>> {
+    let (format, scale_types) = match format {
+        OutputFormat::None => return None,
+        OutputFormat::Json => ("json", ""),
+        OutputFormat::Scale(types) => ("scale", types.as_str()),
+    };
+    let encode = |output: js::Value, error: Option<(String, String)>| -> Result<Vec<u8>> {
+        let encode_fn = service
+            .context()
+            .get_global_object()
+            .get_property("Wapo")?
+            .get_property("encodeOutput")?;
+        // A throw is part of the result rather than an uncaught exception to report.
+        let encoded = service
+            .call_raw(&encode_fn, (format, scale_types, output, error))?
+            .map_err(|exception| anyhow::anyhow!("{}", service.format_error(&exception)))?;
+        if encoded.is_uint8_array() {
+            return Ok(encoded.decode_bytes()?);
+        }
+        let mut text = encoded.decode_string()?;
+        // A JSON envelope is a line of its own, for line based readers of the output.
+        if format == "json" {
+            text.push('\n');
+        }
+        Ok(text.into_bytes())
+    };
+    let encoded = match result {
+        Ok(output) => encode(output.clone(), None),
+        Err(err) => encode(js::Value::Undefined, Some(split_error(err))),
+    };
+    // The output may not be serializable, e.g. a cyclic object or a mismatched SCALE type.
+    let encoded = encoded.or_else(|err| encode(js::Value::Undefined, Some(split_error(&err))));
+    match encoded {
+        Ok(encoded) => Some(encoded),
+        Err(err) => {
+            log::error!(target: "js", "failed to encode the output: {err:#}");
+            None
+        }
+    }
+}
+
+async fn run_with_service(service: ServiceRef, args: Args) -> Result<js::Value> {
     #[cfg(feature = "native")]
     {
         crate::runtime::set_sni_tls_port(args.tls_port);
     }
+    for (path, source) in args.cjs_modules {
+        service.register_module(&path, source);
+    }
+    for (name, json) in args.source_maps {
+        service.add_source_map(&name, &json)?;
+    }
+    let exit_on_unhandled_rejection = args.exit_on_unhandled_rejection;
     let js_ctx = service.context();
     let js_args = args
         .js_args
@@ -175,10 +642,23 @@ async fn run_with_service(service: ServiceRef, args: Args) -> Result<JsValue> {
         .context("failed to set scriptArgs")?;
 
     let mut expr_val = None;
+    // The evaluation promises of the modules, checked once the tasks are done.
+    let mut modules = vec![];
     for code in args.codes.into_iter() {
         let result = match code {
-            JsCode::Source(src) => service.exec_script(&src),
-            JsCode::Bytecode(bytes) => service.exec_bytecode(&bytes),
+            Script::Classic(JsCode::Source(src)) => service.exec_script(&src),
+            Script::Classic(JsCode::Bytecode(bytes)) => service.exec_bytecode(&bytes),
+            Script::File { path, source } => service.exec_script_file(&source, &path),
+            // The completion value of a module is the promise of its evaluation, which is not
+            // meaningful as output.
+            Script::Module { name, source } => {
+                service.exec_module(&source, &name).and_then(|promise| {
+                    // A later rejection fails the run instead of being reported as unhandled.
+                    service.mark_handled(&promise)?;
+                    modules.push((name, promise));
+                    Ok(js::Value::Undefined)
+                })
+            }
         };
         match result {
             Ok(value) => expr_val = Some(value),
@@ -217,6 +697,16 @@ async fn run_with_service(service: ServiceRef, args: Args) -> Result<JsValue> {
     {
         service.wait_for_tasks().await;
     }
+    for (name, promise) in modules {
+        if let Err(err) = service.module_result(&promise) {
+            bail!("failed to execute module {name}: {err}");
+        }
+    }
+    if exit_on_unhandled_rejection {
+        if let Some(err) = service.unhandled_rejection()? {
+            bail!("unhandled promise rejection: {err}");
+        }
+    }
     // If scriptOutput is set, use it as output. Otherwise, use the last expression value.
     let output = js_ctx
         .get_global_object()
@@ -227,7 +717,7 @@ async fn run_with_service(service: ServiceRef, args: Args) -> Result<JsValue> {
     } else {
         output
     };
-    convert(output).context("failed to convert output")
+    Ok(output)
 }
 
 fn convert(output: js::Value) -> Result<JsValue> {
@@ -1,15 +1,26 @@
+use std::collections::{hash_map::Entry, HashMap};
+use std::future::Future;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicU16, Ordering};
-use std::sync::{Arc, OnceLock};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::Poll;
 use std::time::Duration;
 
-use anyhow::{anyhow, Context, Result};
-use hyper::client::HttpConnector;
-use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
-use sni_tls_listener::{Agent, Generate as _, SniTlsListener, Subscription};
+use anyhow::{anyhow, bail, Context, Result};
+use hyper::client::connect::{Connected, Connection};
 use tokio::io::DuplexStream;
 pub use tokio::net::TcpListener;
-use tokio_rustls::rustls::ClientConfig;
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::{
+    self,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    client::WebPkiServerVerifier,
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    server::Acceptor,
+    ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+};
+use tokio_rustls::{server::TlsStream as ServerTlsStream, LazyConfigAcceptor};
 pub use wapo::env::messages::{HttpHead, HttpResponseHead};
 
 static WAPO_SNI_TLS_PORT: AtomicU16 = AtomicU16::new(443);
@@ -18,37 +29,162 @@ pub fn set_sni_tls_port(port: u16) {
     WAPO_SNI_TLS_PORT.store(port, Ordering::Relaxed);
 }
 
-pub struct TlsListener(Subscription);
+/// The protocols offered to clients via ALPN, HTTP/2 first.
+const SERVER_ALPN_PROTOCOLS: [&[u8]; 2] = [b"h2", b"http/1.1"];
+/// Connections that haven't completed the TLS handshake by then are dropped.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Handshaked connections waiting for each listener to accept them. Connections beyond that
+/// are dropped.
+const TLS_ACCEPT_BACKLOG: usize = 128;
 
-fn global_sni_listener() -> Result<&'static Agent> {
-    static GLOBAL_SNI_LISTENER: OnceLock<Result<(SniTlsListener, Agent)>> = OnceLock::new();
+type TlsConnection = (ServerTlsStream<tokio::net::TcpStream>, SocketAddr);
+
+/// A TLS listener for one server name, sharing the TLS port with the other listeners.
+pub struct TlsListener {
+    sni: String,
+    id: u64,
+    rx: mpsc::Receiver<TlsConnection>,
+}
+
+struct SniRoute {
+    id: u64,
+    config: Arc<ServerConfig>,
+    tx: mpsc::Sender<TlsConnection>,
+}
+
+/// Accepts the connections on the TLS port and completes their handshakes with the config of
+/// the listener subscribed to the server name sent by the client.
+#[derive(Default)]
+struct SniRouter {
+    routes: Mutex<HashMap<String, SniRoute>>,
+}
+
+impl SniRouter {
+    async fn handshake(&self, tcp: tokio::net::TcpStream, remote_addr: SocketAddr) -> Result<()> {
+        let start = LazyConfigAcceptor::new(Acceptor::default(), tcp).await?;
+        let sni = start
+            .client_hello()
+            .server_name()
+            .context("client sent no server name")?
+            .to_ascii_lowercase();
+        let (config, tx) = {
+            let routes = self.routes.lock().unwrap();
+            let route = routes
+                .get(&sni)
+                .with_context(|| format!("no listener for {sni}"))?;
+            (route.config.clone(), route.tx.clone())
+        };
+        let stream = start.into_stream(config).await?;
+        tx.try_send((stream, remote_addr)).map_err(|err| match err {
+            mpsc::error::TrySendError::Full(_) => anyhow!("listener for {sni} is not accepting"),
+            mpsc::error::TrySendError::Closed(_) => anyhow!("listener for {sni} has been closed"),
+        })
+    }
+}
+
+async fn route_connections(listener: TcpListener, router: &'static SniRouter) {
+    loop {
+        let (tcp, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log::warn!(target: "wapo::tls", "failed to accept tls connection: {err}");
+                time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        spawn(async move {
+            let result = time::timeout(TLS_HANDSHAKE_TIMEOUT, router.handshake(tcp, remote_addr))
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("tls handshake timed out")));
+            if let Err(err) = result {
+                log::debug!(target: "wapo::tls", "dropped tls connection from {remote_addr}: {err:#}");
+            }
+        });
+    }
+}
+
+/// Makes ring the process-wide rustls crypto provider, since both ring and aws-lc-rs are
+/// compiled in and rustls can't pick one on its own.
+fn install_crypto_provider() {
+    static INSTALLED: OnceLock<()> = OnceLock::new();
+    INSTALLED.get_or_init(|| {
+        // Fails only if a provider has already been installed.
+        let _ = rustls::crypto::ring::default_provider().install_default();
+    });
+}
+
+fn global_sni_listener() -> Result<&'static SniRouter> {
+    static GLOBAL_SNI_LISTENER: OnceLock<Result<&'static SniRouter, String>> = OnceLock::new();
     let sni_tls_port = WAPO_SNI_TLS_PORT.load(Ordering::Relaxed);
-    let listener = GLOBAL_SNI_LISTENER.get_or_init(|| {
-        SniTlsListener::install_ring_provider();
-        let listener =
-            futures::executor::block_on(SniTlsListener::bind("0.0.0.0", sni_tls_port, false))?;
-        let agent = listener.agent(|| {}, true, Duration::from_micros(0));
-        Ok((listener, agent))
+    let router = GLOBAL_SNI_LISTENER.get_or_init(|| {
+        install_crypto_provider();
+        let listener = std::net::TcpListener::bind(("0.0.0.0", sni_tls_port))
+            .and_then(|listener| {
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)
+            })
+            .map_err(|err| err.to_string())?;
+        // Like the port, the router lives as long as the process.
+        let router: &'static SniRouter = Box::leak(Box::default());
+        spawn(route_connections(listener, router));
+        Ok(router)
     });
-    match listener {
-        Ok((_listener, agent)) => Ok(agent),
+    match router {
+        Ok(router) => Ok(router),
         Err(err) => Err(anyhow!("failed to bind SNI listener: {err}")),
     }
 }
 
 pub fn sni_listen(sni: &str, cert: &str, key: &str) -> Result<TlsListener> {
-    let listener = global_sni_listener()?;
-    let key = sni_tls_listener::wrap_certified_key(cert.as_bytes(), key.as_bytes())
+    let router = global_sni_listener()?;
+    let certs = rustls_pemfile::certs(&mut cert.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .context("invalid cert or key")?;
+    let key = rustls_pemfile::private_key(&mut key.as_bytes())
+        .context("invalid cert or key")?
+        .context("no private key found")?;
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
         .context("invalid cert or key")?;
+    config.alpn_protocols = SERVER_ALPN_PROTOCOLS.iter().map(|p| p.to_vec()).collect();
+
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let sni = sni.to_ascii_lowercase();
+    let (tx, rx) = mpsc::channel(TLS_ACCEPT_BACKLOG);
+    let route = SniRoute {
+        id,
+        config: Arc::new(config),
+        tx,
+    };
+    match router.routes.lock().unwrap().entry(sni.clone()) {
+        Entry::Occupied(_) => bail!("{sni} is already bound by another listener"),
+        Entry::Vacant(entry) => entry.insert(route),
+    };
     log::info!(target: "wapo::tls", "SNI listener is listening on {sni}");
-    let subscription = listener.subscribe(sni, key)?;
-    Ok(TlsListener(subscription))
+    Ok(TlsListener { sni, id, rx })
 }
 
 impl TlsListener {
     pub async fn accept(&mut self) -> Result<(TcpStream, SocketAddr)> {
-        let conn = self.0.next().await.ok_or(anyhow!("listener closed"))?;
-        Ok((TcpStream::ServerTlsSteam(conn.stream), conn.remote_addr))
+        let (stream, remote_addr) = self.rx.recv().await.ok_or(anyhow!("listener closed"))?;
+        Ok((TcpStream::ServerTlsSteam(stream), remote_addr))
+    }
+}
+
+impl Drop for TlsListener {
+    fn drop(&mut self) {
+        let Ok(router) = global_sni_listener() else {
+            return;
+        };
+        let mut routes = router.routes.lock().unwrap();
+        if routes
+            .get(&self.sni)
+            .is_some_and(|route| route.id == self.id)
+        {
+            routes.remove(&self.sni);
+        }
     }
 }
 
@@ -60,21 +196,179 @@ pub async fn tcp_accept(listener: &TcpListener) -> Result<(TcpStream, SocketAddr
     Ok((TcpStream::TcpStream(tcp), addr))
 }
 
+/// TLS session details of an accepted connection.
+#[derive(Clone, Debug, Default)]
+pub struct ServerTlsInfo {
+    pub server_name: Option<String>,
+    pub protocol_version: Option<String>,
+    pub alpn_protocol: Option<String>,
+}
+
+pub fn server_tls_info(stream: &TcpStream) -> Option<ServerTlsInfo> {
+    let TcpStream::ServerTlsSteam(stream) = stream else {
+        return None;
+    };
+    let conn = stream.get_ref().1;
+    Some(ServerTlsInfo {
+        server_name: conn.server_name().map(Into::into),
+        protocol_version: conn.protocol_version().map(|version| match version {
+            rustls::ProtocolVersion::TLSv1_2 => "TLSv1.2".into(),
+            rustls::ProtocolVersion::TLSv1_3 => "TLSv1.3".into(),
+            version => format!("{version:?}"),
+        }),
+        alpn_protocol: conn
+            .alpn_protocol()
+            .map(|proto| String::from_utf8_lossy(proto).into_owned()),
+    })
+}
+
+fn default_root_store() -> RootCertStore {
+    RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned())
+}
+
 fn default_client_config() -> Arc<ClientConfig> {
     static CLIENT_CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
     CLIENT_CONFIG
         .get_or_init(|| {
-            let root_store = tokio_rustls::rustls::RootCertStore::from_iter(
-                webpki_roots::TLS_SERVER_ROOTS.iter().cloned(),
-            );
+            install_crypto_provider();
             let config = ClientConfig::builder()
-                .with_root_certificates(root_store)
+                .with_root_certificates(default_root_store())
                 .with_no_client_auth();
             Arc::new(config)
         })
         .clone()
 }
 
+/// A TLS client configuration with user supplied trust roots, client identity and pins.
+#[derive(Clone)]
+pub struct TlsClientConfig(Arc<ClientConfig>);
+
+impl TlsClientConfig {
+    /// Build a client config.
+    ///
+    /// `ca_certs` are PEM encoded roots trusted in addition to the built-in ones, `identity` is
+    /// a PEM encoded (certificate chain, private key) pair for mutual TLS, and if
+    /// `pinned_spki_sha256` is not empty the server chain must contain a certificate whose
+    /// SubjectPublicKeyInfo hashes to one of the pins.
+    pub fn new(
+        ca_certs: &[String],
+        identity: Option<(&str, &str)>,
+        pinned_spki_sha256: &[[u8; 32]],
+    ) -> Result<Self> {
+        install_crypto_provider();
+        let mut root_store = default_root_store();
+        for pem in ca_certs {
+            let certs = rustls_pemfile::certs(&mut pem.as_bytes())
+                .collect::<Result<Vec<_>, _>>()
+                .context("invalid CA certificate")?;
+            if certs.is_empty() {
+                bail!("no certificate found in a CA certificate PEM");
+            }
+            for cert in certs {
+                root_store.add(cert).context("invalid CA certificate")?;
+            }
+        }
+        let builder = if pinned_spki_sha256.is_empty() {
+            ClientConfig::builder().with_root_certificates(root_store)
+        } else {
+            let inner = WebPkiServerVerifier::builder(Arc::new(root_store))
+                .build()
+                .context("failed to create certificate verifier")?;
+            ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier {
+                    inner,
+                    pins: pinned_spki_sha256.to_vec(),
+                }))
+        };
+        let config = match identity {
+            Some((cert, key)) => {
+                let certs = rustls_pemfile::certs(&mut cert.as_bytes())
+                    .collect::<Result<Vec<_>, _>>()
+                    .context("invalid client certificate")?;
+                if certs.is_empty() {
+                    bail!("no certificate found in the client certificate PEM");
+                }
+                let key = rustls_pemfile::private_key(&mut key.as_bytes())
+                    .context("invalid client key")?
+                    .context("no private key found")?;
+                builder
+                    .with_client_auth_cert(certs, key)
+                    .context("invalid client certificate or key")?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(Self(Arc::new(config)))
+    }
+}
+
+/// Verifies the certificate chain with webpki, then requires one of the certificates in the
+/// chain to match a pinned public key.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+fn spki_sha256(cert: &CertificateDer<'_>) -> Result<[u8; 32]> {
+    use sha2::Digest;
+    use x509_cert::der::{Decode, Encode};
+    let cert = x509_cert::Certificate::from_der(cert.as_ref())?;
+    let spki = cert.tbs_certificate.subject_public_key_info.to_der()?;
+    Ok(sha2::Sha256::digest(spki).into())
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        let pinned = std::iter::once(end_entity)
+            .chain(intermediates)
+            .filter_map(|cert| spki_sha256(cert).ok())
+            .any(|hash| self.pins.contains(&hash));
+        if !pinned {
+            return Err(rustls::Error::General(
+                "server certificate does not match any pinned public key".into(),
+            ));
+        }
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
 pub enum TcpStream {
     TcpStream(tokio::net::TcpStream),
     ClientTlsSteam(tokio_rustls::client::TlsStream<tokio::net::TcpStream>),
@@ -83,9 +377,17 @@ pub enum TcpStream {
 
 impl TcpStream {
     pub async fn connect(host: &str, port: u16, enable_tls: bool) -> Result<TcpStream> {
+        Self::connect_with_config(host, port, enable_tls.then(default_client_config)).await
+    }
+
+    async fn connect_with_config(
+        host: &str,
+        port: u16,
+        tls_config: Option<Arc<ClientConfig>>,
+    ) -> Result<TcpStream> {
         let stream = tokio::net::TcpStream::connect((host, port)).await?;
-        if enable_tls {
-            let connector = tokio_rustls::TlsConnector::from(default_client_config());
+        if let Some(tls_config) = tls_config {
+            let connector = tokio_rustls::TlsConnector::from(tls_config);
             let server_name = host.to_string().try_into().context("invalid server name")?;
             let stream = connector.connect(server_name, stream).await?;
             Ok(TcpStream::ClientTlsSteam(stream))
@@ -95,6 +397,11 @@ impl TcpStream {
     }
 }
 
+/// Connect to `host:port` over TLS with a custom client config.
+pub async fn tls_connect(host: &str, port: u16, config: &TlsClientConfig) -> Result<TcpStream> {
+    TcpStream::connect_with_config(host, port, Some(config.0.clone())).await
+}
+
 impl tokio::io::AsyncRead for TcpStream {
     fn poll_read(
         self: std::pin::Pin<&mut Self>,
@@ -181,13 +488,73 @@ pub struct HttpRequest {
 }
 
 pub use tokio::main;
-pub use tokio::{task::spawn_local as spawn, time};
-pub fn http_connector() -> HttpsConnector<HttpConnector> {
-    HttpsConnectorBuilder::new()
-        .with_webpki_roots()
-        .https_or_http()
-        .enable_http1()
-        .build()
+pub use tokio::{task::spawn_local as spawn, task::yield_now, time};
+
+/// The hyper connector used by the HTTP client, dialing with [`TcpStream`] and negotiating
+/// HTTP/2 via ALPN on TLS connections.
+#[derive(Clone)]
+pub struct HttpClientConnector {
+    tls_config: Arc<ClientConfig>,
+}
+
+impl HttpClientConnector {
+    fn new(tls_config: &ClientConfig) -> Self {
+        let mut tls_config = tls_config.clone();
+        tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Self {
+            tls_config: Arc::new(tls_config),
+        }
+    }
+}
+
+impl hyper::service::Service<hyper::Uri> for HttpClientConnector {
+    type Response = TcpStream;
+    type Error = anyhow::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<TcpStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: hyper::Uri) -> Self::Future {
+        let tls_config = self.tls_config.clone();
+        Box::pin(async move {
+            let use_tls = match uri.scheme_str() {
+                Some("https") => true,
+                Some("http") | None => false,
+                Some(scheme) => bail!("unsupported scheme: {scheme}"),
+            };
+            let host = uri.host().context("missing host")?;
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            let port = uri.port_u16().unwrap_or(if use_tls { 443 } else { 80 });
+            TcpStream::connect_with_config(host, port, use_tls.then_some(tls_config)).await
+        })
+    }
+}
+
+impl Connection for TcpStream {
+    fn connected(&self) -> Connected {
+        let connected = Connected::new();
+        match self {
+            TcpStream::ClientTlsSteam(stream)
+                if stream.get_ref().1.alpn_protocol() == Some(b"h2") =>
+            {
+                connected.negotiated_h2()
+            }
+            _ => connected,
+        }
+    }
+}
+
+pub fn http_connector() -> HttpClientConnector {
+    static CONNECTOR: OnceLock<HttpClientConnector> = OnceLock::new();
+    CONNECTOR
+        .get_or_init(|| HttpClientConnector::new(&default_client_config()))
+        .clone()
+}
+
+pub fn http_connector_with_tls(config: &TlsClientConfig) -> HttpClientConnector {
+    HttpClientConnector::new(&config.0)
 }
 pub fn getrandom(buf: &mut [u8]) -> Option<()> {
     use rand::RngCore;
@@ -205,8 +572,34 @@ pub async fn run_local<F: core::future::Future>(fut: F) -> F::Output {
     let local = tokio::task::LocalSet::new();
     local.run_until(fut).await
 }
-pub use tracing_subscriber::fmt::init as init_logger;
-pub fn set_output(_output: Vec<u8>) {}
+/// Logs to stderr, filtered by `RUST_LOG`, so that stdout only carries the program output.
+pub fn init_logger() {
+    use std::str::FromStr;
+    use tracing_subscriber::{
+        filter::{LevelFilter, Targets},
+        layer::SubscriberExt,
+        util::SubscriberInitExt,
+    };
+
+    let targets = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|filter| Targets::from_str(&filter).ok())
+        .unwrap_or_else(|| Targets::new().with_default(LevelFilter::INFO));
+    tracing_subscriber::fmt()
+        .with_max_level(LevelFilter::TRACE)
+        .with_writer(std::io::stderr)
+        .finish()
+        .with(targets)
+        .init();
+}
+/// Writes the program output to stdout, where the wapo runtime hands it to the host instead.
+pub fn set_output(output: Vec<u8>) {
+    use std::io::Write;
+    let mut stdout = std::io::stdout().lock();
+    if let Err(err) = stdout.write_all(&output).and_then(|_| stdout.flush()) {
+        log::error!("failed to write program output: {err}");
+    }
+}
 
 pub mod ocall {
     use anyhow::Result;
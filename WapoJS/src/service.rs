@@ -3,19 +3,32 @@ use alloc::{
     collections::BTreeMap,
     rc::{Rc, Weak},
 };
-use core::{any::Any, cell::RefCell, ops::Deref, time::Duration};
+use core::{
+    any::Any,
+    cell::{Cell, OnceCell, RefCell},
+    ops::Deref,
+    time::Duration,
+};
 use log::{debug, error};
-use std::ffi::{c_int, c_void};
+use std::ffi::{c_int, c_void, CString};
 use std::{future::Future, sync::Mutex};
 
-use crate::{host_functions::setup_host_functions, runtime};
+use crate::host_functions::{
+    drive_timers, new_http_client, setup_host_functions, HttpClient, ModuleRegistry, TimerQueue,
+};
+use crate::runtime;
 use anyhow::{Context, Result};
-use js::{c, Code, EngineConfig, Error as ValueError, ToArgs};
+use js::{c, Code, EngineConfig, Error as ValueError, FromJsValue, ToArgs};
 use tokio::sync::{broadcast, oneshot};
 
+mod module_loader;
 mod resource;
+mod source_map;
 
+pub(crate) use module_loader::join_path;
+pub use module_loader::ImportMap;
 pub(crate) use resource::{OwnedJsValue, Resource};
+pub use source_map::{find_source_map, SourceMap};
 
 #[derive(Clone)]
 pub struct ServiceRef(Rc<Service>);
@@ -107,6 +120,30 @@ pub struct ServiceConfig {
     pub engine_config: EngineConfig,
     pub is_sandbox: bool,
     pub worker_secret: String,
+    pub http_client: HttpClientConfig,
+    /// Close all resources, ending the script, on the first promise rejection that no
+    /// `unhandledRejection` listener handles.
+    pub exit_on_unhandled_rejection: bool,
+    /// Resolves the bare specifiers of ES module imports.
+    pub import_map: ImportMap,
+}
+
+/// Settings of the HTTP client shared by all `Wapo.httpRequest` calls of a service.
+#[derive(Clone, Debug)]
+pub struct HttpClientConfig {
+    /// How long an idle connection is kept in the pool before being closed.
+    pub pool_idle_timeout: Duration,
+    /// The maximum number of idle connections kept in the pool for each host.
+    pub pool_max_idle_per_host: usize,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        Self {
+            pool_idle_timeout: Duration::from_secs(90),
+            pool_max_idle_per_host: 32,
+        }
+    }
 }
 
 pub struct Service {
@@ -114,6 +151,25 @@ pub struct Service {
     state: RefCell<ServiceState>,
     config: ServiceConfig,
     unhandled_rejection_str: RefCell<Option<String>>,
+    /// The shared HTTP clients, one per connect timeout used by the requests.
+    http_clients: RefCell<BTreeMap<Option<Duration>, HttpClient>>,
+    timers: OnceCell<Rc<TimerQueue>>,
+    /// Set by the bootcode to dispatch error events to the JS listeners.
+    error_handler: RefCell<Option<OwnedJsValue>>,
+    rejections: RefCell<Vec<RejectionEvent>>,
+    /// Set while the rejections are reported, so that the events raised by the listeners are
+    /// queued for the next flush instead of being flushed recursively.
+    flushing_rejections: Cell<bool>,
+    modules: RefCell<ModuleRegistry>,
+    /// Source maps keyed by the name of the script they map.
+    source_maps: RefCell<BTreeMap<String, SourceMap>>,
+}
+
+/// A call of the promise rejection tracker, waiting to be reported.
+struct RejectionEvent {
+    promise: OwnedJsValue,
+    reason: OwnedJsValue,
+    is_handled: bool,
 }
 
 struct ServiceState {
@@ -156,9 +212,9 @@ impl Service {
 
         extern "C" fn promise_rejection_tracker(
             ctx: *mut c::JSContext,
-            _promise: c::JSValue,
+            promise: c::JSValue,
             reason: c::JSValue,
-            _is_handled: c_int,
+            is_handled: c_int,
             _opaque: *mut c_void,
         ) {
             let Some(ctx) = js::Context::clone_from_ptr(ctx) else {
@@ -167,17 +223,13 @@ impl Service {
             let Ok(service) = ServiceRef::try_from(ctx) else {
                 return;
             };
-            let error = js::Value::new_cloned(&service.context(), reason);
-            let stack = error.get_property("stack").unwrap_or_default();
-            let exc_str = if stack.is_undefined() {
-                error.to_string()
-            } else {
-                format!("{error}\n[stack]\n{stack}")
-            };
-            service
-                .unhandled_rejection_str
-                .borrow_mut()
-                .replace(exc_str);
+            // A rejection may get a handler attached later in the same tick, so the events are
+            // only reported once the pending jobs have been drained.
+            service.rejections.borrow_mut().push(RejectionEvent {
+                promise: service.runtime.dup_value(promise),
+                reason: service.runtime.dup_value(reason),
+                is_handled: is_handled != 0,
+            });
         }
 
         unsafe {
@@ -185,7 +237,13 @@ impl Service {
                 runtime.as_ptr(),
                 Some(promise_rejection_tracker),
                 core::ptr::null_mut(),
-            )
+            );
+            c::JS_SetModuleLoaderFunc(
+                runtime.as_ptr(),
+                Some(module_loader::normalize),
+                Some(module_loader::load),
+                core::ptr::null_mut(),
+            );
         };
 
         if let Ok(v) = std::env::var("WAPO_RT_FLAGS") {
@@ -216,6 +274,13 @@ impl Service {
             state,
             config,
             unhandled_rejection_str: Default::default(),
+            http_clients: Default::default(),
+            timers: OnceCell::new(),
+            error_handler: Default::default(),
+            rejections: Default::default(),
+            flushing_rejections: Default::default(),
+            modules: Default::default(),
+            source_maps: Default::default(),
         }
     }
 
@@ -243,6 +308,74 @@ impl Service {
         self.config.worker_secret.clone()
     }
 
+    pub fn http_client_config(&self) -> &HttpClientConfig {
+        &self.config.http_client
+    }
+
+    /// The HTTP client shared by the requests of this service with the same connect timeout,
+    /// so that connections to the same host are kept alive and reused.
+    ///
+    /// Only a few distinct timeouts get a shared client, requests with yet another one get a
+    /// client of their own.
+    pub(crate) fn http_client(&self, connect_timeout: Option<Duration>) -> HttpClient {
+        const MAX_SHARED_CLIENTS: usize = 8;
+        let mut clients = self.http_clients.borrow_mut();
+        if let Some(client) = clients.get(&connect_timeout) {
+            return client.clone();
+        }
+        let client = new_http_client(&self.config.http_client, connect_timeout);
+        if connect_timeout.is_none() || clients.len() < MAX_SHARED_CLIENTS {
+            clients.insert(connect_timeout, client.clone());
+        }
+        client
+    }
+
+    /// The queue of all JS timers of this service, fired by a single driver task which is
+    /// started on first use.
+    pub(crate) fn timers(&self) -> Rc<TimerQueue> {
+        self.timers
+            .get_or_init(|| {
+                let queue = Rc::new(TimerQueue::default());
+                crate::runtime::spawn(drive_timers(self.weak_self(), queue.clone()));
+                queue
+            })
+            .clone()
+    }
+
+    /// The CommonJS modules that `require()` can load.
+    pub(crate) fn modules(&self) -> &RefCell<ModuleRegistry> {
+        &self.modules
+    }
+
+    /// Registers the source of a CommonJS module under the absolute virtual `path`.
+    pub fn register_module(&self, path: &str, source: String) {
+        self.modules.borrow_mut().register(path, source);
+    }
+
+    /// Maps the stack frames of the script or module `name` back to the original sources.
+    pub fn add_source_map(&self, name: &str, json: &str) -> Result<()> {
+        let map = SourceMap::parse(json).with_context(|| format!("source map of {name}"))?;
+        self.source_maps.borrow_mut().insert(name.to_string(), map);
+        Ok(())
+    }
+
+    /// Rewrites the locations of a stack trace to the original sources of the scripts that
+    /// have a source map.
+    pub fn rewrite_stack(&self, text: &str) -> String {
+        source_map::rewrite_stack(&self.source_maps.borrow(), text)
+    }
+
+    /// Formats a thrown value, including its stack if it has one.
+    pub(crate) fn format_error(&self, error: &js::Value) -> String {
+        let stack = error.get_property("stack").unwrap_or_default();
+        if stack.is_undefined() {
+            error.to_string()
+        } else {
+            let stack = self.rewrite_stack(&stack.to_string());
+            format!("{error}\n[stack]\n{stack}")
+        }
+    }
+
     pub(crate) fn weak_self(&self) -> ServiceWeakRef {
         unsafe {
             let ptr = c::JS_GetContextOpaque(self.context().as_ptr()) as *mut ServiceWeakRef;
@@ -280,13 +413,105 @@ impl Service {
         self.eval(Code::Bytecode(script))
     }
 
-    pub fn eval(&self, code: Code) -> Result<js::Value> {
-        let result = js::eval(self.context(), &code).map_err(|err| anyhow::anyhow!("{err}"));
+    /// Like `exec_script`, naming the script `filename` in its stack traces.
+    pub fn exec_script_file(&self, script: &str, filename: &str) -> Result<js::Value> {
+        let source = CString::new(script).context("script contains a nul byte")?;
+        let c_filename = CString::new(filename).context("script name contains a nul byte")?;
+        let ctx = self.context();
+        let ret = unsafe {
+            c::JS_Eval(
+                ctx.as_ptr(),
+                source.as_ptr(),
+                source.as_bytes().len(),
+                c_filename.as_ptr(),
+                c::JS_EVAL_TYPE_GLOBAL as c_int,
+            )
+        };
+        let result = if c::is_exception(ret) {
+            let exception = unsafe { c::JS_GetException(ctx.as_ptr()) };
+            let exception = js::Value::new_moved(ctx, exception);
+            Err(anyhow::anyhow!("{}", self.format_error(&exception)))
+        } else {
+            Ok(js::Value::new_moved(ctx, ret))
+        };
+        self.run_pending_jobs();
+        result
+    }
+
+    /// Evaluates `source` as an ES module named `name`, a file path or `hash:<code_hash>`
+    /// against which its relative imports are resolved.
+    ///
+    /// Returns the promise of the module evaluation, which is still pending if the module awaits
+    /// at top level.
+    pub fn exec_module(&self, source: &str, name: &str) -> Result<js::Value> {
+        let result = module_loader::eval(self.context(), source, name)
+            .map_err(|err| anyhow::anyhow!("{}", self.rewrite_stack(&format!("{err:#}"))));
+        // The rejections are flushed only after a rejected evaluation has been marked as
+        // handled, as the error is returned rather than reported as an unhandled rejection.
         self.runtime.exec_pending_jobs();
+        let result = result.and_then(|promise| {
+            if let Some(reason) = module_loader::rejection_reason(self.context(), &promise) {
+                self.mark_handled(&promise)?;
+                anyhow::bail!("{}", self.format_error(&reason));
+            }
+            Ok(promise)
+        });
+        self.run_pending_jobs();
+        result
+    }
+
+    /// Checks the evaluation promise returned by `exec_module` once the tasks are done, failing
+    /// with the rejection reason or if the module is still waiting on a top-level `await`.
+    pub fn module_result(&self, promise: &js::Value) -> Result<()> {
+        if let Some(reason) = module_loader::rejection_reason(self.context(), promise) {
+            anyhow::bail!("{}", self.format_error(&reason));
+        }
+        if module_loader::is_pending(self.context(), promise) {
+            anyhow::bail!("module evaluation never settled");
+        }
+        Ok(())
+    }
+
+    /// Attaches a no-op rejection handler to `promise`, so that its rejection is not reported
+    /// to the `unhandledRejection` listeners.
+    pub(crate) fn mark_handled(&self, promise: &js::Value) -> Result<()> {
+        let ctx = self.context();
+        let mark = ctx
+            .get_qjsbind_object("wapo.mark_handled", || {
+                ctx.eval(&Code::Source(
+                    r#"(function (promise) { promise.catch(function () {}); })"#,
+                ))
+                .map_err(js::Error::msg)
+            })
+            .context("failed to create the mark_handled helper")?;
+        mark.call(&js::Value::null(), &[promise.clone()])
+            .context("failed to mark the promise as handled")?;
+        Ok(())
+    }
+
+    pub fn eval(&self, code: Code) -> Result<js::Value> {
+        let result = js::eval(self.context(), &code)
+            .map_err(|err| anyhow::anyhow!("{}", self.rewrite_stack(&err.to_string())));
+        self.run_pending_jobs();
         result
     }
 
     pub fn call_function(&self, func: js::Value, args: impl ToArgs) -> Result<js::Value> {
+        let result = self.call_raw(&func, args)?.map_err(|exception| {
+            let err = self.format_error(&exception);
+            self.report_uncaught_exception(exception);
+            anyhow::anyhow!("failed to call function: {err}")
+        });
+        self.run_pending_jobs();
+        result
+    }
+
+    /// Calls `func`, returning the thrown value as the inner error if it throws.
+    pub(crate) fn call_raw(
+        &self,
+        func: &js::Value,
+        args: impl ToArgs,
+    ) -> Result<Result<js::Value, js::Value>> {
         let ctx = self.context();
         let mut args = args.to_raw_args(ctx)?;
         let func = *func.raw_value();
@@ -298,17 +523,116 @@ impl Service {
             c::JS_Call(ctx.as_ptr(), func, this, args_len, args)
         };
         if c::is_exception(ret) {
-            let err = self.context().get_exception_str();
-            anyhow::bail!("failed to call function: {err}");
+            let exception = unsafe { c::JS_GetException(ctx.as_ptr()) };
+            return Ok(Err(js::Value::new_moved(ctx, exception)));
         }
+        Ok(Ok(js::Value::new_moved(ctx, ret)))
+    }
+
+    fn run_pending_jobs(&self) {
         self.runtime.exec_pending_jobs();
-        Ok(js::Value::new_moved(self.context(), ret))
+        self.flush_rejections();
+    }
+
+    pub(crate) fn set_error_handler(&self, handler: OwnedJsValue) {
+        self.error_handler.borrow_mut().replace(handler);
+    }
+
+    /// Calls the error handler installed by the bootcode. Errors thrown by the handler itself
+    /// are logged rather than reported again.
+    fn call_error_handler(&self, args: impl ToArgs) -> Option<js::Value> {
+        let handler = self.error_handler.borrow().as_ref()?.to_js_value()?;
+        let result = self.call_raw(&handler, args);
+        self.run_pending_jobs();
+        match result {
+            Ok(Ok(value)) => Some(value),
+            Ok(Err(exception)) => {
+                let err = self.format_error(&exception);
+                error!(target: "js::rt", "error handler threw: {err}");
+                None
+            }
+            Err(err) => {
+                error!(target: "js::rt", "failed to call error handler: {err:?}");
+                None
+            }
+        }
+    }
+
+    /// Dispatches an exception thrown by a callback to the `uncaughtException` listeners.
+    fn report_uncaught_exception(&self, exception: js::Value) {
+        let handled = self
+            .call_error_handler(("uncaughtException", exception))
+            .and_then(|ret| bool::from_js_value(ret).ok())
+            .unwrap_or(false);
+        if handled {
+            debug!(target: "js::rt", "uncaught exception handled by a listener");
+        }
+    }
+
+    /// Reports the rejections tracked since the last call to the `unhandledRejection` and
+    /// `rejectionHandled` listeners.
+    ///
+    /// The JS side pairs each rejection with a later `is_handled` event of the same promise and
+    /// returns the reasons of those that are still unhandled and that no listener took care of.
+    /// Without an error handler, e.g. in an isolate booted without polyfills, every unhandled
+    /// event is counted since the promises can't be matched.
+    ///
+    /// Rejections raised while the listeners run are left for the next flush.
+    fn flush_rejections(&self) {
+        if self.flushing_rejections.get() {
+            return;
+        }
+        let events = core::mem::take(&mut *self.rejections.borrow_mut());
+        if events.is_empty() {
+            return;
+        }
+        self.flushing_rejections.set(true);
+        self.report_rejections(events);
+        self.flushing_rejections.set(false);
+    }
+
+    fn report_rejections(&self, events: Vec<RejectionEvent>) {
+        let fallback = || {
+            events
+                .iter()
+                .filter(|event| !event.is_handled)
+                .filter_map(|event| event.reason.to_js_value())
+                .collect::<Vec<_>>()
+        };
+        let unhandled = if self.error_handler.borrow().is_some() {
+            let args: Vec<_> = events
+                .iter()
+                .filter_map(|event| {
+                    let promise = event.promise.to_js_value()?;
+                    let reason = event.reason.to_js_value()?;
+                    Some((promise, reason, event.is_handled))
+                })
+                .collect();
+            self.call_error_handler(("rejections", args))
+                .and_then(|ret| Vec::<js::Value>::from_js_value(ret).ok())
+                .unwrap_or_else(fallback)
+        } else {
+            fallback()
+        };
+        for reason in unhandled {
+            let err = self.format_error(&reason);
+            error!(target: "js::rt", "unhandled promise rejection: {err}");
+            self.unhandled_rejection_str.borrow_mut().replace(err);
+            if self.config.exit_on_unhandled_rejection {
+                self.close_all();
+            }
+        }
     }
 
     pub fn push_resource(&self, resource: Resource) -> u64 {
+        self.push_resource_with(|_| resource)
+    }
+
+    /// Like `push_resource`, for resources that need to know their own id.
+    pub(crate) fn push_resource_with(&self, make: impl FnOnce(u64) -> Resource) -> u64 {
         let mut state = self.state.borrow_mut();
         let id = state.take_next_resource_id();
-        state.resources.insert(id, resource);
+        state.resources.insert(id, make(id));
         debug!(target: "js::rt", "created resource {id}");
         id
     }
@@ -430,6 +754,8 @@ impl Service {
     /// by the tasks are released before dropping the JS runtime.
     pub async fn shutdown(&self) {
         *self.state.borrow_mut() = Default::default();
+        self.error_handler.borrow_mut().take();
+        self.rejections.borrow_mut().clear();
         runtime::time::sleep(Duration::from_millis(2)).await;
     }
 
@@ -475,5 +801,8 @@ impl Drop for Service {
             let pname = c::JS_GetContextOpaque(self.context().as_ptr()) as *mut ServiceWeakRef;
             drop(Box::from_raw(pname));
         }
+        if let Some(timers) = self.timers.get() {
+            timers.shutdown();
+        }
     }
 }
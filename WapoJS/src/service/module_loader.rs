@@ -0,0 +1,290 @@
+//! ES module resolution and loading.
+//!
+//! Module names are canonical so that QuickJS, which caches the loaded modules by name, only
+//! evaluates each module once. A name is either a file path, or `hash:<code_hash>` for a blob
+//! stored by the wapo host.
+
+use std::ffi::{c_char, CStr, CString};
+
+use anyhow::{anyhow, bail};
+use serde::Deserialize;
+
+use super::*;
+
+const HASH_SCHEME: &str = "hash:";
+
+/// A subset of the [import maps](https://github.com/WICG/import-maps) standard, mapping bare
+/// specifiers (`"lodash"`) or prefixes of them (`"lodash/"`) to module names.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ImportMap {
+    #[serde(default)]
+    imports: BTreeMap<String, String>,
+    #[serde(default)]
+    scopes: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl ImportMap {
+    /// Parses an import map, resolving relative addresses against `base_dir`.
+    pub fn parse(json: &str, base_dir: &str) -> Result<Self> {
+        let map: ImportMap = serde_json::from_str(json).context("invalid import map")?;
+        let resolve_all = |imports: BTreeMap<String, String>| {
+            imports
+                .into_iter()
+                .map(|(key, target)| (key, resolve_relative(&target, base_dir)))
+                .collect()
+        };
+        Ok(Self {
+            imports: resolve_all(map.imports),
+            scopes: map
+                .scopes
+                .into_iter()
+                .map(|(scope, imports)| (resolve_relative(&scope, base_dir), resolve_all(imports)))
+                .collect(),
+        })
+    }
+
+    fn lookup(&self, specifier: &str, referrer: &str) -> Option<String> {
+        // Iterating backwards visits a nested scope before the scopes it is nested in.
+        for (scope, imports) in self.scopes.iter().rev() {
+            if referrer.starts_with(scope.as_str()) {
+                if let Some(target) = match_imports(imports, specifier) {
+                    return Some(target);
+                }
+            }
+        }
+        match_imports(&self.imports, specifier)
+    }
+}
+
+fn match_imports(imports: &BTreeMap<String, String>, specifier: &str) -> Option<String> {
+    if let Some(target) = imports.get(specifier) {
+        return Some(target.clone());
+    }
+    imports
+        .iter()
+        .filter(|(key, _)| key.ends_with('/') && specifier.starts_with(key.as_str()))
+        .max_by_key(|(key, _)| key.len())
+        .map(|(key, target)| format!("{target}{}", &specifier[key.len()..]))
+}
+
+fn is_relative(specifier: &str) -> bool {
+    specifier.starts_with("./") || specifier.starts_with("../")
+}
+
+fn resolve_relative(specifier: &str, base_dir: &str) -> String {
+    if is_relative(specifier) {
+        join_path(base_dir, specifier)
+    } else {
+        specifier.to_string()
+    }
+}
+
+fn parent_dir(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) => "/",
+        Some(pos) => &path[..pos],
+        None => ".",
+    }
+}
+
+/// Joins a relative path to a directory, folding the `.` and `..` segments.
+pub(crate) fn join_path(dir: &str, relative: &str) -> String {
+    let absolute = dir.starts_with('/');
+    let mut segments: Vec<&str> = dir
+        .split('/')
+        .filter(|s| !s.is_empty() && *s != ".")
+        .collect();
+    for segment in relative.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => match segments.last() {
+                Some(&last) if last != ".." => {
+                    segments.pop();
+                }
+                _ if absolute => {}
+                _ => segments.push(".."),
+            },
+            _ => segments.push(segment),
+        }
+    }
+    let joined = segments.join("/");
+    if absolute {
+        format!("/{joined}")
+    } else {
+        joined
+    }
+}
+
+/// Resolves the specifier of an `import` in the module named `referrer` to a module name.
+fn resolve(import_map: &ImportMap, specifier: &str, referrer: &str) -> Result<String> {
+    let relative = is_relative(specifier);
+    let specifier = if relative {
+        if referrer.starts_with(HASH_SCHEME) {
+            bail!("relative import {specifier:?} from {referrer} is not supported");
+        }
+        join_path(parent_dir(referrer), specifier)
+    } else {
+        specifier.to_string()
+    };
+    if let Some(target) = import_map.lookup(&specifier, referrer) {
+        return Ok(target);
+    }
+    if relative || specifier.starts_with('/') || specifier.starts_with(HASH_SCHEME) {
+        return Ok(specifier);
+    }
+    bail!("failed to resolve module {specifier:?}, bare specifiers must be in the import map")
+}
+
+fn load_source(service: &Service, name: &str) -> Result<String> {
+    if service.is_sandbox() {
+        bail!("loading modules is not allowed in sandbox");
+    }
+    if let Some(code_hash) = name.strip_prefix(HASH_SCHEME) {
+        return load_blob(code_hash);
+    }
+    std::fs::read_to_string(name).with_context(|| format!("failed to read module {name}"))
+}
+
+#[cfg(feature = "wapo")]
+fn load_blob(code_hash: &str) -> Result<String> {
+    let blob = wapo::ocall::blob_get(code_hash).context("failed to get module source")?;
+    String::from_utf8(blob).context("module source is not valid utf-8")
+}
+
+#[cfg(not(feature = "wapo"))]
+fn load_blob(code_hash: &str) -> Result<String> {
+    bail!("can not load module {HASH_SCHEME}{code_hash}, blobs are only available in wapo")
+}
+
+/// The pointer held by an object value, i.e. `JS_VALUE_GET_PTR`.
+#[cfg(target_pointer_width = "64")]
+fn value_ptr(value: c::JSValue) -> *mut c_void {
+    unsafe { value.u.ptr }
+}
+
+/// The pointer held by an object value, i.e. `JS_VALUE_GET_PTR`. 32-bit targets use NaN-boxing
+/// which stores the pointer in the low bits.
+#[cfg(not(target_pointer_width = "64"))]
+fn value_ptr(value: c::JSValue) -> *mut c_void {
+    value as usize as *mut c_void
+}
+
+fn throw_error(ctx: *mut c::JSContext, err: &anyhow::Error) {
+    let msg = CString::new(format!("{err:#}").replace('\0', " ")).unwrap_or_default();
+    unsafe { c::JS_ThrowReferenceError(ctx, c"%s".as_ptr(), msg.as_ptr()) };
+}
+
+fn service_of(ctx: *mut c::JSContext) -> Result<ServiceRef> {
+    let ctx = js::Context::clone_from_ptr(ctx).ok_or(anyhow!("invalid context"))?;
+    ServiceRef::try_from(ctx).map_err(|err| anyhow!("{err}"))
+}
+
+/// Compiles a module without evaluating it, setting `import.meta.url` to its name.
+fn compile(ctx: *mut c::JSContext, source: &str, name: &str) -> Result<c::JSValue> {
+    let source = CString::new(source).context("module source contains a nul byte")?;
+    let c_name = CString::new(name).context("module name contains a nul byte")?;
+    let flags = c::JS_EVAL_TYPE_MODULE | c::JS_EVAL_FLAG_COMPILE_ONLY;
+    let func = unsafe {
+        c::JS_Eval(
+            ctx,
+            source.as_ptr(),
+            source.as_bytes().len(),
+            c_name.as_ptr(),
+            flags as c_int,
+        )
+    };
+    if c::is_exception(func) {
+        bail!("failed to compile module {name}");
+    }
+    let module = value_ptr(func) as *mut c::JSModuleDef;
+    let meta = unsafe { c::JS_GetImportMeta(ctx, module) };
+    if let Some(context) = js::Context::clone_from_ptr(ctx) {
+        let meta = js::Value::new_moved(&context, meta);
+        meta.set_property("url", &context.new_string(name)).ok();
+    }
+    Ok(func)
+}
+
+pub(super) extern "C" fn normalize(
+    ctx: *mut c::JSContext,
+    base: *const c_char,
+    name: *const c_char,
+    _opaque: *mut c_void,
+) -> *mut c_char {
+    let base = unsafe { CStr::from_ptr(base) }.to_string_lossy();
+    let name = unsafe { CStr::from_ptr(name) }.to_string_lossy();
+    let resolved = service_of(ctx).and_then(|service| {
+        let resolved = resolve(&service.config.import_map, &name, &base)?;
+        debug!(target: "js::module", "resolved {name:?} from {base} to {resolved}");
+        Ok(CString::new(resolved)?)
+    });
+    match resolved {
+        Ok(resolved) => unsafe { c::js_strdup(ctx, resolved.as_ptr()) },
+        Err(err) => {
+            throw_error(ctx, &err);
+            core::ptr::null_mut()
+        }
+    }
+}
+
+pub(super) extern "C" fn load(
+    ctx: *mut c::JSContext,
+    name: *const c_char,
+    _opaque: *mut c_void,
+) -> *mut c::JSModuleDef {
+    let name = unsafe { CStr::from_ptr(name) }.to_string_lossy();
+    let loaded = service_of(ctx).and_then(|service| {
+        let source = load_source(&service, &name)?;
+        Ok((service, source))
+    });
+    let (service, source) = match loaded {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            throw_error(ctx, &err);
+            return core::ptr::null_mut();
+        }
+    };
+    debug!(target: "js::module", "loaded module {name}");
+    // A broken source map only costs the readability of stack traces.
+    let source_map = find_source_map(&name, &source)
+        .and_then(|json| json.map_or(Ok(()), |json| service.add_source_map(&name, &json)));
+    if let Err(err) = source_map {
+        log::warn!(target: "js::module", "ignored the source map of {name}: {err:#}");
+    }
+    // On failure the compile error is left pending in the context.
+    let Ok(func) = compile(ctx, &source, &name) else {
+        return core::ptr::null_mut();
+    };
+    let module = value_ptr(func) as *mut c::JSModuleDef;
+    // The module is kept alive by the runtime's module list.
+    unsafe { c::JS_FreeValue(ctx, func) };
+    module
+}
+
+/// Evaluates `source` as the module `name`, returning the promise of its evaluation which
+/// settles after any top-level `await`.
+pub(super) fn eval(ctx: &js::Context, source: &str, name: &str) -> Result<js::Value> {
+    let func = compile(ctx.as_ptr(), source, name)
+        .map_err(|err| anyhow!("{err}: {}", ctx.get_exception_str()))?;
+    let ret = unsafe { c::JS_EvalFunction(ctx.as_ptr(), func) };
+    if c::is_exception(ret) {
+        bail!("{}", ctx.get_exception_str());
+    }
+    Ok(js::Value::new_moved(ctx, ret))
+}
+
+pub(super) fn is_pending(ctx: &js::Context, promise: &js::Value) -> bool {
+    let state = unsafe { c::JS_PromiseState(ctx.as_ptr(), *promise.raw_value()) };
+    state == c::JSPromiseStateEnum_JS_PROMISE_PENDING
+}
+
+/// The rejection reason if the evaluation promise of a module has been rejected.
+pub(super) fn rejection_reason(ctx: &js::Context, promise: &js::Value) -> Option<js::Value> {
+    let raw = *promise.raw_value();
+    let state = unsafe { c::JS_PromiseState(ctx.as_ptr(), raw) };
+    if state != c::JSPromiseStateEnum_JS_PROMISE_REJECTED {
+        return None;
+    }
+    let reason = unsafe { c::JS_PromiseResult(ctx.as_ptr(), raw) };
+    Some(js::Value::new_moved(ctx, reason))
+}
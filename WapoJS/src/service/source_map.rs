@@ -0,0 +1,320 @@
+//! Source maps for the stack traces of bundled scripts.
+//!
+//! Only the parts of [Source Map v3](https://sourcemaps.info/spec.html) needed to map a
+//! generated location back to its original source are supported: `sources`, `sourceRoot` and
+//! `mappings`. Stack frames of QuickJS read `at func (file:line:column)`, with the column
+//! omitted by some builds, and are rewritten to the original `source:line:column`.
+
+use anyhow::{anyhow, bail};
+use serde::Deserialize;
+
+use super::*;
+
+const SOURCE_MAPPING_URL: &str = "//# sourceMappingURL=";
+const DATA_URL_PREFIX: &str = "data:application/json;";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawSourceMap {
+    version: u32,
+    #[serde(default)]
+    source_root: Option<String>,
+    sources: Vec<Option<String>>,
+    mappings: String,
+}
+
+/// A generated location, mapped to `line` and `column` of `sources[source]`. All are 0-based.
+#[derive(Clone, Copy)]
+struct Mapping {
+    column: u32,
+    source: u32,
+    line: u32,
+    src_column: u32,
+}
+
+pub struct SourceMap {
+    sources: Vec<String>,
+    /// The mappings of each generated line, ordered by column.
+    lines: Vec<Vec<Mapping>>,
+}
+
+impl SourceMap {
+    pub fn parse(json: &str) -> Result<Self> {
+        let raw: RawSourceMap = serde_json::from_str(json).context("invalid source map")?;
+        if raw.version != 3 {
+            bail!("unsupported source map version {}", raw.version);
+        }
+        let root = raw.source_root.unwrap_or_default();
+        let sources = raw
+            .sources
+            .into_iter()
+            .map(|source| {
+                let source = source.unwrap_or_default();
+                if root.is_empty() || root.ends_with('/') {
+                    format!("{root}{source}")
+                } else {
+                    format!("{root}/{source}")
+                }
+            })
+            .collect();
+        Ok(Self {
+            sources,
+            lines: parse_mappings(&raw.mappings)?,
+        })
+    }
+
+    /// Maps a 1-based generated location to the 1-based location in the original source.
+    ///
+    /// Without a column, the first mapping of the line is used.
+    fn lookup(&self, line: u32, column: Option<u32>) -> Option<(&str, u32, u32)> {
+        let mappings = self.lines.get(line.checked_sub(1)? as usize)?;
+        let index = match column {
+            Some(column) => {
+                let column = column.saturating_sub(1);
+                mappings
+                    .partition_point(|m| m.column <= column)
+                    .saturating_sub(1)
+            }
+            None => 0,
+        };
+        let mapping = mappings.get(index)?;
+        let source = self.sources.get(mapping.source as usize)?;
+        Some((source, mapping.line + 1, mapping.src_column + 1))
+    }
+}
+
+fn parse_mappings(mappings: &str) -> Result<Vec<Vec<Mapping>>> {
+    // Except for the generated column, the fields are relative to the previous segment, even
+    // across lines.
+    let (mut source, mut line, mut src_column) = (0i64, 0i64, 0i64);
+    let mut lines = vec![];
+    for group in mappings.split(';') {
+        let mut column = 0i64;
+        let mut segments = vec![];
+        for segment in group.split(',').filter(|s| !s.is_empty()) {
+            let fields = decode_vlq(segment)?;
+            column += fields[0];
+            // A segment of a single field maps to no source.
+            if fields.len() < 4 {
+                continue;
+            }
+            source += fields[1];
+            line += fields[2];
+            src_column += fields[3];
+            let to_u32 = |v: i64| u32::try_from(v).map_err(|_| anyhow!("invalid source map"));
+            segments.push(Mapping {
+                column: to_u32(column)?,
+                source: to_u32(source)?,
+                line: to_u32(line)?,
+                src_column: to_u32(src_column)?,
+            });
+        }
+        segments.sort_by_key(|m| m.column);
+        lines.push(segments);
+    }
+    Ok(lines)
+}
+
+fn base64_digit(c: u8) -> Option<u8> {
+    match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+/// Decodes the base64 VLQ fields of a mapping segment.
+fn decode_vlq(segment: &str) -> Result<Vec<i64>> {
+    let mut fields = vec![];
+    let (mut value, mut shift) = (0i64, 0u32);
+    for c in segment.bytes() {
+        let digit = base64_digit(c).ok_or(anyhow!("invalid source map mappings"))?;
+        if shift > 60 {
+            bail!("invalid source map mappings");
+        }
+        value |= ((digit & 0x1f) as i64) << shift;
+        if digit & 0x20 != 0 {
+            shift += 5;
+            continue;
+        }
+        // The lowest bit is the sign.
+        let magnitude = value >> 1;
+        fields.push(if value & 1 == 1 {
+            -magnitude
+        } else {
+            magnitude
+        });
+        (value, shift) = (0, 0);
+    }
+    if shift != 0 {
+        bail!("invalid source map mappings");
+    }
+    Ok(fields)
+}
+
+fn decode_base64(data: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(data.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0u32);
+    for c in data.trim_end_matches('=').bytes() {
+        let digit = base64_digit(c).ok_or(anyhow!("invalid base64 data"))?;
+        acc = (acc << 6) | digit as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Ok(bytes)
+}
+
+/// Finds the source map referenced by the `//# sourceMappingURL=` comment of the script
+/// `name`, either inline as a base64 data URL or as a file relative to the script.
+pub fn find_source_map(name: &str, source: &str) -> Result<Option<String>> {
+    let Some(url) = source
+        .lines()
+        .rev()
+        .take_while(|line| line.trim().is_empty() || line.trim_start().starts_with("//"))
+        .find_map(|line| line.trim().strip_prefix(SOURCE_MAPPING_URL))
+    else {
+        return Ok(None);
+    };
+    let url = url.trim();
+    if let Some(data) = url.strip_prefix(DATA_URL_PREFIX) {
+        let data = data
+            .split_once("base64,")
+            .map(|(_, data)| data)
+            .ok_or(anyhow!("only base64 source map data URLs are supported"))?;
+        let json = String::from_utf8(decode_base64(data)?).context("invalid source map")?;
+        return Ok(Some(json));
+    }
+    if url.contains(':') || name.starts_with('<') || name.contains(':') {
+        bail!("can not load source map {url} of {name}");
+    }
+    let dir = name.rsplit_once('/').map_or(".", |(dir, _)| dir);
+    let path = join_path(dir, url);
+    let json = std::fs::read_to_string(&path)
+        .with_context(|| format!("failed to read source map {path}"))?;
+    Ok(Some(json))
+}
+
+/// Rewrites the locations in the frames of a stack trace that belong to the scripts in `maps`.
+pub(super) fn rewrite_stack(maps: &BTreeMap<String, SourceMap>, text: &str) -> String {
+    if maps.is_empty() {
+        return text.to_string();
+    }
+    text.split('\n')
+        .map(|line| {
+            maps.iter()
+                .find_map(|(name, map)| rewrite_frame(line, name, map))
+                .unwrap_or_else(|| line.to_string())
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn rewrite_frame(frame: &str, name: &str, map: &SourceMap) -> Option<String> {
+    // The name must start the location, so that `dist/index.js` doesn't match
+    // `other/dist/index.js`.
+    let start = frame
+        .match_indices(name)
+        .map(|(start, _)| start)
+        .find(|&start| {
+            let before = &frame[..start];
+            before.ends_with('(') || before.ends_with("at ")
+        })?;
+    let rest = frame[start + name.len()..].strip_prefix(':')?;
+    let (line, rest) = split_number(rest)?;
+    let (column, rest) = match rest.strip_prefix(':').and_then(split_number) {
+        Some((column, rest)) => (Some(column), rest),
+        None => (None, rest),
+    };
+    let (source, line, column) = map.lookup(line, column)?;
+    Some(format!("{}{source}:{line}:{column}{rest}", &frame[..start]))
+}
+
+fn split_number(text: &str) -> Option<(u32, &str)> {
+    let len = text.bytes().take_while(u8::is_ascii_digit).count();
+    Some((text[..len].parse().ok()?, &text[len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Line 1 maps columns 0 and 4, line 2 continues relative to the end of line 1 and has a
+    // negative column delta, line 3 starts with a segment of a single field and switches to
+    // the second source.
+    const MAP: &str = r#"{
+        "version": 3,
+        "sourceRoot": "src",
+        "sources": ["a.ts", "b.ts"],
+        "mappings": "AAAA,IAAI;AACA,KAAD;A,ECAH;AAEA"
+    }"#;
+
+    #[test]
+    fn decodes_vlq() {
+        let cases: &[(&str, &[i64])] = &[
+            ("A", &[0]),
+            ("D", &[-1]),
+            ("gB", &[16]),
+            ("hB", &[-16]),
+            ("AAgBC", &[0, 0, 16, 1]),
+            ("2HwcqxB", &[123, 456, 789]),
+        ];
+        for (segment, fields) in cases {
+            assert_eq!(decode_vlq(segment).unwrap(), *fields, "{segment}");
+        }
+        assert!(decode_vlq("g").is_err());
+        assert!(decode_vlq("A*").is_err());
+    }
+
+    #[test]
+    fn looks_up_locations() {
+        let map = SourceMap::parse(MAP).unwrap();
+        let cases = [
+            ((1, Some(1)), Some(("src/a.ts", 1, 1))),
+            ((1, Some(7)), Some(("src/a.ts", 1, 5))),
+            // Relative to the last segment of the previous line.
+            ((2, Some(1)), Some(("src/a.ts", 2, 5))),
+            // Negative source column delta.
+            ((2, Some(6)), Some(("src/a.ts", 2, 4))),
+            // The single field segment maps nothing.
+            ((3, Some(1)), Some(("src/b.ts", 2, 1))),
+            ((3, Some(3)), Some(("src/b.ts", 2, 1))),
+            // Without a column, the first mapping of the line.
+            ((2, None), Some(("src/a.ts", 2, 5))),
+            ((4, None), Some(("src/b.ts", 4, 1))),
+            ((5, Some(1)), None),
+            ((0, None), None),
+        ];
+        for ((line, column), expected) in cases {
+            assert_eq!(map.lookup(line, column), expected, "{line}:{column:?}");
+        }
+    }
+
+    #[test]
+    fn rewrites_frames() {
+        let maps = BTreeMap::from([("dist/index.js".to_string(), SourceMap::parse(MAP).unwrap())]);
+        let cases = [
+            ("    at f (dist/index.js:2:6)", "    at f (src/a.ts:2:4)"),
+            ("    at dist/index.js:1:7", "    at src/a.ts:1:5"),
+            ("    at f (dist/index.js:3)", "    at f (src/b.ts:2:1)"),
+            (
+                "    at f (dist/index.js:9:1)",
+                "    at f (dist/index.js:9:1)",
+            ),
+            (
+                "    at g (other/dist/index.js:1:1)",
+                "    at g (other/dist/index.js:1:1)",
+            ),
+            ("Error: dist/index.js:1:1", "Error: dist/index.js:1:1"),
+        ];
+        for (frame, expected) in cases {
+            assert_eq!(rewrite_stack(&maps, frame), expected);
+        }
+    }
+}
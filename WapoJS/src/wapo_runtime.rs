@@ -14,10 +14,47 @@ pub use wapo::main;
 pub use wapo::net::SniTlsListener as TlsListener;
 pub use wapo::net::TcpListener;
 
-pub fn http_connector() -> HttpConnector {
+/// Lets other ready tasks run once before resuming.
+pub async fn yield_now() {
+    let mut yielded = false;
+    std::future::poll_fn(|cx| {
+        if yielded {
+            return std::task::Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        std::task::Poll::Pending
+    })
+    .await
+}
+
+pub type HttpClientConnector = HttpConnector;
+pub fn http_connector() -> HttpClientConnector {
     HttpConnector::new()
 }
 
+/// Custom TLS settings are not supported in wapo, where TLS connections are made by the host.
+#[derive(Clone)]
+pub struct TlsClientConfig(core::convert::Infallible);
+
+impl TlsClientConfig {
+    pub fn new(
+        _ca_certs: &[String],
+        _identity: Option<(&str, &str)>,
+        _pinned_spki_sha256: &[[u8; 32]],
+    ) -> Result<Self> {
+        anyhow::bail!("custom TLS settings are not supported in wapo")
+    }
+}
+
+pub fn http_connector_with_tls(config: &TlsClientConfig) -> HttpClientConnector {
+    match config.0 {}
+}
+
+pub async fn tls_connect(_host: &str, _port: u16, config: &TlsClientConfig) -> Result<TcpStream> {
+    match config.0 {}
+}
+
 pub fn init_logger() {
     wapo::logger::init();
 }
@@ -28,6 +65,8 @@ pub async fn run_local<F: core::future::Future>(fut: F) -> F::Output {
     fut.await
 }
 
+/// The TLS handshake is done by the host, which offers no ALPN, so unlike on native the
+/// listeners only serve HTTP/1.1.
 pub fn sni_listen(sni: &str, cert: &str, key: &str) -> Result<TlsListener> {
     let tls_config = wapo::env::tls::TlsServerConfig::V0 {
         cert: cert.to_string(),
@@ -36,6 +75,19 @@ pub fn sni_listen(sni: &str, cert: &str, key: &str) -> Result<TlsListener> {
     Ok(TlsListener::bind(sni, tls_config)?)
 }
 
+/// TLS session details of an accepted connection.
+#[derive(Clone, Debug, Default)]
+pub struct ServerTlsInfo {
+    pub server_name: Option<String>,
+    pub protocol_version: Option<String>,
+    pub alpn_protocol: Option<String>,
+}
+
+/// TLS is terminated by the host in wapo, so no session details are available to the guest.
+pub fn server_tls_info(_stream: &TcpStream) -> Option<ServerTlsInfo> {
+    None
+}
+
 pub async fn tcp_accept(listener: &TcpListener) -> Result<(TcpStream, SocketAddr)> {
     listener
         .accept()
@@ -8,7 +8,9 @@ use wapo_quickjs::{js_eval, runtime};
 async fn main() {
     runtime::init_logger();
     log::debug!(target: "js", "WapoJS started");
-    let _output = runtime::run_local(js_eval::run(std::env::args()))
-        .await
-        .expect("failed to run js code");
+    // The error has been reported in the output envelope if one was requested.
+    if let Err(err) = runtime::run_local(js_eval::run(std::env::args())).await {
+        log::error!(target: "js", "failed to run js code: {err:?}");
+        std::process::exit(1);
+    }
 }
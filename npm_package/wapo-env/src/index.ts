@@ -111,6 +111,18 @@ declare global {
      */
     inspect(...data: any[]): void;
 
+    /**
+     * Returns a monotonic timestamp in nanoseconds, suitable for measuring elapsed time.
+     */
+    hrtime(): bigint;
+
+    /**
+     * Registers the source of a CommonJS module so that `require()` can load it.
+     * @param path - The absolute virtual path of the module, e.g. `/node_modules/lodash/index.js`.
+     * @param source - The module source, or JSON text for a `.json` path.
+     */
+    registerModule(path: string, source: string): void;
+
     /**
      * Signs the provided message using the worker's private key.
      * @param message - The message to sign.
@@ -158,12 +170,12 @@ declare global {
 
 
     /**
-     * Bridges two streams, copying data from the input stream to the output stream.
-     * @param inputStream - The input stream to read data from.
-     * @param outputStream - The output stream to write data to.
+     * Bridges two streams, copying data from the input stream to the output stream natively.
+     * @param args - The streams to bridge and the transforms to apply on the way.
+     * @param callback - Called with `("end", summary)` once done, or `("error", message)`.
      * @returns The ID of the spawned task.
      */
-    streamBridge(inputStream: ReadableStreamHandle, outputStream: WriteableStreamHandle): number;
+    streamBridge(args: StreamBridgeArgs, callback?: (cmd: string, data: StreamBridgeSummary | string) => any): number;
 
     /**
      * Creates a writable stream.
@@ -176,9 +188,20 @@ declare global {
      * Writes a chunk of data to the writable stream.
      * @param writer - The writable stream to write data to.
      * @param chunk - The chunk of data to write.
-     * @param callback - A callback function to be called with the result of the write operation.
+     * @param callback - A callback function to be called once the chunk has been written.
+     * @returns false if too much data is queued and the caller should wait for pending writes.
+     * @throws if the chunk would take the queued data above 16 MiB. The callback is then called
+     * with an error too.
      */
-    streamWriteChunk(writer: WriteStream, chunk: Uint8Array, callback: BoolCallback): void;
+    streamWriteChunk(writer: WriteStream, chunk: Uint8Array, callback: BoolCallback): boolean;
+
+    /**
+     * Writes a chunk of data to the writable stream.
+     * @param writer - The writable stream to write data to.
+     * @param chunk - The chunk of data to write.
+     * @returns A promise that resolves once the chunk has been written.
+     */
+    streamWrite(writer: WriteStream, chunk: Uint8Array): Promise<void>;
 
     /**
      * Creates a readable stream.
@@ -189,10 +212,37 @@ declare global {
     streamOpenRead(inputStream: ReadableStreamHandle, callback: DataCallback): number;
 
     /**
-     * Closes the writable stream.
-     * @param writer - The writable stream to close.
+     * Opens a stream for pull-based reading with `streamRead`.
+     * @param inputStream - The underlying stream to read data from.
+     * @returns An opaque object representing the reader.
+     */
+    streamOpenReader(inputStream: ReadableStreamHandle): StreamReader;
+
+    /**
+     * Reads the next chunk from the reader. Data is only read from the source when requested.
+     * @param reader - The reader to read from.
+     * @param maxBytes - The maximum size of the chunk, 8192 if not given.
+     * @returns A promise that resolves to the chunk, or null at the end of the stream.
      */
-    streamClose(writer: WriteStream): void;
+    streamRead(reader: StreamReader, maxBytes?: number): Promise<Uint8Array | null>;
+
+    /**
+     * Wraps a native input stream in a ReadableStream that reads only when the consumer pulls.
+     * @param inputStream - The underlying stream to read data from.
+     */
+    streamToReadable(inputStream: ReadableStreamHandle): ReadableStream<Uint8Array>;
+
+    /**
+     * Wraps a native output stream in a WritableStream whose writes resolve once flushed.
+     * @param outputStream - The underlying stream to write data to.
+     */
+    streamToWritable(outputStream: WriteableStreamHandle): WritableStream<Uint8Array>;
+
+    /**
+     * Closes the writable stream after the queued chunks are written, or releases a reader.
+     * @param stream - The writable stream or reader to close.
+     */
+    streamClose(stream: WriteStream | StreamReader): void;
 
     /**
      * Sends an HTTP request.
@@ -202,12 +252,24 @@ declare global {
      */
     httpRequest(req: HttpRequest, callback: (resp: ClientHttpResponseHead) => any): HttpRequestReceipt;
 
+    /**
+     * Opens an outbound TCP connection, optionally wrapped in TLS.
+     * @param options - The remote address and TLS settings.
+     * @param callback - A callback function to be called with the "connect" or "error" event.
+     * @returns The cancel token of the connecting task.
+     */
+    tcpConnect(options: TcpConnectOptions, callback: (cmd: string, data: TcpConnection | string) => any): number;
+
     /**
      * Starts an HTTP(S) server and listens for incoming connections.
+     *
+     * On native runtimes, TLS listeners also serve HTTP/2 to clients that negotiate it via
+     * ALPN. Under wapo the TLS handshake is done by the host and only HTTP/1.1 is served.
      * @param config - The configuration for the HTTP(S) server.
      * @param handler - A callback function that handles incoming requests.
+     * @returns A handle used to inspect or close the listener.
      */
-    httpsListen(config: HttpsConfig | HttpConfig, handler: (req: IncomingRequest) => any): void;
+    httpsListen(config: HttpsConfig | HttpConfig, handler: (req: IncomingRequest) => any): ListenerHandle;
 
     /**
      * Sends an HTTP response head to the specified transmitter.
@@ -223,6 +285,23 @@ declare global {
      */
     httpsSendResponseHeadRaw(tx: HttpResponseHeadHandle, head: string): void;
 
+    /**
+     * Sends the trailers of an HTTP response. If the response head declares a `Trailer` header,
+     * the response waits for the trailers after the output stream is closed; otherwise they must
+     * be sent before the stream is closed.
+     * @param tx - The trailers transmitter of the request.
+     * @param trailers - The trailer fields to send.
+     */
+    httpsSendResponseTrailers(tx: HttpTrailersHandle, trailers: HeadersOut): void;
+
+    /**
+     * Starts a plain TCP (or TLS) server and hands each accepted connection to the handler.
+     * @param config - The listening address, or the TLS configuration.
+     * @param handler - A callback function that handles incoming connections.
+     * @returns A handle used to inspect or close the listener.
+     */
+    tcpListen(config: HttpsConfig | HttpConfig, handler: (conn: IncomingConnection) => any): ListenerHandle;
+
     /**
      * Sets the query listener callback function.
      * @param callback - The callback function to be called when a query is received.
@@ -303,6 +382,133 @@ export interface ClientHttpResponseHead {
  */
 type HeadersOut = HeadersIn | Record<string, string>;
 
+/**
+ * Represents the options for `tcpConnect`.
+ */
+export interface TcpConnectOptions {
+  host: string;
+  port: number;
+  /**
+   * Whether to wrap the connection in TLS. An object can be given to customize the
+   * trusted roots, client certificate or pinned keys.
+   */
+  tls?: boolean | TlsOptions;
+}
+
+/**
+ * Represents custom TLS settings for outbound connections.
+ */
+export interface TlsOptions {
+  /**
+   * Extra trusted root certificates in PEM format.
+   */
+  caCerts?: string[];
+  /**
+   * The client certificate chain in PEM format.
+   */
+  clientCert?: string;
+  /**
+   * The client private key in PEM format.
+   */
+  clientKey?: string;
+  /**
+   * Hex encoded SHA-256 digests of acceptable SubjectPublicKeyInfo.
+   */
+  pinnedSpkiSha256?: string[];
+}
+
+/**
+ * Represents an established TCP connection.
+ */
+export interface TcpConnection {
+  opaqueInputStream: ReadableStreamHandle;
+  opaqueOutputStream: WriteableStreamHandle;
+}
+
+/**
+ * Represents a running listener started by `httpsListen` or `tcpListen`.
+ *
+ * The listen functions used to return the listener's resource id directly. The handle keeps
+ * that id in `id` and coerces to it via `valueOf`, so `Wapo.close(handle)` still works.
+ */
+export interface ListenerHandle {
+  /**
+   * The resource id of the listener. Passing it to `Wapo.close` stops the listener and drops all connections.
+   */
+  id: number;
+
+  /**
+   * Same as `id`.
+   */
+  cancelToken: number;
+
+  /**
+   * Whether the listener is still accepting new connections.
+   */
+  readonly accepting: boolean;
+
+  /**
+   * The number of connections currently being served.
+   */
+  readonly activeConnections: number;
+
+  /**
+   * Stops accepting new connections. By default in-flight connections are allowed to finish
+   * until `timeoutMs` elapses and the remaining ones are then dropped. Pass `graceful: false`
+   * to drop them immediately.
+   * @returns The stats at the time the listener stopped waiting for connections.
+   */
+  close(options?: ListenerCloseOptions): Promise<ListenerStats>;
+
+  valueOf(): number;
+}
+
+export interface ListenerCloseOptions {
+  graceful?: boolean;
+  timeoutMs?: number;
+}
+
+export interface ListenerStats {
+  accepting: boolean;
+  activeConnections: number;
+}
+
+/**
+ * Represents a connection accepted by `tcpListen`.
+ */
+export interface IncomingConnection extends TcpConnection {
+  /**
+   * The address of the remote peer.
+   */
+  remoteAddr: string;
+
+  /**
+   * The TLS session details, if the connection was accepted over TLS.
+   */
+  tls?: TlsMetadata;
+}
+
+/**
+ * Represents the TLS details of an accepted connection. Fields are absent when the runtime
+ * can not tell them.
+ */
+export interface TlsMetadata {
+  /**
+   * The server name (SNI) requested by the client.
+   */
+  serverName?: string;
+
+  /**
+   * The negotiated protocol version, e.g. "TLSv1.3".
+   */
+  version?: string;
+
+  /**
+   * The negotiated ALPN protocol, e.g. "h2".
+   */
+  alpnProtocol?: string;
+}
+
 /**
  * Represents the configuration for HTTPS server.
  */
@@ -372,11 +578,31 @@ export interface IncomingRequest {
    */
   headers: HeadersIn;
 
+  /**
+   * The address of the remote peer.
+   */
+  remoteAddr: string;
+
+  /**
+   * The HTTP version of the request, e.g. "HTTP/1.1" or "HTTP/2.0".
+   */
+  httpVersion: string;
+
+  /**
+   * The TLS session details, if the request was received over TLS.
+   */
+  tls?: TlsMetadata;
+
   /**
    * An opaque value representing the response transmitter.
    */
   opaqueResponseTx: HttpResponseHeadHandle;
 
+  /**
+   * An opaque value used to send the response trailers.
+   */
+  opaqueTrailersTx: HttpTrailersHandle;
+
   /**
    * An opaque value representing the input stream of the request.
    */
@@ -419,6 +645,13 @@ export interface HttpResponseHeadHandle {
   [_httpResponseHeadHandleBrand]: "HttpResponseHeadHandle";
 }
 declare const _httpResponseHeadHandleBrand: unique symbol;
+/**
+ * A handle used to send the trailers of an HTTP response.
+ */
+export interface HttpTrailersHandle {
+  [_httpTrailersHandleBrand]: "HttpTrailersHandle";
+}
+declare const _httpTrailersHandleBrand: unique symbol;
 /**
  * A handle used to respond to a query
  */
@@ -435,6 +668,38 @@ export interface WriteStream {
 }
 declare const _writeStreamBrand: unique symbol;
 
+export interface StreamBridgeArgs {
+  input: ReadableStreamHandle;
+  output: WriteableStreamHandle;
+  /** Applied in order to the bytes on their way from input to output. */
+  transforms?: StreamTransform[];
+}
+
+export type StreamTransform =
+  | { kind: "count" }
+  | { kind: "digest"; algorithm: "sha256" | "keccak256" | "blake2b128" | "blake2b256" | "blake2b512" }
+  | { kind: "compress" | "decompress"; format: "gzip" | "deflate" | "deflate-raw" | "br" }
+  /** Chunked AES-GCM with a 16 or 32 byte key and a 12 byte base nonce. */
+  | { kind: "encrypt" | "decrypt"; key: Uint8Array; nonce: Uint8Array };
+
+export interface StreamTransformResult {
+  kind: string;
+  /** Bytes that reached this stage. */
+  bytes: number;
+  digest?: Uint8Array;
+}
+
+export interface StreamBridgeSummary {
+  bytesRead: number;
+  bytesWritten: number;
+  transforms: StreamTransformResult[];
+}
+
+export interface StreamReader {
+  [_streamReaderBrand]: "StreamReader";
+}
+declare const _streamReaderBrand: unique symbol;
+
 
 /**
  * Represents a query received by the query listener.
@@ -494,6 +759,11 @@ export interface IsolateEvalArgs {
    * 'browser' for browser polyfills, 'nodejs' for nodejs API polyfills.
    */
   polyfills: string[];
+  /**
+   * Stop the evaluation on the first promise rejection that no `unhandledRejection`
+   * listener handles.
+   */
+  exitOnUnhandledRejection?: boolean;
 }
 
 
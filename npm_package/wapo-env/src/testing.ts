@@ -1,4 +1,4 @@
-import type { TypeRegistry, Codec, LockGuard, ReadableStreamHandle, WriteableStreamHandle, WriteStream, DataCallback, BoolCallback, HttpsConfig, HttpConfig, HttpResponseHeadHandle, QueryResposneHandle, Query, IsolateEvalArgs, RunCodeOptions, RunCodeReturns, MemoryStats, HttpResponseHead, IncomingRequest, HttpRequestReceipt, HttpRequest, ClientHttpResponseHead } from './index'
+import type { TypeRegistry, Codec, LockGuard, ReadableStreamHandle, WriteableStreamHandle, WriteStream, StreamReader, StreamBridgeArgs, StreamBridgeSummary, DataCallback, BoolCallback, HttpsConfig, HttpConfig, HttpResponseHeadHandle, QueryResposneHandle, Query, IsolateEvalArgs, RunCodeOptions, RunCodeReturns, MemoryStats, HttpResponseHead, IncomingRequest, HttpRequestReceipt, HttpRequest, ClientHttpResponseHead, TcpConnectOptions, TcpConnection, IncomingConnection, HttpTrailersHandle, ListenerHandle } from './index'
 import { blake2b } from '@noble/hashes/blake2b'
 import { keccak_256, sha3_256 } from '@noble/hashes/sha3'
 import { Wyhash } from 'wyhash.js'
@@ -64,6 +64,14 @@ globalThis.Wapo = {
         return JSON.stringify(obj, null, 2);
     },
 
+    hrtime: function(): bigint {
+        return process.hrtime.bigint();
+    },
+
+    registerModule: function(path: string, source: string): void {
+        throw new Error("Not implemented");
+    },
+
     workerSign: function(message: Uint8Array): Uint8Array {
         return new Uint8Array(64);
     },
@@ -92,7 +100,7 @@ globalThis.Wapo = {
         throw new Error("Not implemented");
     },
 
-    streamBridge(inputStream: ReadableStreamHandle, outputStream: WriteableStreamHandle): number {
+    streamBridge(args: StreamBridgeArgs, callback?: (cmd: string, data: StreamBridgeSummary | string) => any): number {
         throw new Error("Not implemented");
     },
 
@@ -100,7 +108,11 @@ globalThis.Wapo = {
         throw new Error("Not implemented");
     },
 
-    streamWriteChunk(writer: WriteStream, chunk: Uint8Array, callback: BoolCallback): void {
+    streamWriteChunk(writer: WriteStream, chunk: Uint8Array, callback: BoolCallback): boolean {
+        throw new Error("Not implemented");
+    },
+
+    streamWrite(writer: WriteStream, chunk: Uint8Array): Promise<void> {
         throw new Error("Not implemented");
     },
 
@@ -108,7 +120,23 @@ globalThis.Wapo = {
         throw new Error("Not implemented");
     },
 
-    streamClose(writer: WriteStream): void {
+    streamOpenReader(inputStream: ReadableStreamHandle): StreamReader {
+        throw new Error("Not implemented");
+    },
+
+    streamRead(reader: StreamReader, maxBytes?: number): Promise<Uint8Array | null> {
+        throw new Error("Not implemented");
+    },
+
+    streamToReadable(inputStream: ReadableStreamHandle): ReadableStream<Uint8Array> {
+        throw new Error("Not implemented");
+    },
+
+    streamToWritable(outputStream: WriteableStreamHandle): WritableStream<Uint8Array> {
+        throw new Error("Not implemented");
+    },
+
+    streamClose(stream: WriteStream | StreamReader): void {
         throw new Error("Not implemented");
     },
 
@@ -116,7 +144,11 @@ globalThis.Wapo = {
         throw new Error("Not implemented");
     },
 
-    httpsListen(config: HttpsConfig | HttpConfig, handler: (req: IncomingRequest) => any): void {
+    tcpConnect(options: TcpConnectOptions, callback: (cmd: string, data: TcpConnection | string) => any): number {
+        throw new Error("Not implemented");
+    },
+
+    httpsListen(config: HttpsConfig | HttpConfig, handler: (req: IncomingRequest) => any): ListenerHandle {
         throw new Error("Not implemented");
     },
 
@@ -128,6 +160,14 @@ globalThis.Wapo = {
         throw new Error("Not implemented");
     },
 
+    httpsSendResponseTrailers(tx: HttpTrailersHandle, trailers: HttpResponseHead['headers']): void {
+        throw new Error("Not implemented");
+    },
+
+    tcpListen(config: HttpsConfig | HttpConfig, handler: (conn: IncomingConnection) => any): ListenerHandle {
+        throw new Error("Not implemented");
+    },
+
     queryListen(callback: (query: Query) => void): void {
         throw new Error("Not implemented");
     },